@@ -193,3 +193,10 @@ pub extern "C" fn alloc(len: usize) -> usize {
     let layout = std::alloc::Layout::array::<u8>(len).unwrap();
     unsafe { std::alloc::alloc(layout) as usize }
 }
+
+// Called by the host once poll_stream returns, the data has been copied by then
+#[unsafe(no_mangle)]
+pub extern "C" fn dealloc(ptr: usize, len: usize) {
+    let layout = std::alloc::Layout::array::<u8>(len).unwrap();
+    unsafe { std::alloc::dealloc(ptr as *mut u8, layout) }
+}
@@ -0,0 +1,63 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{ItemFn, parse_macro_input};
+
+/// Turns `fn(nur::Request) -> nur::Response` into the function entrypoint.
+///
+/// Generates the `poll_stream`, `alloc` and `dealloc` exports expected by the worker.
+/// Requests are accumulated until complete and the returned response is sent before ending.
+///
+/// ```ignore
+/// #[nur::handler]
+/// fn handle(req: nur::Request) -> nur::Response {
+///     nur::Response::new(200).body(format!("Hello from {}", req.path))
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn handler(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "#[nur::handler] takes no arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let func = parse_macro_input!(item as ItemFn);
+    let sig = &func.sig;
+    if let Some(asyncness) = sig.asyncness {
+        return syn::Error::new_spanned(asyncness, "#[nur::handler] functions cannot be async")
+            .to_compile_error()
+            .into();
+    }
+    if sig.inputs.len() != 1 || !sig.generics.params.is_empty() {
+        return syn::Error::new_spanned(
+            sig,
+            "#[nur::handler] functions must look like fn(nur::Request) -> nur::Response",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let name = &sig.ident;
+    quote! {
+        #func
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn poll_stream(ptr: usize, len: usize) {
+            ::nur::__private::poll_stream(ptr, len, #name)
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn alloc(len: usize) -> usize {
+            ::nur::__private::alloc(len)
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn dealloc(ptr: usize, len: usize) {
+            ::nur::__private::dealloc(ptr, len)
+        }
+    }
+    .into()
+}
@@ -0,0 +1,234 @@
+use std::fmt;
+
+const MAX_HEADERS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// Path and query string, e.g. `/cows?name=lola`
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Malformed(httparse::Error),
+    InvalidContentLength,
+    /// The client stopped sending before the request was complete
+    Incomplete,
+}
+
+/// Buffers the chunks received by `poll_stream` until a whole request arrived
+#[derive(Default)]
+pub struct RequestAccumulator {
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl Request {
+    /// Returns the first value of the header `name`, case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The body as UTF-8, if it is valid
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.body).ok()
+    }
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Serializes the response, adding a `content-length` unless already present
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !self
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        {
+            head.push_str(&format!("content-length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+impl RequestAccumulator {
+    /// Appends `data`, returning the request once its head and `content-length` body arrived.
+    /// Requests are only returned once.
+    pub fn push(&mut self, data: &[u8]) -> Result<Option<Request>, Error> {
+        if self.done {
+            return Ok(None);
+        }
+        self.buf.extend_from_slice(data);
+        self.parse(false)
+    }
+
+    /// Called once the client is done sending. Requests without a `content-length`
+    /// get everything after the head as body.
+    pub fn finish(&mut self) -> Result<Option<Request>, Error> {
+        if self.done {
+            return Ok(None);
+        }
+        self.parse(true)
+    }
+
+    fn parse(&mut self, eof: bool) -> Result<Option<Request>, Error> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        let head_len = match req.parse(&self.buf).map_err(Error::Malformed)? {
+            httparse::Status::Complete(head_len) => head_len,
+            httparse::Status::Partial if eof => return Err(Error::Incomplete),
+            httparse::Status::Partial => return Ok(None),
+        };
+
+        let content_length = match req
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+        {
+            Some(header) => Some(
+                std::str::from_utf8(header.value)
+                    .ok()
+                    .and_then(|value| value.trim().parse::<usize>().ok())
+                    .ok_or(Error::InvalidContentLength)?,
+            ),
+            None => None,
+        };
+
+        let body = &self.buf[head_len..];
+        let body = match content_length {
+            Some(len) if body.len() >= len => &body[..len],
+            Some(_) if eof => return Err(Error::Incomplete),
+            Some(_) => return Ok(None),
+            // Without a length, only bodyless requests can be answered before the client is done
+            None if eof || body.is_empty() => body,
+            None => return Ok(None),
+        };
+
+        let request = Request {
+            method: req.method.unwrap_or_default().to_string(),
+            path: req.path.unwrap_or("/").to_string(),
+            headers: req
+                .headers
+                .iter()
+                .map(|header| {
+                    (
+                        header.name.to_string(),
+                        String::from_utf8_lossy(header.value).to_string(),
+                    )
+                })
+                .collect(),
+            body: body.to_vec(),
+        };
+        self.done = true;
+        self.buf = Vec::new();
+        Ok(Some(request))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Malformed(e) => write!(f, "malformed request: {e}"),
+            Error::InvalidContentLength => write!(f, "invalid content-length"),
+            Error::Incomplete => write!(f, "incomplete request"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accumulates_chunks() {
+        let mut acc = RequestAccumulator::default();
+        assert_eq!(
+            acc.push(b"POST /cows HTTP/1.1\r\nContent-Le").unwrap(),
+            None
+        );
+        assert_eq!(acc.push(b"ngth: 3\r\n\r\nm").unwrap(), None);
+
+        let req = acc.push(b"u!").unwrap().unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/cows");
+        assert_eq!(req.header("content-length"), Some("3"));
+        assert_eq!(req.text(), Some("mu!"));
+
+        assert_eq!(acc.finish().unwrap(), None);
+    }
+
+    #[test]
+    fn test_incomplete_request() {
+        let mut acc = RequestAccumulator::default();
+        assert_eq!(acc.push(b"GET / HTTP/1.1\r\n").unwrap(), None);
+        assert_eq!(acc.finish().unwrap_err(), Error::Incomplete);
+    }
+
+    #[test]
+    fn test_response_to_bytes() {
+        let response = Response::new(200)
+            .header("content-type", "text/plain")
+            .body("mu!");
+        assert_eq!(
+            response.to_bytes(),
+            b"HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: 3\r\n\r\nmu!"
+        );
+    }
+}
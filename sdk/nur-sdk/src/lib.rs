@@ -0,0 +1,115 @@
+//! Write Nur functions in Rust.
+//!
+//! ```ignore
+//! #[nur::handler]
+//! fn handle(req: nur::Request) -> nur::Response {
+//!     nur::log(&format!("{} {}", req.method, req.path));
+//!     nur::Response::new(200)
+//!         .header("content-type", "text/plain")
+//!         .body("mu!")
+//! }
+//! ```
+//!
+//! Build with `cargo build --target wasm32-unknown-unknown --release`.
+//! Lower level functions such as [send] and [end] are available for guests that export
+//! `poll_stream` themselves.
+
+mod http;
+
+pub use http::{Error, Request, RequestAccumulator, Response};
+pub use nur_sdk_macros::handler;
+
+#[cfg(target_arch = "wasm32")]
+mod import {
+    #[link(wasm_import_module = "nur")]
+    unsafe extern "C" {
+        pub fn nur_log(ptr: *const u8, len: usize);
+        pub fn nur_send(ptr: *const u8, len: usize);
+        pub fn nur_end();
+    }
+}
+
+/// Outside of WebAssembly, e.g. in unit tests, the host is the terminal
+#[cfg(not(target_arch = "wasm32"))]
+mod import {
+    pub unsafe fn nur_log(ptr: *const u8, len: usize) {
+        let msg = unsafe { std::slice::from_raw_parts(ptr, len) };
+        eprintln!("[nur_log] {}", String::from_utf8_lossy(msg));
+    }
+
+    pub unsafe fn nur_send(ptr: *const u8, len: usize) {
+        let data = unsafe { std::slice::from_raw_parts(ptr, len) };
+        println!("{}", String::from_utf8_lossy(data));
+    }
+
+    pub unsafe fn nur_end() {}
+}
+
+/// Sends `msg` to the function logs
+pub fn log(msg: &str) {
+    unsafe { import::nur_log(msg.as_ptr(), msg.len()) }
+}
+
+/// Writes raw bytes to the client
+pub fn send(data: &[u8]) {
+    unsafe { import::nur_send(data.as_ptr(), data.len()) }
+}
+
+/// Tells the worker the function is done, the connection is closed right after
+pub fn end() {
+    unsafe { import::nur_end() }
+}
+
+/// Used by the code generated by [handler], not part of the public API
+#[doc(hidden)]
+pub mod __private {
+    use super::*;
+    use std::alloc::Layout;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static REQUEST: RefCell<RequestAccumulator> = RefCell::new(RequestAccumulator::default());
+    }
+
+    pub fn alloc(len: usize) -> usize {
+        if len == 0 {
+            return std::ptr::NonNull::<u8>::dangling().as_ptr() as usize;
+        }
+        let layout = Layout::array::<u8>(len).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        ptr as usize
+    }
+
+    pub fn dealloc(ptr: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let layout = Layout::array::<u8>(len).unwrap();
+        unsafe { std::alloc::dealloc(ptr as *mut u8, layout) }
+    }
+
+    pub fn poll_stream(ptr: usize, len: usize, handler: impl FnOnce(Request) -> Response) {
+        let result = REQUEST.with_borrow_mut(|request| {
+            if ptr == 0 {
+                request.finish()
+            } else {
+                let data = unsafe { std::slice::from_raw_parts(ptr as *const u8, len) };
+                request.push(data)
+            }
+        });
+
+        let response = match result {
+            Ok(None) => return,
+            Ok(Some(request)) => handler(request),
+            Err(e) => {
+                log(&format!("Invalid request: {e}"));
+                Response::new(400).body(format!("{e}\n"))
+            }
+        };
+        send(&response.to_bytes());
+        end();
+    }
+}
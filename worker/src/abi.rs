@@ -0,0 +1,499 @@
+//! Guest ABIs understood by the worker.
+//!
+//! - [GuestAbi::PollStream]: the module exports `poll_stream(ptr, len)` and receives the raw
+//!   bytes of the connection, answering with `nur_send`.
+//! - [GuestAbi::HandleRequest]: the module exports `handle_request(ptr, len)`. The worker parses
+//!   the HTTP/1.1 request itself and passes it encoded as described in [encode_request]. The
+//!   guest answers with `nur_response_start(status, headers_ptr, headers_len)`, any number of
+//!   `nur_response_write(ptr, len)` and finally `nur_end()`. Both return 0, or -1 when their
+//!   range is out of bounds. Headers with invalid names or values are dropped.
+//!
+//! Every integer of the request ABI is a little-endian u32, and header lists are encoded as
+//! `count` followed by `name_len | name | value_len | value` entries.
+//!
+//! - [GuestAbi::Component]: a component exporting `nur:http/incoming-handler`, run through the
+//!   `nur:http/incoming-handler#handle` export of its core module (see [crate::component]).
+
+use crate::component;
+use crate::metrics::METRICS;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use wasmer::Instance;
+
+/// `\0asm` followed by the component encoding version and layer
+const COMPONENT_HEADER: [u8; 8] = [0x00, 0x61, 0x73, 0x6d, 0x0d, 0x00, 0x01, 0x00];
+
+pub const EXPORTED_POLL_HANDLER_SYMBOL_NAME: &str = "poll_stream";
+pub const EXPORTED_REQUEST_HANDLER_SYMBOL_NAME: &str = "handle_request";
+
+const MAX_REQUEST_HEAD_LEN: usize = 64 * 1024;
+const MAX_REQUEST_HEADERS: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestAbi {
+    PollStream,
+    HandleRequest,
+    Component,
+}
+
+impl GuestAbi {
+    /// `handle_request` takes precedence, so a module can keep `poll_stream` while migrating.
+    /// Components are detected from the core module, which is what precompiled functions keep.
+    pub fn detect(instance: &Instance) -> Option<Self> {
+        if instance
+            .exports
+            .get_function(component::EXPORTED_HANDLE_SYMBOL_NAME)
+            .is_ok()
+        {
+            Some(GuestAbi::Component)
+        } else if instance
+            .exports
+            .get_function(EXPORTED_REQUEST_HANDLER_SYMBOL_NAME)
+            .is_ok()
+        {
+            Some(GuestAbi::HandleRequest)
+        } else if instance
+            .exports
+            .get_function(EXPORTED_POLL_HANDLER_SYMBOL_NAME)
+            .is_ok()
+        {
+            Some(GuestAbi::PollStream)
+        } else {
+            None
+        }
+    }
+
+    pub fn handler_symbol_name(&self) -> &'static str {
+        match self {
+            GuestAbi::PollStream => EXPORTED_POLL_HANDLER_SYMBOL_NAME,
+            GuestAbi::HandleRequest => EXPORTED_REQUEST_HANDLER_SYMBOL_NAME,
+            GuestAbi::Component => component::EXPORTED_HANDLE_SYMBOL_NAME,
+        }
+    }
+}
+
+/// Whether `wasm_bytes` is a component rather than a core module
+pub fn is_component(wasm_bytes: &[u8]) -> bool {
+    wasm_bytes.starts_with(&COMPONENT_HEADER)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct GuestRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Reasons a request cannot be handed to the guest, answered by the worker itself
+#[derive(Debug, PartialEq, Eq)]
+pub enum RequestError {
+    /// The peer closed the connection before sending a whole request
+    Closed,
+    Malformed(String),
+    TooLarge,
+    /// `Transfer-Encoding` bodies are not supported, clients must send a `Content-Length`
+    UnsupportedTransferEncoding,
+}
+
+impl RequestError {
+    /// Status and body answered to the client, [None] when there is nobody to answer
+    pub fn response(&self) -> Option<(u16, String)> {
+        match self {
+            RequestError::Closed => None,
+            RequestError::Malformed(e) => Some((400, format!("bad request: {e}\n"))),
+            RequestError::TooLarge => Some((413, "request too large\n".to_string())),
+            RequestError::UnsupportedTransferEncoding => {
+                Some((411, "a content-length is required\n".to_string()))
+            }
+        }
+    }
+}
+
+/// Reads a whole HTTP/1.1 request, rejecting bodies larger than `max_body_len`
+pub async fn read_request<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_body_len: usize,
+) -> Result<GuestRequest, RequestError> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0_u8; 4096];
+
+    let (mut request, head_len, content_length) = loop {
+        let n = reader
+            .read(&mut chunk)
+            .await
+            .map_err(|e| RequestError::Malformed(e.to_string()))?;
+        if n == 0 {
+            return Err(RequestError::Closed);
+        }
+        METRICS.bytes_received.add(n as u64);
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf) {
+            Ok(httparse::Status::Complete(head_len)) => {
+                let mut content_length = None;
+                let mut request_headers = Vec::with_capacity(req.headers.len());
+                for header in req.headers.iter() {
+                    let value = String::from_utf8_lossy(header.value).to_string();
+                    if header.name.eq_ignore_ascii_case("transfer-encoding") {
+                        return Err(RequestError::UnsupportedTransferEncoding);
+                    }
+                    if header.name.eq_ignore_ascii_case("content-length") {
+                        let len = value.trim().parse::<usize>().map_err(|_| {
+                            RequestError::Malformed(format!("invalid content-length {value:?}"))
+                        })?;
+                        // Peers disagreeing on the body length would let requests be smuggled
+                        if content_length.is_some_and(|previous| previous != len) {
+                            return Err(RequestError::Malformed(
+                                "conflicting content-length headers".to_string(),
+                            ));
+                        }
+                        content_length = Some(len);
+                    }
+                    request_headers.push((header.name.to_string(), value));
+                }
+                let request = GuestRequest {
+                    method: req.method.unwrap_or_default().to_string(),
+                    path: req.path.unwrap_or("/").to_string(),
+                    headers: request_headers,
+                    body: Vec::new(),
+                };
+                break (request, head_len, content_length.unwrap_or(0));
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_REQUEST_HEAD_LEN => {}
+            Ok(httparse::Status::Partial) => return Err(RequestError::TooLarge),
+            Err(e) => return Err(RequestError::Malformed(e.to_string())),
+        }
+    };
+
+    if content_length > max_body_len {
+        return Err(RequestError::TooLarge);
+    }
+
+    let mut body = buf.split_off(head_len);
+    body.truncate(content_length);
+    while body.len() < content_length {
+        let n = reader
+            .read(&mut chunk)
+            .await
+            .map_err(|e| RequestError::Malformed(e.to_string()))?;
+        if n == 0 {
+            return Err(RequestError::Closed);
+        }
+        METRICS.bytes_received.add(n as u64);
+        let missing = content_length - body.len();
+        body.extend_from_slice(&chunk[..n.min(missing)]);
+    }
+    request.body = body;
+
+    Ok(request)
+}
+
+/// Encodes `request` as `method | path | headers | body`, each prefixed by its length
+pub fn encode_request(request: &GuestRequest) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(request.body.len() + 256);
+    put_bytes(&mut buf, request.method.as_bytes());
+    put_bytes(&mut buf, request.path.as_bytes());
+    buf.extend_from_slice(&encode_headers(&request.headers));
+    put_bytes(&mut buf, &request.body);
+    buf
+}
+
+pub fn encode_headers(headers: &[(String, String)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(headers.len() as u32).to_le_bytes());
+    for (name, value) in headers {
+        put_bytes(&mut buf, name.as_bytes());
+        put_bytes(&mut buf, value.as_bytes());
+    }
+    buf
+}
+
+/// Inverse of [encode_request]
+pub fn decode_request(mut buf: &[u8]) -> Result<GuestRequest, String> {
+    let method = take_string(&mut buf)?;
+    let path = take_string(&mut buf)?;
+    let headers = take_headers(&mut buf)?;
+    let body = take_bytes(&mut buf)?.to_vec();
+    Ok(GuestRequest {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+pub fn decode_headers(mut buf: &[u8]) -> Result<Vec<(String, String)>, String> {
+    take_headers(&mut buf)
+}
+
+fn take_headers(buf: &mut &[u8]) -> Result<Vec<(String, String)>, String> {
+    let count = take_u32(buf)?;
+    let mut headers = Vec::new();
+    for _ in 0..count {
+        headers.push((take_string(buf)?, take_string(buf)?));
+    }
+    Ok(headers)
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn take_u32(buf: &mut &[u8]) -> Result<u32, String> {
+    let Some((len, rest)) = buf.split_first_chunk::<4>() else {
+        return Err("truncated buffer".to_string());
+    };
+    *buf = rest;
+    Ok(u32::from_le_bytes(*len))
+}
+
+fn take_string(buf: &mut &[u8]) -> Result<String, String> {
+    let bytes = take_bytes(buf)?;
+    String::from_utf8(bytes.to_vec()).map_err(|e| format!("invalid string: {e}"))
+}
+
+fn take_bytes<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8], String> {
+    let len = take_u32(buf)? as usize;
+    if buf.len() < len {
+        return Err("truncated buffer".to_string());
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(bytes)
+}
+
+/// Serializes the response described by the guest calls into HTTP/1.1.
+/// Bodies are sent with chunked encoding unless the guest sets a `content-length`.
+#[derive(Default)]
+pub struct ResponseEncoder {
+    state: ResponseState,
+}
+
+#[derive(Default, PartialEq, Eq)]
+enum ResponseState {
+    #[default]
+    Pending,
+    Streaming {
+        chunked: bool,
+    },
+    Finished,
+}
+
+impl ResponseEncoder {
+    pub fn start(&mut self, status: u16, headers: &[(String, String)]) -> Vec<u8> {
+        if self.state != ResponseState::Pending {
+            log::warn!("Response already started, ignoring status {status}");
+            return Vec::new();
+        }
+
+        let status = if (100..1000).contains(&status) {
+            status
+        } else {
+            log::warn!("Invalid response status {status}, answering 500");
+            500
+        };
+        let headers: Vec<_> = headers
+            .iter()
+            .filter(|(name, value)| {
+                let valid = is_valid_header(name, value);
+                if !valid {
+                    log::warn!("Dropping invalid response header {name:?}");
+                }
+                valid
+            })
+            .collect();
+
+        let chunked = !headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("content-length"));
+        let mut head = format!("HTTP/1.1 {status} {}\r\n", reason(status));
+        for (name, value) in headers {
+            if name.eq_ignore_ascii_case("connection")
+                || name.eq_ignore_ascii_case("transfer-encoding")
+            {
+                continue;
+            }
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if chunked {
+            head.push_str("transfer-encoding: chunked\r\n");
+        }
+        head.push_str("connection: close\r\n\r\n");
+
+        self.state = ResponseState::Streaming { chunked };
+        head.into_bytes()
+    }
+
+    /// Body writes before [ResponseEncoder::start] imply a `200` without headers
+    pub fn write(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut buf = match self.state {
+            ResponseState::Pending => self.start(200, &[]),
+            ResponseState::Streaming { .. } => Vec::new(),
+            ResponseState::Finished => return Vec::new(),
+        };
+        match self.state {
+            ResponseState::Streaming { chunked: true } if !data.is_empty() => {
+                buf.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+                buf.extend_from_slice(data);
+                buf.extend_from_slice(b"\r\n");
+            }
+            ResponseState::Streaming { chunked: true } => {}
+            _ => buf.extend_from_slice(data),
+        }
+        buf
+    }
+
+    /// Terminates the response. A guest that never answered gets a `502`.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let buf = match self.state {
+            ResponseState::Pending => {
+                let body = "function did not respond\n";
+                let mut buf = self.start(
+                    502,
+                    &[
+                        ("content-type".to_string(), "text/plain".to_string()),
+                        ("content-length".to_string(), body.len().to_string()),
+                    ],
+                );
+                buf.extend_from_slice(body.as_bytes());
+                buf
+            }
+            ResponseState::Streaming { chunked: true } => b"0\r\n\r\n".to_vec(),
+            _ => Vec::new(),
+        };
+        self.state = ResponseState::Finished;
+        buf
+    }
+}
+
+/// Names must be RFC 9110 tokens and values can't contain CR, LF or NUL, which would let
+/// guests inject headers or a whole response
+fn is_valid_header(name: &str, value: &str) -> bool {
+    let is_token_char = |b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+    !name.is_empty()
+        && name.bytes().all(is_token_char)
+        && !value.bytes().any(|b| matches!(b, b'\r' | b'\n' | b'\0'))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_component() {
+        let module = wasmer::wat2wasm(b"(module)").unwrap();
+        assert!(!is_component(&module));
+        assert!(is_component(&COMPONENT_HEADER));
+        assert!(!is_component(b"\0as"));
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let mut raw: &[u8] =
+            b"POST /cows?name=lola HTTP/1.1\r\nHost: nur\r\nContent-Length: 3\r\n\r\nmu!extra";
+        let request = read_request(&mut raw, 1024).await.unwrap();
+        assert_eq!(
+            request,
+            GuestRequest {
+                method: "POST".to_string(),
+                path: "/cows?name=lola".to_string(),
+                headers: vec![
+                    ("Host".to_string(), "nur".to_string()),
+                    ("Content-Length".to_string(), "3".to_string()),
+                ],
+                body: b"mu!".to_vec(),
+            }
+        );
+
+        let encoded = encode_request(&request);
+        assert_eq!(&encoded[..8], b"\x04\x00\x00\x00POST");
+        assert_eq!(decode_request(&encoded).unwrap(), request);
+
+        let mut raw: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nmu!";
+        assert_eq!(
+            read_request(&mut raw, 2).await.unwrap_err(),
+            RequestError::TooLarge
+        );
+
+        let mut raw: &[u8] =
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 30\r\n\r\nmu!";
+        assert_eq!(
+            read_request(&mut raw, 1024).await.unwrap_err(),
+            RequestError::Malformed("conflicting content-length headers".to_string())
+        );
+        let mut raw: &[u8] =
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nmu!";
+        assert_eq!(read_request(&mut raw, 1024).await.unwrap().body, b"mu!");
+
+        let mut raw: &[u8] = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(
+            read_request(&mut raw, 2).await.unwrap_err(),
+            RequestError::UnsupportedTransferEncoding
+        );
+    }
+
+    #[test]
+    fn test_headers_roundtrip() {
+        let headers = vec![("content-type".to_string(), "text/plain".to_string())];
+        assert_eq!(decode_headers(&encode_headers(&headers)).unwrap(), headers);
+        assert!(decode_headers(&[1, 0, 0, 0, 9]).is_err());
+    }
+
+    #[test]
+    fn test_response_encoder_chunked() {
+        let mut encoder = ResponseEncoder::default();
+        let mut out = encoder.start(201, &[("x-cow".to_string(), "lola".to_string())]);
+        out.extend(encoder.write(b"mu"));
+        out.extend(encoder.finish());
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 201 Created\r\nx-cow: lola\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n2\r\nmu\r\n0\r\n\r\n"
+        );
+
+        let mut encoder = ResponseEncoder::default();
+        let head = encoder.start(
+            200,
+            &[
+                ("x-cow".to_string(), "lola\r\nset-cookie: a=b".to_string()),
+                ("x-cow\r\nset-cookie".to_string(), "a=b".to_string()),
+                ("content-length".to_string(), "0".to_string()),
+            ],
+        );
+        assert_eq!(
+            String::from_utf8(head).unwrap(),
+            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+        );
+
+        let mut encoder = ResponseEncoder::default();
+        assert!(
+            String::from_utf8(encoder.finish())
+                .unwrap()
+                .starts_with("HTTP/1.1 502 Bad Gateway\r\n")
+        );
+    }
+}
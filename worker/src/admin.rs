@@ -0,0 +1,250 @@
+//! Admin listener for orchestrators and operators.
+//!
+//! - `GET /healthz`: the process is up
+//! - `GET /readyz`: log sink connected, storage reachable and cache dir writable
+//! - `GET /functions`: functions held by the L1 and L2 caches
+//! - `DELETE /functions/{uuid}`: evicts a function from every cache tier
+//! - `POST /functions/{uuid}/warm?last_deployment={unix_seconds}`: fetches a function ahead of time
+//! - `GET /invocations`: connections currently being served
+
+use crate::fetcher::{FunctionCache, FunctionFetch};
+use crate::http::{HttpRequest, HttpResponse};
+use crate::invocations::Invocations;
+use crate::logs_service::LogsService;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use uuid::Uuid;
+
+pub struct AdminState<F, L> {
+    pub function_fetcher: Arc<F>,
+    pub logs_service: Arc<L>,
+    pub invocations: Invocations,
+}
+
+// Not derived, which would require `F: Clone` and `L: Clone`
+impl<F, L> Clone for AdminState<F, L> {
+    fn clone(&self) -> Self {
+        AdminState {
+            function_fetcher: self.function_fetcher.clone(),
+            logs_service: self.logs_service.clone(),
+            invocations: self.invocations.clone(),
+        }
+    }
+}
+
+/// Serves the admin API until the listener fails
+pub async fn serve<A, F, L>(addr: A, state: AdminState<F, L>) -> std::io::Result<()>
+where
+    A: ToSocketAddrs,
+    F: FunctionFetch + FunctionCache + Send + Sync + 'static,
+    L: LogsService + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).await?;
+    crate::http::serve(listener, move |req: HttpRequest| {
+        let state = state.clone();
+        async move { route(&state, req).await }
+    })
+    .await
+}
+
+async fn route<F, L>(state: &AdminState<F, L>, req: HttpRequest) -> HttpResponse
+where
+    F: FunctionFetch + FunctionCache,
+    L: LogsService,
+{
+    let segments: Vec<&str> = req.path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match (req.method.as_str(), segments.as_slice()) {
+        ("GET", ["healthz"]) => HttpResponse::text(200, "ok\n"),
+        ("GET", ["readyz"]) => readyz(state).await,
+        ("GET", ["functions"]) => list_functions(state).await,
+        ("DELETE", ["functions", uuid]) => match Uuid::parse_str(uuid) {
+            Ok(function_uuid) => evict_function(state, &function_uuid).await,
+            Err(e) => HttpResponse::text(400, &format!("invalid function uuid: {e}\n")),
+        },
+        ("POST", ["functions", uuid, "warm"]) => match Uuid::parse_str(uuid) {
+            Ok(function_uuid) => warm_function(state, &req, function_uuid).await,
+            Err(e) => HttpResponse::text(400, &format!("invalid function uuid: {e}\n")),
+        },
+        ("GET", ["invocations"]) => list_invocations(state),
+        (_, ["healthz" | "readyz" | "functions" | "invocations"])
+        | (_, ["functions", _] | ["functions", _, "warm"]) => {
+            HttpResponse::text(405, "method not allowed\n")
+        }
+        _ => HttpResponse::not_found(),
+    }
+}
+
+async fn readyz<F: FunctionCache, L: LogsService>(state: &AdminState<F, L>) -> HttpResponse {
+    let (log_sink, storage, cache_dir) = tokio::join!(
+        state.logs_service.check_connection(),
+        state.function_fetcher.check_storage(),
+        state.function_fetcher.check_cache_dir(),
+    );
+
+    let checks = [
+        ("log_sink", log_sink.map_err(|e| e.to_string())),
+        ("storage", storage),
+        ("cache_dir", cache_dir),
+    ];
+    let ready = checks.iter().all(|(_, result)| result.is_ok());
+
+    let checks: serde_json::Map<String, serde_json::Value> = checks
+        .into_iter()
+        .map(|(name, result)| {
+            let value = match result {
+                Ok(()) => json!({ "ok": true }),
+                Err(e) => json!({ "ok": false, "error": e }),
+            };
+            (name.to_string(), value)
+        })
+        .collect();
+
+    HttpResponse::json(
+        if ready { 200 } else { 503 },
+        json!({ "ready": ready, "checks": checks }),
+    )
+}
+
+async fn list_functions<F: FunctionCache, L>(state: &AdminState<F, L>) -> HttpResponse {
+    let functions = state
+        .function_fetcher
+        .cached_functions()
+        .await
+        .into_iter()
+        .map(|func| {
+            json!({
+                "function_uuid": func.function_uuid.to_string(),
+                "tier": func.tier.label(),
+                "size": func.size,
+                "cached_at": func.cached_at,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    HttpResponse::json(200, json!({ "functions": functions }))
+}
+
+async fn evict_function<F: FunctionCache, L>(
+    state: &AdminState<F, L>,
+    function_uuid: &Uuid,
+) -> HttpResponse {
+    if state.function_fetcher.evict(function_uuid).await {
+        log::info!("Evicted function {function_uuid} from cache");
+        HttpResponse::json(200, json!({ "evicted": function_uuid.to_string() }))
+    } else {
+        HttpResponse::not_found()
+    }
+}
+
+async fn warm_function<F: FunctionFetch, L>(
+    state: &AdminState<F, L>,
+    req: &HttpRequest,
+    function_uuid: Uuid,
+) -> HttpResponse {
+    let last_deployment = match req.query_param("last_deployment").map(str::parse::<u64>) {
+        None => 0,
+        Some(Ok(last_deployment)) => last_deployment,
+        Some(Err(e)) => return HttpResponse::text(400, &format!("invalid last_deployment: {e}\n")),
+    };
+
+    match state
+        .function_fetcher
+        .fetch(&function_uuid, last_deployment)
+        .await
+    {
+        Ok(func) => {
+            log::info!(
+                "Pre-warmed function {function_uuid} from {}",
+                func.tier.label()
+            );
+            HttpResponse::json(
+                200,
+                json!({
+                    "function_uuid": function_uuid.to_string(),
+                    "tier": func.tier.label(),
+                    "precompiled": func.is_precompiled,
+                }),
+            )
+        }
+        Err(e) => HttpResponse::json(
+            404,
+            json!({ "function_uuid": function_uuid.to_string(), "error": format!("{e:?}") }),
+        ),
+    }
+}
+
+fn list_invocations<F, L>(state: &AdminState<F, L>) -> HttpResponse {
+    let now = SystemTime::now();
+    let invocations = state
+        .invocations
+        .snapshot()
+        .into_iter()
+        .map(|(id, info)| {
+            json!({
+                "id": id.to_string(),
+                "peer_addr": info.peer_addr.to_string(),
+                "function_uuid": info.function_uuid.map(|uuid| uuid.to_string()),
+                "started_at": info
+                    .started_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+                "elapsed_ms": now
+                    .duration_since(info.started_at)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    HttpResponse::json(200, json!({ "invocations": invocations }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetcher::MemoryFunctionFetcher;
+    use crate::logs_service::MemoryLogsService;
+
+    fn request(method: &str, path: &str) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: None,
+        }
+    }
+
+    fn body(response: &HttpResponse) -> serde_json::Value {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_with_memory_services() {
+        let function_uuid = Uuid::new_v4();
+        let function_fetcher = Arc::new(MemoryFunctionFetcher::default());
+        function_fetcher.insert(function_uuid, b"(module)");
+        let state = AdminState {
+            function_fetcher,
+            logs_service: Arc::new(MemoryLogsService::default()),
+            invocations: Invocations::default(),
+        };
+
+        let response = route(&state, request("GET", "/readyz")).await;
+        assert_eq!(response.status, 200);
+
+        let response = route(&state, request("GET", "/functions")).await;
+        assert_eq!(
+            body(&response)["functions"][0]["function_uuid"],
+            function_uuid.to_string()
+        );
+
+        let path = format!("/functions/{function_uuid}");
+        assert_eq!(route(&state, request("DELETE", &path)).await.status, 200);
+        assert_eq!(route(&state, request("DELETE", &path)).await.status, 404);
+        let response = route(&state, request("POST", &format!("{path}/warm"))).await;
+        assert_eq!(response.status, 404);
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Bounds the number of concurrent invocations, globally and per function.
+/// A limit of 0 means unlimited.
+#[derive(Clone)]
+pub struct Admission {
+    state: Arc<Mutex<AdmissionState>>,
+}
+
+struct AdmissionState {
+    max_global: usize,
+    max_per_function: usize,
+    global: usize,
+    per_function: HashMap<Uuid, usize>,
+}
+
+/// Slot held by an admitted invocation, released when dropped
+pub struct Permit {
+    admission: Admission,
+    function_uuid: Uuid,
+}
+
+impl Admission {
+    pub fn new(max_global: usize, max_per_function: usize) -> Self {
+        Admission {
+            state: Arc::new(Mutex::new(AdmissionState {
+                max_global,
+                max_per_function,
+                global: 0,
+                per_function: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Changes the limits on reload. Running invocations keep their slot when the limits
+    /// shrink, new ones are refused until enough of them finish.
+    pub fn set_limits(&self, max_global: usize, max_per_function: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.max_global = max_global;
+        state.max_per_function = max_per_function;
+    }
+
+    /// Returns [None] when admitting `function_uuid` would exceed any of the limits
+    pub fn try_acquire(&self, function_uuid: Uuid) -> Option<Permit> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.max_global > 0 && state.global >= state.max_global {
+            return None;
+        }
+
+        let running = state.per_function.get(&function_uuid).copied().unwrap_or(0);
+        if state.max_per_function > 0 && running >= state.max_per_function {
+            return None;
+        }
+
+        state.global += 1;
+        state.per_function.insert(function_uuid, running + 1);
+
+        Some(Permit {
+            admission: self.clone(),
+            function_uuid,
+        })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.admission.state.lock().unwrap();
+        state.global -= 1;
+        if let Some(running) = state.per_function.get_mut(&self.function_uuid) {
+            *running -= 1;
+            if *running == 0 {
+                state.per_function.remove(&self.function_uuid);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_global_limit() {
+        let admission = Admission::new(2, 0);
+        let a = admission.try_acquire(Uuid::new_v4()).unwrap();
+        let _b = admission.try_acquire(Uuid::new_v4()).unwrap();
+        assert!(admission.try_acquire(Uuid::new_v4()).is_none());
+
+        drop(a);
+        assert!(admission.try_acquire(Uuid::new_v4()).is_some());
+    }
+
+    #[test]
+    fn test_per_function_limit() {
+        let admission = Admission::new(0, 1);
+        let function_uuid = Uuid::new_v4();
+        let permit = admission.try_acquire(function_uuid).unwrap();
+        assert!(admission.try_acquire(function_uuid).is_none());
+        assert!(admission.try_acquire(Uuid::new_v4()).is_some());
+
+        drop(permit);
+        assert!(admission.try_acquire(function_uuid).is_some());
+    }
+
+    #[test]
+    fn test_set_limits() {
+        let admission = Admission::new(1, 0);
+        let a = admission.try_acquire(Uuid::new_v4()).unwrap();
+        assert!(admission.try_acquire(Uuid::new_v4()).is_none());
+
+        admission.set_limits(2, 0);
+        let b = admission.try_acquire(Uuid::new_v4()).unwrap();
+
+        // Both keep running, but nothing else is admitted until both are done
+        admission.set_limits(1, 0);
+        drop(a);
+        assert!(admission.try_acquire(Uuid::new_v4()).is_none());
+        drop(b);
+        assert!(admission.try_acquire(Uuid::new_v4()).is_some());
+    }
+}
@@ -0,0 +1,301 @@
+//! Authenticates gateways in the handshake against the keys of `gateway_auth_keys`. When keys
+//! are configured, handshakes are v2 and their metadata carries either:
+//!
+//! - `authorization: Bearer <secret>`, one of the configured secrets. The secret is sent as is,
+//!   so bearer tokens should only be used over TLS.
+//! - `x-nur-key-id`, `x-nur-timestamp` (UNIX seconds), `x-nur-nonce` and `x-nur-signature`, the
+//!   lowercase hex HMAC-SHA256 of [signed_message] with the secret of `x-nur-key-id`.
+//!
+//! Signed handshakes are rejected when their timestamp is more than `gateway_auth_max_skew_secs`
+//! away from the worker clock or their nonce was already seen, so they can't be replayed.
+
+use crate::config::Config;
+use crate::trace::decode_hex;
+#[cfg(test)]
+use crate::trace::encode_hex;
+use ring::hmac;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const AUTHORIZATION_KEY: &str = "authorization";
+pub const KEY_ID_KEY: &str = "x-nur-key-id";
+pub const TIMESTAMP_KEY: &str = "x-nur-timestamp";
+pub const NONCE_KEY: &str = "x-nur-nonce";
+pub const SIGNATURE_KEY: &str = "x-nur-signature";
+
+/// Metadata entries carrying credentials, removed before the metadata reaches the guest
+pub const CREDENTIAL_KEYS: &[&str] = &[
+    AUTHORIZATION_KEY,
+    KEY_ID_KEY,
+    TIMESTAMP_KEY,
+    NONCE_KEY,
+    SIGNATURE_KEY,
+];
+
+/// Longest `x-nur-nonce` accepted
+const MAX_NONCE_LEN: usize = 128;
+
+/// The keys gateways authenticate with
+pub struct GatewayAuth {
+    keys: HashMap<String, GatewayKey>,
+    max_skew_secs: u64,
+}
+
+struct GatewayKey {
+    key: hmac::Key,
+    /// HMAC of the secret itself, so bearer tokens are compared in constant time
+    secret_tag: hmac::Tag,
+}
+
+/// Nonces of the signed handshakes still within the allowed clock skew. Shared by every
+/// connection and kept across reloads.
+#[derive(Clone, Default)]
+pub struct Nonces(Arc<Mutex<NonceSet>>);
+
+#[derive(Default)]
+struct NonceSet {
+    /// `key_id:nonce` and the UNIX timestamp (seconds) it can be forgotten at
+    expires_at: HashMap<String, u64>,
+    pruned_at: u64,
+}
+
+impl GatewayAuth {
+    /// [None] when `gateway_auth_keys` is unset, every gateway is accepted then
+    pub fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        let Some(keys) = config.gateway_auth_keys.as_deref() else {
+            return Ok(None);
+        };
+        Ok(Some(GatewayAuth {
+            keys: GatewayAuth::parse_keys(keys)?,
+            max_skew_secs: config.gateway_auth_max_skew_secs,
+        }))
+    }
+
+    /// Parses `key_id=secret` pairs separated by `;`
+    fn parse_keys(keys: &str) -> Result<HashMap<String, GatewayKey>, String> {
+        let mut parsed = HashMap::new();
+        for pair in keys
+            .split(';')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let Some((key_id, secret)) = pair.split_once('=') else {
+                return Err("invalid key, expected key_id=secret".to_string());
+            };
+            let (key_id, secret) = (key_id.trim(), secret.trim());
+            if key_id.is_empty() || secret.is_empty() {
+                return Err("invalid key, expected key_id=secret".to_string());
+            }
+            let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+            let secret_tag = hmac::sign(&key, secret.as_bytes());
+            if parsed
+                .insert(key_id.to_string(), GatewayKey { key, secret_tag })
+                .is_some()
+            {
+                return Err(format!("duplicate key id {key_id:?}"));
+            }
+        }
+        if parsed.is_empty() {
+            return Err("no keys".to_string());
+        }
+        Ok(parsed)
+    }
+
+    /// Checks the credentials in the `metadata` of a handshake, `message` being its
+    /// [signed_message]
+    pub fn verify(
+        &self,
+        metadata: &HashMap<String, String>,
+        message: &[u8],
+        nonces: &Nonces,
+    ) -> Result<(), String> {
+        let get = |name: &str| {
+            metadata
+                .get(name)
+                .map(String::as_str)
+                .ok_or_else(|| format!("missing {name}"))
+        };
+
+        if let Some(authorization) = metadata.get(AUTHORIZATION_KEY) {
+            let token = authorization
+                .strip_prefix("Bearer ")
+                .ok_or("unsupported authorization scheme")?;
+            let valid = self.keys.values().any(|key| {
+                hmac::verify(&key.key, token.as_bytes(), key.secret_tag.as_ref()).is_ok()
+            });
+            return if valid {
+                Ok(())
+            } else {
+                Err("invalid bearer token".to_string())
+            };
+        }
+
+        let Some(signature) = metadata.get(SIGNATURE_KEY) else {
+            return Err("no credentials".to_string());
+        };
+        let key_id = get(KEY_ID_KEY)?;
+        let key = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| format!("unknown key id {key_id:?}"))?;
+        let signature = decode_hex(signature).ok_or("malformed signature")?;
+        hmac::verify(&key.key, message, &signature).map_err(|_| "invalid signature")?;
+
+        // Both are covered by the signature
+        let timestamp: u64 = get(TIMESTAMP_KEY)?
+            .parse()
+            .map_err(|_| "malformed timestamp")?;
+        let nonce = get(NONCE_KEY)?;
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err(format!("nonce must be 1 to {MAX_NONCE_LEN} bytes long"));
+        }
+
+        let now = unix_now();
+        if now.abs_diff(timestamp) > self.max_skew_secs {
+            return Err(format!(
+                "timestamp {timestamp} is more than {}s away from the worker clock",
+                self.max_skew_secs
+            ));
+        }
+        if !nonces.insert(
+            format!("{key_id}:{nonce}"),
+            timestamp + self.max_skew_secs,
+            now,
+        ) {
+            return Err(format!("replayed nonce {nonce:?}"));
+        }
+        Ok(())
+    }
+}
+
+impl Nonces {
+    /// Remembers `nonce` until `expires_at`, false when it is already known
+    fn insert(&self, nonce: String, expires_at: u64, now: u64) -> bool {
+        let mut nonces = self.0.lock().unwrap();
+        // Expired nonces are dropped at most once a second, their handshakes are too old anyway
+        if nonces.pruned_at < now {
+            nonces.expires_at.retain(|_, expires_at| *expires_at >= now);
+            nonces.pruned_at = now;
+        }
+        match nonces.expires_at.entry(nonce) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(expires_at);
+                true
+            }
+        }
+    }
+}
+
+/// The bytes gateways sign: the v2 handshake frame without the `x-nur-signature` entry, with
+/// the metadata keys in lowercase and sorted
+pub fn signed_message(
+    version: u8,
+    function_uuid: &[u8; 16],
+    last_deployment: u64,
+    metadata: &HashMap<String, String>,
+) -> Vec<u8> {
+    let mut entries: Vec<_> = metadata
+        .iter()
+        .filter(|(key, _)| *key != SIGNATURE_KEY)
+        .collect();
+    entries.sort();
+
+    let mut message = vec![version];
+    message.extend_from_slice(function_uuid);
+    message.extend_from_slice(&last_deployment.to_be_bytes());
+    message.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+    for (key, value) in entries {
+        for s in [key, value] {
+            message.extend_from_slice(&(s.len() as u16).to_be_bytes());
+            message.extend_from_slice(s.as_bytes());
+        }
+    }
+    message
+}
+
+/// What a gateway sends as `x-nur-signature`
+#[cfg(test)]
+pub fn sign(secret: &str, message: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    encode_hex(hmac::sign(&key, message).as_ref())
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FUNCTION_UUID: [u8; 16] = [7; 16];
+
+    fn auth() -> GatewayAuth {
+        GatewayAuth {
+            keys: GatewayAuth::parse_keys("gw1=secret1; gw2=secret2").unwrap(),
+            max_skew_secs: 60,
+        }
+    }
+
+    /// Metadata of a handshake signed with `gw1`
+    fn signed(timestamp: u64, nonce: &str) -> (HashMap<String, String>, Vec<u8>) {
+        let mut metadata = HashMap::from([
+            ("x-request-id".to_string(), "abc".to_string()),
+            (KEY_ID_KEY.to_string(), "gw1".to_string()),
+            (TIMESTAMP_KEY.to_string(), timestamp.to_string()),
+            (NONCE_KEY.to_string(), nonce.to_string()),
+        ]);
+        let message = signed_message(2, &FUNCTION_UUID, 0, &metadata);
+        metadata.insert(SIGNATURE_KEY.to_string(), sign("secret1", &message));
+        (metadata, message)
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(GatewayAuth::parse_keys("a=1;b=2;").unwrap().len(), 2);
+        for keys in ["", "a", "a=", "=1", "a=1;a=2"] {
+            assert!(GatewayAuth::parse_keys(keys).is_err(), "{keys:?}");
+        }
+    }
+
+    #[test]
+    fn test_verify_bearer() {
+        let auth = auth();
+        let nonces = Nonces::default();
+        let bearer = |token: &str| {
+            HashMap::from([(AUTHORIZATION_KEY.to_string(), format!("Bearer {token}"))])
+        };
+
+        assert!(auth.verify(&bearer("secret2"), &[], &nonces).is_ok());
+        assert!(auth.verify(&bearer("secret3"), &[], &nonces).is_err());
+        assert!(auth.verify(&HashMap::new(), &[], &nonces).is_err());
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let auth = auth();
+        let nonces = Nonces::default();
+
+        let (metadata, message) = signed(unix_now(), "n1");
+        assert_eq!(auth.verify(&metadata, &message, &nonces), Ok(()));
+        // The same handshake again
+        assert!(auth.verify(&metadata, &message, &nonces).is_err());
+
+        // Another function than the one signed for
+        let (metadata, _) = signed(unix_now(), "n2");
+        let message = signed_message(2, &[8; 16], 0, &metadata);
+        assert_eq!(
+            auth.verify(&metadata, &message, &nonces),
+            Err("invalid signature".to_string())
+        );
+
+        let (metadata, message) = signed(unix_now() - 120, "n3");
+        assert!(auth.verify(&metadata, &message, &nonces).is_err());
+    }
+}
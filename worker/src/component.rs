@@ -0,0 +1,280 @@
+//! Component-model guests, which export the `nur:http/incoming-handler` interface of
+//! `worker/wit/nur-http.wit` instead of a legacy handler:
+//!
+//! ```wit
+//! handle: func(request: request) -> response;
+//! ```
+//!
+//! The worker reads the whole request, calls `handle` once and sends the returned response.
+//! wasmer only runs core modules, so the component is validated and its single core module is
+//! run directly. The call is lowered and lifted as the canonical ABI does for the core exports
+//! generated by `wit-bindgen`: `nur:http/incoming-handler#handle`, `cabi_realloc`, `memory` and
+//! the optional `cabi_post_nur:http/incoming-handler#handle`.
+//!
+//! Components can't import anything yet, so the `nur_*` and WASI imports are not available to
+//! them. Components built with the `wasi_snapshot_preview1` adapter import WASI and are refused.
+
+use crate::abi::GuestRequest;
+use std::ops::Range;
+use wasmer::{Instance, MemoryView, Store, Value};
+use wasmparser::{Encoding, Parser, Payload, Validator, WasmFeatures};
+
+pub const HANDLER_INTERFACE: &str = "nur:http/incoming-handler";
+pub const EXPORTED_HANDLE_SYMBOL_NAME: &str = "nur:http/incoming-handler#handle";
+const EXPORTED_POST_HANDLE_SYMBOL_NAME: &str = "cabi_post_nur:http/incoming-handler#handle";
+const EXPORTED_REALLOC_SYMBOL_NAME: &str = "cabi_realloc";
+
+/// Size of a `tuple<string, string>`, two pointer and length pairs
+const HEADER_SIZE: u32 = 16;
+/// Size of a `response`: `status` is padded to 4 bytes, then both lists
+const RESPONSE_SIZE: usize = 20;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct GuestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Validates the component in `wasm_bytes` and returns the bytes of its core module
+pub fn core_module(wasm_bytes: &[u8]) -> Result<&[u8], String> {
+    Validator::new_with_features(WasmFeatures::default())
+        .validate_all(wasm_bytes)
+        .map_err(|e| format!("invalid component: {e}"))?;
+
+    let mut modules: Vec<Range<usize>> = Vec::new();
+    let mut exports_handler = false;
+    // Nesting of the payloads, 1 for the sections of the component itself
+    let mut depth = 0;
+    for payload in Parser::new(0).parse_all(wasm_bytes) {
+        match payload.map_err(|e| format!("invalid component: {e}"))? {
+            Payload::Version { encoding, .. } => {
+                if depth == 0 && encoding != Encoding::Component {
+                    return Err("not a component".to_string());
+                }
+                depth += 1;
+            }
+            Payload::End(_) => depth -= 1,
+            Payload::ModuleSection {
+                unchecked_range, ..
+            } if depth == 1 => modules.push(unchecked_range),
+            Payload::ComponentSection { .. } if depth == 1 => {
+                return Err("nested components are not supported".to_string());
+            }
+            Payload::ComponentImportSection(reader) if depth == 1 && reader.count() > 0 => {
+                return Err(
+                    "components can't import anything yet, so the nur_* and WASI imports \
+                     (e.g. of the wasi_snapshot_preview1 adapter) are not available to them"
+                        .to_string(),
+                );
+            }
+            Payload::ComponentExportSection(reader) if depth == 1 => {
+                for export in reader {
+                    let export = export.map_err(|e| format!("invalid component: {e}"))?;
+                    exports_handler |= export.name.0 == HANDLER_INTERFACE;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if !exports_handler {
+        return Err(format!("component does not export {HANDLER_INTERFACE}"));
+    }
+    match modules.as_slice() {
+        [module] => Ok(&wasm_bytes[module.clone()]),
+        _ => Err(format!(
+            "components must contain exactly one core module, found {}",
+            modules.len()
+        )),
+    }
+}
+
+/// Calls the `handle` export of the core module instance with `request`
+pub fn handle(
+    store: &mut Store,
+    instance: &Instance,
+    request: &GuestRequest,
+) -> Result<GuestResponse, String> {
+    let export = |name: &str| {
+        instance
+            .exports
+            .get_function(name)
+            .cloned()
+            .map_err(|e| format!("missing export '{name}': {e}"))
+    };
+    let handle = export(EXPORTED_HANDLE_SYMBOL_NAME)?;
+    let realloc = export(EXPORTED_REALLOC_SYMBOL_NAME)?;
+    let memory = instance
+        .exports
+        .get_memory("memory")
+        .map_err(|e| format!("missing export 'memory': {e}"))?
+        .clone();
+
+    let mut lower = |bytes: &[u8], align: i32| -> Result<[Value; 2], String> {
+        let len = i32::try_from(bytes.len()).map_err(|_| "request too large".to_string())?;
+        let ptr = match realloc
+            .call(
+                store,
+                &[
+                    Value::I32(0),
+                    Value::I32(0),
+                    Value::I32(align),
+                    Value::I32(len),
+                ],
+            )
+            .map_err(|e| format!("Call error: {EXPORTED_REALLOC_SYMBOL_NAME}({len}): {e}"))?
+            .first()
+        {
+            Some(Value::I32(ptr)) => *ptr,
+            _ => return Err(format!("{EXPORTED_REALLOC_SYMBOL_NAME} must return an i32")),
+        };
+        memory
+            .view(store)
+            .write(ptr as u32 as u64, bytes)
+            .map_err(|e| format!("Failed to write to WASM memory at &{ptr}: {e}"))?;
+        Ok([Value::I32(ptr), Value::I32(len)])
+    };
+
+    let mut headers = Vec::with_capacity(request.headers.len() * HEADER_SIZE as usize);
+    for (name, value) in &request.headers {
+        for s in [name, value] {
+            for value in lower(s.as_bytes(), 1)? {
+                headers.extend_from_slice(&value.unwrap_i32().to_le_bytes());
+            }
+        }
+    }
+
+    let mut params = Vec::with_capacity(8);
+    params.extend(lower(request.method.as_bytes(), 1)?);
+    params.extend(lower(request.path.as_bytes(), 1)?);
+    let [headers_ptr, _] = lower(&headers, 4)?;
+    params.extend([headers_ptr, Value::I32(request.headers.len() as i32)]);
+    params.extend(lower(&request.body, 1)?);
+
+    let result = handle
+        .call(store, &params)
+        .map_err(|e| format!("Call error: {EXPORTED_HANDLE_SYMBOL_NAME}(): {e}"))?;
+    let Some(Value::I32(response_ptr)) = result.first() else {
+        return Err(format!("{EXPORTED_HANDLE_SYMBOL_NAME} must return an i32"));
+    };
+    let response = lift_response(&memory.view(store), *response_ptr as u32);
+
+    // Lets the guest free the response, whether it could be read or not
+    if let Ok(post_handle) = instance
+        .exports
+        .get_function(EXPORTED_POST_HANDLE_SYMBOL_NAME)
+        && let Err(e) = post_handle.call(store, &[Value::I32(*response_ptr)])
+    {
+        log::error!("Call error: {EXPORTED_POST_HANDLE_SYMBOL_NAME}(): {e}");
+    }
+    response
+}
+
+fn lift_response(view: &MemoryView, ptr: u32) -> Result<GuestResponse, String> {
+    let record = read(view, ptr, RESPONSE_SIZE as u32)?;
+    let u32_at = |bytes: &[u8], offset: usize| {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    };
+
+    let (headers_ptr, headers_len) = (u32_at(&record, 4), u32_at(&record, 8));
+    let size = headers_len
+        .checked_mul(HEADER_SIZE)
+        .ok_or("headers out of bounds")?;
+    let entries = read(view, headers_ptr, size)?;
+    let mut headers = Vec::with_capacity(headers_len as usize);
+    for entry in entries.chunks_exact(HEADER_SIZE as usize) {
+        headers.push((
+            read_string(view, u32_at(entry, 0), u32_at(entry, 4))?,
+            read_string(view, u32_at(entry, 8), u32_at(entry, 12))?,
+        ));
+    }
+
+    Ok(GuestResponse {
+        status: u16::from_le_bytes([record[0], record[1]]),
+        headers,
+        body: read(view, u32_at(&record, 12), u32_at(&record, 16))?,
+    })
+}
+
+/// Reads `len` bytes at `ptr`, checking the range before allocating the buffer
+fn read(view: &MemoryView, ptr: u32, len: u32) -> Result<Vec<u8>, String> {
+    match (ptr as u64).checked_add(len as u64) {
+        Some(end) if end <= view.data_size() => {}
+        _ => return Err(format!("range &{ptr}+{len} is out of bounds")),
+    }
+    let mut buf = vec![0; len as usize];
+    view.read(ptr as u64, &mut buf)
+        .map_err(|e| format!("Failed to read WASM memory at &{ptr}: {e}"))?;
+    Ok(buf)
+}
+
+fn read_string(view: &MemoryView, ptr: u32, len: u32) -> Result<String, String> {
+    String::from_utf8(read(view, ptr, len)?).map_err(|e| format!("invalid string: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::fixtures;
+
+    #[test]
+    fn test_core_module() {
+        let wat = fixtures::hello_component();
+        let component = wasmer::wat2wasm(wat.as_bytes()).unwrap();
+        let module = core_module(&component).unwrap();
+        assert!(wasmer::Module::validate(&Store::default(), module).is_ok());
+
+        let module = wasmer::wat2wasm(b"(module)").unwrap();
+        assert_eq!(core_module(&module).unwrap_err(), "not a component");
+
+        let component = wasmer::wat2wasm(b"(component (core module))").unwrap();
+        assert_eq!(
+            core_module(&component).unwrap_err(),
+            "component does not export nur:http/incoming-handler"
+        );
+
+        let component = wasmer::wat2wasm(b"(component (import \"env\" (func)))").unwrap();
+        assert!(
+            core_module(&component)
+                .unwrap_err()
+                .starts_with("components can't import anything yet")
+        );
+    }
+
+    #[test]
+    fn test_handle() {
+        let wat = fixtures::hello_component();
+        let component = wasmer::wat2wasm(wat.as_bytes()).unwrap();
+        let mut store = Store::default();
+        let module = wasmer::Module::new(&store, core_module(&component).unwrap()).unwrap();
+        let instance = Instance::new(&mut store, &module, &wasmer::imports! {}).unwrap();
+
+        let request = GuestRequest {
+            method: "GET".to_string(),
+            path: "/cows".to_string(),
+            headers: vec![("host".to_string(), "nur".to_string())],
+            body: Vec::new(),
+        };
+        assert_eq!(
+            handle(&mut store, &instance, &request).unwrap(),
+            GuestResponse {
+                status: 200,
+                headers: vec![("content-type".to_string(), "text/plain".to_string())],
+                body: b"/cows".to_vec(),
+            }
+        );
+
+        // A header name pointing past the end of memory
+        let memory = instance.exports.get_memory("memory").unwrap();
+        memory
+            .view(&store)
+            .write(64, &u32::MAX.to_le_bytes())
+            .unwrap();
+        assert!(
+            handle(&mut store, &instance, &request)
+                .unwrap_err()
+                .contains("out of bounds")
+        );
+    }
+}
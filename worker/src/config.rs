@@ -0,0 +1,588 @@
+//! Worker settings. Each one is read, from lowest to highest precedence, from its default, the
+//! config file, the environment variable named after it in upper case and its command line flag:
+//!
+//! ```text
+//! # nur.toml, passed with --config nur.toml or NUR_CONFIG=nur.toml
+//! port = 7000
+//! kv_backend = "memory"
+//! ```
+//!
+//! `PORT=7001` then overrides the file and `--port 7002` (or `--port=7002`) overrides both.
+//! YAML files (`.yaml`, `.yml`) are read the same way. Everything is validated before the
+//! worker starts, so a typo fails right away instead of when the setting is first used.
+
+use crate::auth::GatewayAuth;
+use crate::kv::KvBackend;
+use crate::outbound::EgressPolicy;
+use crate::secrets::SecretsBackend;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Environment variable naming the config file when there is no `--config` flag
+const CONFIG_FILE_VAR: &str = "NUR_CONFIG";
+
+/// Picks the default of a setting, when it has one
+macro_rules! default {
+    () => {
+        None
+    };
+    ($default:literal) => {
+        Some($default)
+    };
+}
+
+macro_rules! settings {
+    ($(
+        $(#[doc = $doc:literal])*
+        $name:ident: $ty:ty $(= $default:literal)?,
+    )*) => {
+        #[derive(Clone)]
+        pub struct Config {
+            $( $(#[doc = $doc])* pub $name: $ty, )*
+        }
+
+        impl Config {
+            /// Every setting, as named in config files
+            const NAMES: &[&str] = &[$(stringify!($name)),*];
+
+            /// Whether the flag of setting `name` can be given without a value
+            fn is_switch(name: &str) -> bool {
+                $( if name == stringify!($name) { return <$ty as Setting>::SWITCH; } )*
+                false
+            }
+
+            /// Names of the settings that differ in `other`
+            pub fn changed(&self, other: &Config) -> Vec<&'static str> {
+                let mut changed = Vec::new();
+                $( if self.$name != other.$name { changed.push(stringify!($name)); } )*
+                changed
+            }
+
+            /// Copies the settings named in `names` from `other`
+            pub fn update(&mut self, other: &Config, names: &[&str]) {
+                $( if names.contains(&stringify!($name)) { self.$name = other.$name.clone(); } )*
+            }
+
+            fn from_layers(layers: &Layers) -> Result<Self, String> {
+                Ok(Config {
+                    $( $name: layers.parse(stringify!($name), default!($($default)?))?, )*
+                })
+            }
+        }
+    };
+}
+
+settings! {
+    /// The host where the worker will be listening
+    host: String = "0.0.0.0",
+
+    /// The port where the worker will be listening
+    port: u16 = "6969",
+
+    /// PEM certificate chain and key of the gateway listener, which is plaintext when unset
+    tls_cert_file: Option<String>,
+
+    tls_key_file: Option<String>,
+
+    /// PEM bundle of the CAs issuing gateway client certificates. When set, connections without
+    /// a valid client certificate are rejected.
+    tls_client_ca_file: Option<String>,
+
+    /// `key_id=secret` pairs separated by `;` gateways authenticate with, see [crate::auth].
+    /// Any gateway is accepted when unset.
+    gateway_auth_keys: Option<String>,
+
+    /// Largest difference in seconds between the timestamp of a signed handshake and the
+    /// worker clock
+    gateway_auth_max_skew_secs: u64 = "300",
+
+    /// The port where Prometheus metrics are served
+    metrics_port: u16 = "9464",
+
+    /// The port where the admin API (health, readiness, cache management) is served
+    admin_port: u16 = "6970",
+
+    /// The host where the admin API listens. It is unauthenticated, so only local by default.
+    admin_host: String = "127.0.0.1",
+
+    /// Static S3 credentials. When unset, credentials come from the default AWS chain:
+    /// `AWS_*` variables, profile files, web identity tokens or the instance profile.
+    s3_access_key_id: Option<String>,
+
+    s3_secret_access_key: Option<String>,
+
+    /// Session token of temporary `s3_access_key_id` credentials
+    s3_session_token: Option<String>,
+
+    s3_region: String = "us-east-2",
+
+    cache_dir: String = ".cache",
+
+    /// `sslmode` is read from the url, see [crate::tls]
+    postgres_url: Option<String>,
+
+    /// PEM bundle of the roots trusted for the Postgres server certificate
+    postgres_ca_file: Option<String>,
+
+    /// PEM client certificate presented to Postgres, along with `postgres_client_key_file`
+    postgres_client_cert_file: Option<String>,
+
+    postgres_client_key_file: Option<String>,
+
+    /// OTLP/HTTP collector base url, e.g. `http://localhost:4318`. Tracing is disabled when unset.
+    otel_exporter_otlp_endpoint: Option<String>,
+
+    otel_service_name: String = "nur-worker",
+
+    /// Maximum number of function logs waiting to be sent before new ones are dropped
+    log_queue_capacity: usize = "4096",
+
+    /// Maximum number of invocations running at once, 0 for unlimited
+    max_concurrent_invocations: usize = "256",
+
+    /// Maximum number of invocations of a single function running at once, 0 for unlimited
+    max_concurrent_per_function: usize = "0",
+
+    /// Seconds in-flight invocations are given to finish after a shutdown signal
+    drain_timeout_secs: u64 = "30",
+
+    /// Seconds pending function logs are given to be sent before exiting
+    log_flush_timeout_secs: u64 = "5",
+
+    /// Number of concurrent tasks sending function logs
+    log_workers: usize = "8",
+
+    /// Exposes the `wasi_snapshot_preview1` imports to guest modules
+    wasi_enabled: bool = "false",
+
+    /// Directory snapshotted at startup into the read-only filesystem seen by WASI guests
+    wasi_root_dir: Option<String>,
+
+    /// Largest request body accepted for `handle_request` guests
+    max_request_body_bytes: usize = "8388608",
+
+    /// Hosts functions may reach with `nur_http_fetch`, e.g. `*=api.example.com;<function_uuid>=*.example.org`.
+    /// Every host is denied when unset.
+    http_egress_allowlist: Option<String>,
+
+    /// Milliseconds an outbound request is given, from connecting to reading the whole body
+    http_fetch_timeout_ms: u64 = "10000",
+
+    /// Largest response body an outbound request may return
+    http_fetch_max_response_bytes: usize = "10485760",
+
+    /// Storage behind the `nur_kv_*` imports, `postgres` or `memory`
+    kv_backend: KvBackend = "postgres",
+
+    /// Largest value a function may store under a single key
+    kv_max_value_bytes: usize = "65536",
+
+    /// Source of the variables behind `nur_env_get`, `env`, `postgres` or `file`
+    secrets_backend: SecretsBackend = "env",
+
+    /// Worker variables starting with this prefix are given to every function with `secrets_backend = "env"`
+    secrets_env_prefix: String = "NUR_ENV_",
+
+    /// JSON file read with `secrets_backend = "file"`
+    secrets_file: Option<String>,
+
+    /// Longest single `nur_sleep_ms` call, longer sleeps are cut short
+    max_sleep_ms: u64 = "30000",
+
+    /// Log filter with the `RUST_LOG` syntax, e.g. `info,nur_worker::server=debug`, applied on
+    /// top of `RUST_LOG`
+    log_level: Option<String>,
+}
+
+/// Limits applied to every invocation
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_request_body_bytes: usize,
+    pub kv_max_value_bytes: usize,
+    pub max_sleep_ms: u64,
+}
+
+/// A value a setting can be parsed into
+trait Setting: Sized {
+    /// A flag without a value sets the setting to `true`
+    const SWITCH: bool = false;
+
+    /// `raw` is [None] when the setting was given nowhere and has no default
+    fn parse(raw: Option<&str>) -> Result<Self, String>;
+}
+
+macro_rules! from_str_setting {
+    ($($ty:ty),*) => {
+        $(
+            impl Setting for $ty {
+                fn parse(raw: Option<&str>) -> Result<Self, String> {
+                    let raw = raw.ok_or("must be set")?;
+                    <$ty>::from_str(raw).map_err(|e| format!("{raw:?} is invalid: {e}"))
+                }
+            }
+        )*
+    };
+}
+
+from_str_setting!(u16, u64, usize, String, KvBackend, SecretsBackend);
+
+impl Setting for bool {
+    const SWITCH: bool = true;
+
+    fn parse(raw: Option<&str>) -> Result<Self, String> {
+        let raw = raw.ok_or("must be set")?;
+        bool::from_str(raw).map_err(|e| format!("{raw:?} is invalid: {e}"))
+    }
+}
+
+impl Setting for Option<String> {
+    fn parse(raw: Option<&str>) -> Result<Self, String> {
+        Ok(raw.map(str::to_string))
+    }
+}
+
+/// The raw value of every setting given somewhere, along with where it was given
+#[derive(Default)]
+struct Layers(HashMap<String, (String, String)>);
+
+impl Layers {
+    /// Later calls win, so layers are set from lowest to highest precedence
+    fn set(&mut self, name: &str, value: String, source: String) {
+        self.0.insert(name.to_string(), (value, source));
+    }
+
+    fn parse<T: Setting>(&self, name: &str, default: Option<&str>) -> Result<T, String> {
+        match self.0.get(name) {
+            Some((value, source)) => {
+                T::parse(Some(value)).map_err(|e| format!("{name} from {source}: {e}"))
+            }
+            None => T::parse(default).map_err(|e| format!("{name}: {e}")),
+        }
+    }
+
+    /// Reads a flat TOML or YAML file of settings, picked by its extension
+    fn read_file(&mut self, path: &Path) -> Result<(), String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {e}", path.display()))?;
+        let invalid =
+            |e: &dyn std::fmt::Display| format!("invalid config file {}: {e}", path.display());
+
+        let values: Vec<(String, Option<String>)> =
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("toml") => toml::from_str::<toml::Table>(&contents)
+                    .map_err(|e| invalid(&e))?
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let value = match value {
+                            toml::Value::String(s) => Some(s),
+                            toml::Value::Integer(n) => Some(n.to_string()),
+                            toml::Value::Float(n) => Some(n.to_string()),
+                            toml::Value::Boolean(b) => Some(b.to_string()),
+                            _ => None,
+                        };
+                        (name, value)
+                    })
+                    .collect(),
+                Some("yaml" | "yml") => {
+                    serde_yaml::from_str::<HashMap<String, serde_yaml::Value>>(&contents)
+                        .map_err(|e| invalid(&e))?
+                        .into_iter()
+                        .filter(|(_, value)| !value.is_null())
+                        .map(|(name, value)| {
+                            let value = match value {
+                                serde_yaml::Value::String(s) => Some(s),
+                                serde_yaml::Value::Number(n) => Some(n.to_string()),
+                                serde_yaml::Value::Bool(b) => Some(b.to_string()),
+                                _ => None,
+                            };
+                            (name, value)
+                        })
+                        .collect()
+                }
+                _ => {
+                    return Err(format!(
+                        "config file {} must end in .toml, .yaml or .yml",
+                        path.display()
+                    ));
+                }
+            };
+
+        for (name, value) in values {
+            if !Config::NAMES.contains(&name.as_str()) {
+                return Err(invalid(&format!("unknown setting {name:?}")));
+            }
+            let value = value.ok_or_else(|| {
+                invalid(&format!("{name} must be a string, a number or a boolean"))
+            })?;
+            self.set(&name, value, format!("config file {}", path.display()));
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Reads every layer. `args` are the command line arguments after the program name,
+    /// the ones that are not settings are returned in order.
+    pub fn load(args: &[String]) -> Result<(Self, Vec<String>), String> {
+        Config::load_from(args, |name| std::env::var(name).ok())
+    }
+
+    /// The config file given to [Config::load], if any
+    pub fn file(args: &[String]) -> Result<Option<PathBuf>, String> {
+        let flags = Flags::parse(args, |name| std::env::var(name).ok())?;
+        Ok(flags.config_file.map(PathBuf::from))
+    }
+
+    fn load_from(
+        args: &[String],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<(Self, Vec<String>), String> {
+        let flags = Flags::parse(args, &env)?;
+
+        let mut layers = Layers::default();
+        if let Some(path) = flags.config_file {
+            layers.read_file(Path::new(&path))?;
+        }
+        for name in Config::NAMES {
+            let var = name.to_uppercase();
+            if let Some(value) = env(&var) {
+                layers.set(name, value, format!("environment variable {var}"));
+            }
+        }
+        for (name, value, source) in flags.settings {
+            layers.set(&name, value, source);
+        }
+
+        let config = Config::from_layers(&layers)?;
+        config.validate()?;
+        Ok((config, flags.rest))
+    }
+
+    /// Checks the settings that are valid on their own but not together
+    fn validate(&self) -> Result<(), String> {
+        if self.log_queue_capacity == 0 {
+            return Err("log_queue_capacity must be at least 1".to_string());
+        }
+        if self.secrets_backend == SecretsBackend::File && self.secrets_file.is_none() {
+            return Err("secrets_backend file requires secrets_file".to_string());
+        }
+        if self.s3_access_key_id.is_some() != self.s3_secret_access_key.is_some() {
+            return Err(
+                "s3_access_key_id and s3_secret_access_key must be set together".to_string(),
+            );
+        }
+        if self.s3_session_token.is_some() && self.s3_access_key_id.is_none() {
+            return Err("s3_session_token requires s3_access_key_id".to_string());
+        }
+        if self.postgres_client_cert_file.is_some() != self.postgres_client_key_file.is_some() {
+            return Err(
+                "postgres_client_cert_file and postgres_client_key_file must be set together"
+                    .to_string(),
+            );
+        }
+        if self.tls_cert_file.is_some() != self.tls_key_file.is_some() {
+            return Err("tls_cert_file and tls_key_file must be set together".to_string());
+        }
+        if self.tls_client_ca_file.is_some() && self.tls_cert_file.is_none() {
+            return Err("tls_client_ca_file requires tls_cert_file".to_string());
+        }
+        GatewayAuth::from_config(self).map_err(|e| format!("gateway_auth_keys: {e}"))?;
+        if let Some(rules) = &self.http_egress_allowlist {
+            EgressPolicy::parse(rules).map_err(|e| format!("http_egress_allowlist: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Checks the settings only the gateway facing worker needs, the dev runner does without them
+    pub fn validate_worker(&self) -> Result<(), String> {
+        required("postgres_url", &self.postgres_url)?;
+        Ok(())
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_request_body_bytes: self.max_request_body_bytes,
+            kv_max_value_bytes: self.kv_max_value_bytes,
+            max_sleep_ms: self.max_sleep_ms,
+        }
+    }
+}
+
+/// The command line split into the config file, settings and everything else
+struct Flags {
+    config_file: Option<String>,
+    /// Name, value and flag of every setting given
+    settings: Vec<(String, String, String)>,
+    rest: Vec<String>,
+}
+
+impl Flags {
+    fn parse(args: &[String], env: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut config_file = env(CONFIG_FILE_VAR);
+        let mut settings = Vec::new();
+        let mut rest = Vec::new();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                rest.push(arg.clone());
+                continue;
+            };
+            let (flag, value) = match flag.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (flag, None),
+            };
+            let name = flag.replace('-', "_");
+            if name != "config" && !Config::NAMES.contains(&name.as_str()) {
+                return Err(format!("unknown flag --{flag}"));
+            }
+            let value = match value {
+                Some(value) => value,
+                // `--wasi-enabled dev` must not take `dev` as its value
+                None if Config::is_switch(&name) => "true".to_string(),
+                None => args
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| format!("--{flag} needs a value"))?,
+            };
+            match name.as_str() {
+                "config" => config_file = Some(value),
+                _ => settings.push((name, value, format!("--{flag}"))),
+            }
+        }
+        Ok(Flags {
+            config_file,
+            settings,
+            rest,
+        })
+    }
+}
+
+/// Only the defaults
+impl Default for Config {
+    fn default() -> Self {
+        Config::from_layers(&Layers::default()).expect("Invalid default settings")
+    }
+}
+
+/// The value of an optional setting that is needed after all
+pub fn required<'a>(name: &str, value: &'a Option<String>) -> Result<&'a str, String> {
+    value.as_deref().ok_or_else(|| {
+        format!(
+            "{name} must be set, in the config file, as {} or with --{}",
+            name.to_uppercase(),
+            name.replace('_', "-")
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn load(args: &[String], env: &[(&str, &str)]) -> Result<(Config, Vec<String>), String> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Config::load_from(args, |name| env.get(name).cloned())
+    }
+
+    fn config_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("nur-config-{}-{name}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_layers() {
+        let toml = config_file(
+            "nur.toml",
+            "port = 7000\nlog_workers = 2\nwasi_enabled = true\nkv_backend = \"memory\"",
+        );
+
+        let (config, rest) = load(&args(&["dev", "--config", &toml, "cow.wasm"]), &[]).unwrap();
+        assert_eq!(rest, args(&["dev", "cow.wasm"]));
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.log_workers, 2);
+        assert!(config.wasi_enabled);
+        assert_eq!(config.kv_backend, KvBackend::Memory);
+        assert_eq!(config.host, "0.0.0.0");
+        assert_eq!(config.postgres_url, None);
+
+        let env = [("NUR_CONFIG", toml.as_str()), ("PORT", "7001")];
+        let (config, _) = load(&[], &env).unwrap();
+        assert_eq!(config.port, 7001);
+        assert_eq!(config.log_workers, 2);
+
+        let (config, _) = load(&args(&["--port=7002", "--log-workers", "3"]), &env).unwrap();
+        assert_eq!(config.port, 7002);
+        assert_eq!(config.log_workers, 3);
+
+        let (config, rest) = load(&args(&["--wasi-enabled", "dev", "cow.wasm"]), &[]).unwrap();
+        assert!(config.wasi_enabled);
+        assert_eq!(rest, args(&["dev", "cow.wasm"]));
+        let (config, _) = load(&args(&["--wasi-enabled=false"]), &env).unwrap();
+        assert!(!config.wasi_enabled);
+
+        let yaml = config_file(
+            "nur.yaml",
+            "port: 7003\nsecrets_file: ~\ncache_dir: /tmp/nur",
+        );
+        let (config, _) = load(&args(&["--config", &yaml]), &[]).unwrap();
+        assert_eq!(config.port, 7003);
+        assert_eq!(config.cache_dir, "/tmp/nur");
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |args: &[String], env: &[(&str, &str)]| load(args, env).err().unwrap();
+
+        assert_eq!(
+            error(&[], &[("PORT", "mu")]),
+            r#"port from environment variable PORT: "mu" is invalid: invalid digit found in string"#
+        );
+        assert_eq!(
+            error(&args(&["--kv-backend", "redis"]), &[]),
+            r#"kv_backend from --kv-backend: "redis" is invalid: expected postgres or memory"#
+        );
+        assert_eq!(error(&args(&["--prot", "1"]), &[]), "unknown flag --prot");
+        assert_eq!(error(&args(&["--port"]), &[]), "--port needs a value");
+        assert_eq!(
+            Config::file(&args(&["--config"])).unwrap_err(),
+            "--config needs a value"
+        );
+        assert_eq!(
+            error(&[], &[("SECRETS_BACKEND", "file")]),
+            "secrets_backend file requires secrets_file"
+        );
+        assert_eq!(
+            error(&[], &[("S3_ACCESS_KEY_ID", "AKIA")]),
+            "s3_access_key_id and s3_secret_access_key must be set together"
+        );
+        assert!(
+            error(&[], &[("HTTP_EGRESS_ALLOWLIST", "cow")]).starts_with("http_egress_allowlist: ")
+        );
+        assert_eq!(
+            error(&[], &[("GATEWAY_AUTH_KEYS", "gw=1;gw=2")]),
+            r#"gateway_auth_keys: duplicate key id "gw""#
+        );
+
+        let toml = config_file("nur.toml", "prot = 7000");
+        assert!(error(&args(&["--config", &toml]), &[]).ends_with(r#"unknown setting "prot""#));
+        let json = config_file("nur.json", "{}");
+        assert!(
+            error(&args(&["--config", &json]), &[]).ends_with("must end in .toml, .yaml or .yml")
+        );
+
+        assert_eq!(
+            Config::default().validate_worker().err().unwrap(),
+            "postgres_url must be set, in the config file, as POSTGRES_URL or with --postgres-url"
+        );
+    }
+}
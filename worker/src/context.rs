@@ -0,0 +1,130 @@
+//! Invocation details exposed through the `nur_context_get` import.
+//!
+//! - `nur_context_get(key_ptr, key_len, buf_ptr, buf_len) -> len`: writes the UTF-8 value of `key`
+//!   and returns its length. When `buf_len` is too small nothing is written, call again with the
+//!   returned length. Unknown keys return -1, and keys or buffers outside of the guest memory -2.
+//!
+//! Keys are `function_uuid`, `deployment_timestamp` (UNIX seconds), `client_addr` (the peer of the
+//! worker connection), `request_id` and `metadata.<name>` for entries of a v2 handshake, without
+//! the credentials of [crate::auth].
+
+use crate::intrinsics::{NurFunctionEnv, read_guest, write_if_fits};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use uuid::Uuid;
+use wasmer::FunctionEnvMut;
+
+/// Metadata key the gateway uses for its request id
+const REQUEST_ID_KEY: &str = "x-request-id";
+
+const METADATA_PREFIX: &str = "metadata.";
+
+const CONTEXT_NOT_FOUND: i32 = -1;
+const CONTEXT_OUT_OF_BOUNDS: i32 = -2;
+
+pub struct InvocationContext {
+    pub function_uuid: Uuid,
+    pub deployment_timestamp: u64,
+    pub client_addr: SocketAddr,
+    /// The gateway's `x-request-id`, or the worker's invocation id without one
+    pub request_id: String,
+    pub metadata: HashMap<String, String>,
+}
+
+impl InvocationContext {
+    pub fn new(
+        function_uuid: Uuid,
+        deployment_timestamp: u64,
+        client_addr: SocketAddr,
+        invocation_id: Uuid,
+        metadata: HashMap<String, String>,
+    ) -> Self {
+        let request_id = metadata
+            .get(REQUEST_ID_KEY)
+            .cloned()
+            .unwrap_or_else(|| invocation_id.to_string());
+        InvocationContext {
+            function_uuid,
+            deployment_timestamp,
+            client_addr,
+            request_id,
+            metadata,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        match key {
+            "function_uuid" => Some(self.function_uuid.to_string()),
+            "deployment_timestamp" => Some(self.deployment_timestamp.to_string()),
+            "client_addr" => Some(self.client_addr.to_string()),
+            "request_id" => Some(self.request_id.clone()),
+            _ => {
+                let name = key.strip_prefix(METADATA_PREFIX)?;
+                self.metadata.get(&name.to_ascii_lowercase()).cloned()
+            }
+        }
+    }
+}
+
+pub fn nur_context_get(
+    env: FunctionEnvMut<NurFunctionEnv>,
+    key_ptr: i32,
+    key_len: i32,
+    buf_ptr: i32,
+    buf_len: i32,
+) -> i32 {
+    log::trace!("nur_context_get({key_ptr}, {key_len}, {buf_ptr}, {buf_len})");
+    let Ok(key) = read_guest(&env, key_ptr, key_len) else {
+        return CONTEXT_OUT_OF_BOUNDS;
+    };
+    let Some(value) = String::from_utf8(key)
+        .ok()
+        .and_then(|key| env.data().context.get(&key))
+    else {
+        return CONTEXT_NOT_FOUND;
+    };
+    write_if_fits(&env, value.as_bytes(), buf_ptr, buf_len).unwrap_or(CONTEXT_OUT_OF_BOUNDS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_keys() {
+        let function_uuid = Uuid::new_v4();
+        let invocation_id = Uuid::new_v4();
+        let addr = "10.0.0.1:4242".parse().unwrap();
+
+        let context = InvocationContext::new(
+            function_uuid,
+            1700000000,
+            addr,
+            invocation_id,
+            HashMap::new(),
+        );
+        assert_eq!(
+            context.get("function_uuid"),
+            Some(function_uuid.to_string())
+        );
+        assert_eq!(
+            context.get("deployment_timestamp"),
+            Some("1700000000".to_string())
+        );
+        assert_eq!(
+            context.get("client_addr"),
+            Some("10.0.0.1:4242".to_string())
+        );
+        assert_eq!(context.get("request_id"), Some(invocation_id.to_string()));
+        assert_eq!(context.get("metadata.x-request-id"), None);
+        assert_eq!(context.get("cow"), None);
+
+        let metadata = HashMap::from([
+            ("x-request-id".to_string(), "req-1".to_string()),
+            ("x-region".to_string(), "mx".to_string()),
+        ]);
+        let context = InvocationContext::new(function_uuid, 0, addr, invocation_id, metadata);
+        assert_eq!(context.get("request_id"), Some("req-1".to_string()));
+        assert_eq!(context.get("metadata.X-Region"), Some("mx".to_string()));
+    }
+}
@@ -0,0 +1,236 @@
+//! `nur_worker dev <file.wasm> [--port <port>]`: serves a local module over plain HTTP.
+//! `nur_worker up [nurfile.yaml] [--port <port>]` does the same for every function of a
+//! [Nurfile], after building them. Both take every other [Config] flag too.
+//!
+//! No gateway, Postgres or S3 is involved. Guest logs are printed to the terminal, `nur_kv_*`
+//! is kept in memory and `nur_env_get` reads the `secrets_env_prefix` worker variables.
+//! The module is reloaded whenever the file changes; invocations run through the same
+//! [run_invocation] path as gateway connections.
+
+use crate::config::Config;
+use crate::context::InvocationContext;
+use crate::fetcher::FetchedFunction;
+use crate::kv::MemoryKvStore;
+use crate::logs_service::{LogQueue, TerminalLogService};
+use crate::nurfile::Nurfile;
+use crate::secrets::{EnvSecretsProvider, SecretsProvider};
+use crate::server::{ExecutionState, run_invocation};
+use crate::trace::{Span, SpanKind};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// How often the module file is checked for changes
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+const USAGE: &str = "usage: nur_worker dev <file.wasm> [--port <port>]\n       \
+                     nur_worker up [nurfile.yaml] [--port <port>]";
+
+const NURFILE: &str = "nurfile.yaml";
+
+/// The module being served and the modification time it was loaded from
+struct LoadedFunction {
+    func: FetchedFunction,
+    modified: Option<SystemTime>,
+}
+
+pub async fn run(config: &Config, args: &[String]) -> Result<(), String> {
+    let path = parse_args(args, None)?;
+    let function_uuid = Uuid::new_v4();
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let execution = execution_state(config, HashMap::from([(function_uuid, name.to_string())]))?;
+    let secrets = EnvSecretsProvider::new(config.secrets_env_prefix.clone());
+
+    tokio::select! {
+        served = serve(path, config.port, function_uuid, execution.clone(), secrets) => served?,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    execution.log_queue.flush(Duration::from_secs(1)).await;
+    Ok(())
+}
+
+/// `nur_worker up [nurfile.yaml] [--port <port>]`: builds every function of the nurfile and
+/// serves them on consecutive ports starting at `port`
+pub async fn up(config: &Config, args: &[String]) -> Result<(), String> {
+    let nurfile_path = parse_args(args, Some(NURFILE))?;
+    let nurfile = Nurfile::load(&nurfile_path).await?;
+    let root = nurfile_path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    for function in &nurfile.functions {
+        log::info!("🔨 Building {}...", function.name);
+        function.build(root).await?;
+    }
+
+    let function_uuids: Vec<Uuid> = nurfile.functions.iter().map(|_| Uuid::new_v4()).collect();
+    let execution = execution_state(
+        config,
+        function_uuids
+            .iter()
+            .zip(&nurfile.functions)
+            .map(|(function_uuid, function)| (*function_uuid, function.name.clone()))
+            .collect(),
+    )?;
+    let mut servers = tokio::task::JoinSet::new();
+    for ((function, function_uuid), port) in nurfile
+        .functions
+        .iter()
+        .zip(function_uuids)
+        .zip(config.port..)
+    {
+        servers.spawn(serve(
+            function.output(root),
+            port,
+            function_uuid,
+            execution.clone(),
+            EnvSecretsProvider::new(config.secrets_env_prefix.clone()),
+        ));
+    }
+
+    tokio::select! {
+        Some(served) = servers.join_next() => served.map_err(|e| e.to_string())??,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    execution.log_queue.flush(Duration::from_secs(1)).await;
+    Ok(())
+}
+
+/// `names` labels the logs of each function in the terminal
+fn execution_state(
+    config: &Config,
+    names: HashMap<Uuid, String>,
+) -> Result<ExecutionState, String> {
+    ExecutionState::new(
+        config,
+        LogQueue::spawn(Arc::new(TerminalLogService::new(names)), 1024, 1),
+        Arc::new(MemoryKvStore::default()),
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Serves the module at `path` on `port` until an error occurs, reloading it on changes
+async fn serve(
+    path: PathBuf,
+    port: u16,
+    function_uuid: Uuid,
+    execution: ExecutionState,
+    secrets: EnvSecretsProvider,
+) -> Result<(), String> {
+    let loaded = Arc::new(RwLock::new(load(&path, &secrets).await?));
+
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+        .await
+        .map_err(|e| format!("Failed to listen on 127.0.0.1:{port}: {e}"))?;
+    log::info!(
+        "🛠️ Serving {} at http://127.0.0.1:{port}, reloading on changes",
+        path.display()
+    );
+
+    let accept = async {
+        loop {
+            let (socket, addr) = listener.accept().await.map_err(|e| e.to_string())?;
+            let func = loaded.read().unwrap().func.clone();
+            let context = InvocationContext::new(
+                function_uuid,
+                func.fetched_at,
+                addr,
+                Uuid::new_v4(),
+                Default::default(),
+            );
+            log::info!("💌 Request started {addr}");
+            tokio::spawn(run_invocation(
+                socket,
+                addr,
+                execution.clone(),
+                func,
+                context,
+                Span::root("invocation", SpanKind::Server),
+            ));
+        }
+    };
+    tokio::select! {
+        served = accept => served,
+        _ = watch(path, secrets, loaded.clone()) => Ok(()),
+    }
+}
+
+/// `[path]`, the arguments left once [Config::load] took the flags.
+/// `path` is required unless there is a `default_path`.
+fn parse_args(args: &[String], default_path: Option<&str>) -> Result<PathBuf, String> {
+    match args {
+        [] => default_path
+            .map(PathBuf::from)
+            .ok_or_else(|| USAGE.to_string()),
+        [path] => Ok(PathBuf::from(path)),
+        [_, arg, ..] => Err(format!("unexpected argument {arg:?}\n{USAGE}")),
+    }
+}
+
+async fn load(path: &Path, secrets: &EnvSecretsProvider) -> Result<LoadedFunction, String> {
+    let modified = modified_at(path).await;
+    let wasm_bytes = tokio::fs::read(path)
+        .await
+        .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+    let mut func = FetchedFunction::try_precompile(Arc::from(wasm_bytes));
+    if !func.is_precompiled {
+        return Err(format!(
+            "{} is not a valid WebAssembly module",
+            path.display()
+        ));
+    }
+    func.env = Arc::new(secrets.load(&Uuid::nil()).await?);
+    Ok(LoadedFunction { func, modified })
+}
+
+async fn modified_at(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
+/// Swaps in a new copy of the module whenever its modification time changes.
+/// Invocations already running keep the version they started with.
+async fn watch(path: PathBuf, secrets: EnvSecretsProvider, loaded: Arc<RwLock<LoadedFunction>>) {
+    let mut interval = tokio::time::interval(RELOAD_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let modified = modified_at(&path).await;
+        if modified.is_none() || modified == loaded.read().unwrap().modified {
+            continue;
+        }
+        match load(&path, &secrets).await {
+            Ok(reloaded) => {
+                *loaded.write().unwrap() = reloaded;
+                log::info!("🔁 Reloaded {}", path.display());
+            }
+            Err(e) => {
+                // Remembered, so a half written file is retried on its next change only
+                loaded.write().unwrap().modified = modified;
+                log::error!("{e}, still serving the previous version");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            parse_args(&args(&["cow.wasm"]), None).unwrap(),
+            PathBuf::from("cow.wasm")
+        );
+        assert_eq!(
+            parse_args(&args(&[]), Some(NURFILE)).unwrap(),
+            PathBuf::from(NURFILE)
+        );
+        assert!(parse_args(&args(&[]), None).is_err());
+        assert!(parse_args(&args(&["cow.wasm", "goat.wasm"]), None).is_err());
+    }
+}
@@ -1,4 +1,8 @@
-use std::{collections::HashMap, sync::Arc};
+use crate::config::Config;
+use crate::metrics::{FetchTier, METRICS};
+use crate::secrets::{FunctionEnvVars, SecretsProvider};
+use crate::{abi, component};
+use std::{collections::HashMap, sync::Arc, time::Instant};
 use tokio::{io::AsyncReadExt, sync::RwLock};
 use uuid::Uuid;
 
@@ -13,6 +17,10 @@ pub struct FetchedFunction {
     pub wasm_bytes: Arc<[u8]>,
     pub is_precompiled: bool,
     pub fetched_at: u64,
+    /// Cache tier this copy was served from
+    pub tier: FetchTier,
+    /// Variables behind `nur_env_get`. Only ever cached in memory, never in the L2 directory.
+    pub env: Arc<FunctionEnvVars>,
     _private: (),
 }
 
@@ -21,46 +29,77 @@ pub struct FunctionFetcher {
     s3_client: aws_sdk_s3::Client,
     memory_cache: Arc<RwLock<HashMap<Uuid, FetchedFunction>>>,
     cache_dir: String,
+    secrets: Arc<dyn SecretsProvider>,
+}
+
+/// Bucket holding the compressed function builds
+const STORAGE_BUCKET: &str = "nur-storage";
+
+/// A function currently held by one of the [FunctionFetcher] cache tiers
+#[derive(Debug)]
+pub struct CachedFunction {
+    pub function_uuid: Uuid,
+    pub tier: FetchTier,
+    pub size: usize,
+    /// UNIX timestamp (seconds) at which this copy was fetched or written to disk
+    pub cached_at: u64,
 }
 
 #[derive(Debug)]
 pub enum FetchFunctionError {
     Download,
     Decompression,
+    Secrets,
 }
 
 pub trait FunctionFetch {
-    async fn fetch(
+    fn fetch(
         &self,
-        function_uuid: impl AsRef<Uuid>,
+        function_uuid: impl AsRef<Uuid> + Send,
         last_deployment_timestamp: u64,
-    ) -> Result<FetchedFunction, FetchFunctionError>;
+    ) -> impl Future<Output = Result<FetchedFunction, FetchFunctionError>> + Send;
 }
 
-impl FunctionFetcher {
-    pub async fn from_env() -> Result<Self, String> {
-        let credentials = aws_sdk_s3::config::Credentials::new(
-            crate::env::S3_ACCESS_KEY_ID.clone(),
-            crate::env::S3_SECRET_ACCESS_KEY.clone(),
-            None,
-            None,
-            "nur",
-        );
+/// Cache management and readiness checks behind the [crate::admin] API
+pub trait FunctionCache {
+    /// Lists the functions held by every cache tier
+    fn cached_functions(&self) -> impl Future<Output = Vec<CachedFunction>> + Send;
+
+    /// Removes a function from every cache tier. Returns whether anything was evicted.
+    fn evict(&self, function_uuid: &Uuid) -> impl Future<Output = bool> + Send;
+
+    /// Checks that modules can be downloaded
+    fn check_storage(&self) -> impl Future<Output = Result<(), String>> + Send;
+
+    /// Checks that modules can be cached
+    fn check_cache_dir(&self) -> impl Future<Output = Result<(), String>> + Send;
+}
 
-        let cache_dir = crate::env::CACHE_DIR.clone();
+impl FunctionFetcher {
+    pub async fn new(config: &Config, secrets: Arc<dyn SecretsProvider>) -> Result<Self, String> {
+        let cache_dir = config.cache_dir.clone();
 
         // Ensure the cache directory exists
         if let Err(e) = tokio::fs::create_dir_all(&cache_dir).await {
             return Err(format!("Failed to create cache directory: {e}"));
         }
 
-        let s3_region: &'static str = crate::env::S3_REGION.clone().leak();
-
-        let config = aws_config::defaults(aws_config::BehaviorVersion::v2025_01_17())
-            .region(s3_region)
-            .credentials_provider(credentials)
-            .load()
-            .await;
+        let mut aws_config = aws_config::defaults(aws_config::BehaviorVersion::v2025_01_17())
+            .region(aws_config::Region::new(config.s3_region.clone()));
+        match (&config.s3_access_key_id, &config.s3_secret_access_key) {
+            (Some(access_key_id), Some(secret_access_key)) => {
+                aws_config = aws_config.credentials_provider(aws_sdk_s3::config::Credentials::new(
+                    access_key_id,
+                    secret_access_key,
+                    config.s3_session_token.clone(),
+                    None,
+                    "nur",
+                ));
+            }
+            // Otherwise the default chain is used
+            _ => log::info!("🔑 No static S3 credentials, using the default AWS credential chain"),
+        }
+        let config = aws_config.load().await;
 
         let client = aws_sdk_s3::Client::new(&config);
 
@@ -70,8 +109,112 @@ impl FunctionFetcher {
             s3_client: client,
             memory_cache,
             cache_dir,
+            secrets,
         })
     }
+
+    /// Loads the variables of a function, so that they are cached along with its module
+    async fn load_env(
+        &self,
+        function_uuid: &Uuid,
+    ) -> Result<Arc<FunctionEnvVars>, FetchFunctionError> {
+        match self.secrets.load(function_uuid).await {
+            Ok(env) => Ok(Arc::new(env)),
+            Err(e) => {
+                log::error!("Failed to load variables of function {function_uuid}: {e}");
+                METRICS.fetch_errors.inc();
+                Err(FetchFunctionError::Secrets)
+            }
+        }
+    }
+
+    fn cache_filename(&self, function_uuid: &Uuid) -> String {
+        format!("{cache}/{function_uuid}.wasm.bin", cache = self.cache_dir)
+    }
+}
+
+impl FunctionCache for FunctionFetcher {
+    /// Lists the functions held in the L1 (memory) and L2 (filesystem) caches
+    async fn cached_functions(&self) -> Vec<CachedFunction> {
+        let mut cached: Vec<CachedFunction> = {
+            let memory_cache = self.memory_cache.read().await;
+            memory_cache
+                .iter()
+                .map(|(function_uuid, func)| CachedFunction {
+                    function_uuid: *function_uuid,
+                    tier: FetchTier::L1,
+                    size: func.wasm_bytes.len(),
+                    cached_at: func.fetched_at,
+                })
+                .collect()
+        };
+
+        let Ok(mut entries) = tokio::fs::read_dir(&self.cache_dir).await else {
+            return cached;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let file_name = entry.file_name();
+            let Some(function_uuid) = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".wasm.bin"))
+                .and_then(|uuid| Uuid::parse_str(uuid).ok())
+            else {
+                continue;
+            };
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            let cached_at = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            cached.push(CachedFunction {
+                function_uuid,
+                tier: FetchTier::L2,
+                size: metadata.len() as usize,
+                cached_at,
+            });
+        }
+        cached
+    }
+
+    /// Removes a function from every cache tier. Returns whether anything was evicted.
+    async fn evict(&self, function_uuid: &Uuid) -> bool {
+        let in_memory = self
+            .memory_cache
+            .write()
+            .await
+            .remove(function_uuid)
+            .is_some();
+        let on_disk = tokio::fs::remove_file(self.cache_filename(function_uuid))
+            .await
+            .is_ok();
+        in_memory || on_disk
+    }
+
+    /// Checks that the storage bucket can be reached with the configured credentials
+    async fn check_storage(&self) -> Result<(), String> {
+        self.s3_client
+            .head_bucket()
+            .bucket(STORAGE_BUCKET)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("{e}"))
+    }
+
+    /// Checks that modules can be written to the L2 cache directory
+    async fn check_cache_dir(&self) -> Result<(), String> {
+        let probe = format!("{cache}/.write-probe", cache = self.cache_dir);
+        tokio::fs::write(&probe, b"nur")
+            .await
+            .map_err(|e| e.to_string())?;
+        tokio::fs::remove_file(&probe)
+            .await
+            .map_err(|e| e.to_string())
+    }
 }
 
 impl FunctionFetch for FunctionFetcher {
@@ -81,10 +224,11 @@ impl FunctionFetch for FunctionFetcher {
     /// has been stored before this deployment date, then need to fetch the newest version.
     async fn fetch(
         &self,
-        function_uuid: impl AsRef<Uuid>,
+        function_uuid: impl AsRef<Uuid> + Send,
         last_deployment_timestamp: u64,
     ) -> Result<FetchedFunction, FetchFunctionError> {
         let function_uuid = function_uuid.as_ref();
+        let started_at = Instant::now();
 
         // Assumptions:
         // L1 cache may be precompiled
@@ -98,7 +242,11 @@ impl FunctionFetch for FunctionFetcher {
                 if cached_func.fetched_at >= last_deployment_timestamp {
                     // If the cached function is newer than the last deployment timestamp, use it
                     log::debug!("Using L1 in-memory cache for function {function_uuid}");
-                    return Ok(cached_func.clone());
+                    METRICS.fetch(FetchTier::L1, started_at.elapsed());
+                    return Ok(FetchedFunction {
+                        tier: FetchTier::L1,
+                        ..cached_func.clone()
+                    });
                 }
 
                 log::debug!("L1 In-memory cache is outdated for function={function_uuid}");
@@ -108,7 +256,7 @@ impl FunctionFetch for FunctionFetcher {
 
         // L2 cache: Lets see if we can use or local filesystem cache for this
         let mut use_local_cache = false;
-        let filename = format!("{cache}/{function_uuid}.wasm.bin", cache = self.cache_dir);
+        let filename = self.cache_filename(function_uuid);
 
         match tokio::fs::metadata(&filename).await {
             Ok(metadata) => {
@@ -144,20 +292,23 @@ impl FunctionFetch for FunctionFetcher {
                 .open(&filename)
                 .await;
 
-            if cached_file.is_ok() {
+            if let Ok(mut cached_file) = cached_file {
                 let mut cached_file_bytes = Vec::with_capacity(128);
-                let mut cached_file = cached_file.unwrap();
 
                 match cached_file.read_to_end(&mut cached_file_bytes).await {
                     Ok(_) => {
-                        let precompiled_func =
-                            FetchedFunction::from_precompiled_wasm(Arc::from(cached_file_bytes));
+                        let precompiled_func = FetchedFunction {
+                            tier: FetchTier::L2,
+                            env: self.load_env(function_uuid).await?,
+                            ..FetchedFunction::from_precompiled_wasm(Arc::from(cached_file_bytes))
+                        };
 
                         // Store in memory cache
                         {
                             let mut memory_cache = self.memory_cache.write().await;
                             memory_cache.insert(*function_uuid, precompiled_func.clone());
                         }
+                        METRICS.fetch(FetchTier::L2, started_at.elapsed());
                         return Ok(precompiled_func);
                     }
                     Err(e) => {
@@ -179,7 +330,7 @@ impl FunctionFetch for FunctionFetcher {
         let get_result = match self
             .s3_client
             .get_object()
-            .bucket("nur-storage")
+            .bucket(STORAGE_BUCKET)
             .key(remote_filename)
             .send()
             .await
@@ -187,6 +338,7 @@ impl FunctionFetch for FunctionFetcher {
             Ok(output) => output,
             Err(e) => {
                 log::error!("Failed to fetch wasm module from S3: {e}");
+                METRICS.fetch_errors.inc();
                 return Err(FetchFunctionError::Download);
             }
         };
@@ -200,24 +352,35 @@ impl FunctionFetch for FunctionFetcher {
             Ok(_) => {}
             Err(e) => {
                 log::error!("Failed to decompress wasm module from S3: {e}");
+                METRICS.fetch_errors.inc();
                 return Err(FetchFunctionError::Decompression);
             }
         }
-        let precompiled_func = FetchedFunction::try_precompile(Arc::from(wasm_bytes));
+        let precompiled_func = FetchedFunction {
+            env: self.load_env(function_uuid).await?,
+            ..FetchedFunction::try_precompile(Arc::from(wasm_bytes))
+        };
 
         // Save to L2 cache
         if let Err(e) = tokio::fs::write(&filename, &precompiled_func.wasm_bytes).await {
             log::error!("Failed to write wasm module to cache: {e}");
         }
 
+        // And to L1, so the next invocation (or a pre-warm) skips the filesystem
+        {
+            let mut memory_cache = self.memory_cache.write().await;
+            memory_cache.insert(*function_uuid, precompiled_func.clone());
+        }
+
+        METRICS.fetch(FetchTier::S3, started_at.elapsed());
         Ok(precompiled_func)
     }
 }
 
-impl FunctionFetch for &'_ FunctionFetcher {
+impl<F: FunctionFetch + Sync> FunctionFetch for &'_ F {
     async fn fetch(
         &self,
-        function_uuid: impl AsRef<Uuid>,
+        function_uuid: impl AsRef<Uuid> + Send,
         last_deployment_timestamp: u64,
     ) -> Result<FetchedFunction, FetchFunctionError> {
         (*self)
@@ -226,6 +389,84 @@ impl FunctionFetch for &'_ FunctionFetcher {
     }
 }
 
+/// Serves the modules given to [MemoryFunctionFetcher::insert] and records every fetch
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryFunctionFetcher {
+    functions: std::sync::Mutex<HashMap<Uuid, FetchedFunction>>,
+    fetches: std::sync::Mutex<Vec<(Uuid, u64)>>,
+}
+
+#[cfg(test)]
+impl MemoryFunctionFetcher {
+    /// Precompiles `wasm` (or WAT) when valid, like modules fetched from S3
+    pub fn insert(&self, function_uuid: Uuid, wasm: &[u8]) {
+        let func = FetchedFunction {
+            tier: FetchTier::L1,
+            ..FetchedFunction::try_precompile(Arc::from(wasm))
+        };
+        self.functions.lock().unwrap().insert(function_uuid, func);
+    }
+
+    /// Function uuid and deployment timestamp of every fetch, in order
+    pub fn fetches(&self) -> Vec<(Uuid, u64)> {
+        self.fetches.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl FunctionFetch for MemoryFunctionFetcher {
+    async fn fetch(
+        &self,
+        function_uuid: impl AsRef<Uuid> + Send,
+        last_deployment_timestamp: u64,
+    ) -> Result<FetchedFunction, FetchFunctionError> {
+        let function_uuid = *function_uuid.as_ref();
+        self.fetches
+            .lock()
+            .unwrap()
+            .push((function_uuid, last_deployment_timestamp));
+        self.functions
+            .lock()
+            .unwrap()
+            .get(&function_uuid)
+            .cloned()
+            .ok_or(FetchFunctionError::Download)
+    }
+}
+
+#[cfg(test)]
+impl FunctionCache for MemoryFunctionFetcher {
+    async fn cached_functions(&self) -> Vec<CachedFunction> {
+        let functions = self.functions.lock().unwrap();
+        functions
+            .iter()
+            .map(|(function_uuid, func)| CachedFunction {
+                function_uuid: *function_uuid,
+                tier: FetchTier::L1,
+                size: func.wasm_bytes.len(),
+                cached_at: func.fetched_at,
+            })
+            .collect()
+    }
+
+    async fn evict(&self, function_uuid: &Uuid) -> bool {
+        self.functions
+            .lock()
+            .unwrap()
+            .remove(function_uuid)
+            .is_some()
+    }
+
+    async fn check_storage(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn check_cache_dir(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
 impl FetchedFunction {
     // Private, because no external code should mark arbitrary WASM modules as precompiled.
     fn from_precompiled_wasm(wasm_bytes: Arc<[u8]>) -> Self {
@@ -233,6 +474,8 @@ impl FetchedFunction {
             wasm_bytes,
             is_precompiled: true,
             fetched_at: current_unix_timestamp_s(),
+            tier: FetchTier::S3,
+            env: Arc::default(),
             _private: (),
         }
     }
@@ -242,6 +485,8 @@ impl FetchedFunction {
             wasm_bytes,
             is_precompiled: false,
             fetched_at: current_unix_timestamp_s(),
+            tier: FetchTier::S3,
+            env: Arc::default(),
             _private: (),
         }
     }
@@ -262,8 +507,14 @@ fn current_unix_timestamp_s() -> u64 {
         .as_secs()
 }
 
+/// Components are precompiled as their core module
 fn precompile_wasm_bytes(wasm_bytes: &Arc<[u8]>) -> Option<Vec<u8>> {
     let store = wasmer::Store::default();
+    let wasm_bytes = if abi::is_component(wasm_bytes) {
+        component::core_module(wasm_bytes).ok()?
+    } else {
+        wasm_bytes
+    };
     let module = wasmer::Module::new(&store, wasm_bytes).ok()?;
     let bytes = module.serialize().ok()?;
     Some(bytes.to_vec())
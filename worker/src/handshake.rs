@@ -1,41 +1,83 @@
+use crate::admission::{Admission, Permit};
+use crate::auth::{self, GatewayAuth, Nonces};
 use crate::fetcher::{self, FetchedFunction};
+use crate::metrics::{HandshakeOutcome, METRICS};
+use crate::trace::{Span, SpanContext};
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::time::SystemTime;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use uuid::Uuid;
 
 const HANDSHAKE_OK: u8 = 0;
 const HANDSHAKE_MALFORMED: u8 = 1;
 const HANDSHAKE_NOT_FOUND: u8 = 2;
+/// The worker is at its concurrency limit, the gateway should retry on another worker
+const HANDSHAKE_OVERLOADED: u8 = 3;
+/// The gateway credentials are missing or invalid, see [crate::auth]
+const HANDSHAKE_UNAUTHORIZED: u8 = 4;
+
+/// Upper bound for the number of metadata entries in a v2 handshake
+const MAX_METADATA_ENTRIES: u16 = 64;
+
+/// Metadata key carrying the gateway's W3C trace context
+const TRACEPARENT_KEY: &str = "traceparent";
 
 pub struct HandshakeSuccess {
     /// Function uuid to run
     pub function_uuid: uuid::Uuid,
     /// Fetched wasm module to run, may be precompiled
     pub fetched_func: FetchedFunction,
+    /// UNIX timestamp (seconds) of the deployment the gateway asked for
+    pub last_deployment: u64,
+    /// Key-value pairs sent by the gateway. Always empty for v1 handshakes.
+    pub metadata: HashMap<String, String>,
+    /// Concurrency slot, held for the whole invocation
+    pub permit: Permit,
 }
 
+/// Reads the gateway handshake and fetches the requested function.
+///
+/// v1: `version: u8 | function_uuid: [u8; 16] | last_deployment: u64`
+///
+/// v2: v1 followed by `entries: u16` and `entries` times `key_len: u16 | key | value_len: u16 | value`,
+/// where keys and values are UTF-8. All integers are big endian.
+///
+/// `invocation_span` is moved into the gateway trace when a `traceparent` entry is present.
+/// The invocation is rejected with [HANDSHAKE_UNAUTHORIZED] when `auth` is set and the
+/// metadata has no valid credentials, and with [HANDSHAKE_OVERLOADED] when `admission` has no
+/// room for it.
 pub async fn handle_handshake<R>(
     stream: R,
     function_fetcher: impl fetcher::FunctionFetch,
+    admission: &Admission,
+    auth: Option<&GatewayAuth>,
+    nonces: &Nonces,
+    invocation_span: &mut Span,
 ) -> Result<HandshakeSuccess, String>
 where
     R: AsyncReadExt + AsyncWriteExt + Unpin + Send,
 {
     let mut stream = stream;
+    let started_at = SystemTime::now();
 
     // version is 8bit integer
     let version = match stream.read_u8().await {
         Ok(v) => v,
         Err(e) => {
-            stream.write_u8(HANDSHAKE_MALFORMED).await.unwrap();
+            METRICS.handshake(HandshakeOutcome::Malformed);
+            reject(&mut stream, HANDSHAKE_MALFORMED).await;
             return Err(format!("malformed handshake version: {e}"));
         }
     };
     log::debug!("read version={version}");
 
     match version {
-        1 => {}
-        _ => return Err(format!("unsupported handshake version {version}")),
+        1 | 2 => {}
+        _ => {
+            METRICS.handshake(HandshakeOutcome::Unsupported);
+            return Err(format!("unsupported handshake version {version}"));
+        }
     };
 
     let function_uuid_bytes = &mut [0_u8; 16];
@@ -43,12 +85,14 @@ where
         Ok(_) => match Uuid::from_str(&uuid_from_be_bytes(function_uuid_bytes)) {
             Ok(function_uuid) => function_uuid,
             Err(e) => {
-                stream.write_u8(HANDSHAKE_MALFORMED).await.unwrap();
+                METRICS.handshake(HandshakeOutcome::Malformed);
+                reject(&mut stream, HANDSHAKE_MALFORMED).await;
                 return Err(format!("malformed handshake function uuid: {e}"));
             }
         },
         Err(e) => {
-            stream.write_u8(HANDSHAKE_MALFORMED).await.unwrap();
+            METRICS.handshake(HandshakeOutcome::Malformed);
+            reject(&mut stream, HANDSHAKE_MALFORMED).await;
             return Err(format!("malformed handshake function uuid: {e}"));
         }
     };
@@ -58,13 +102,60 @@ where
     let last_deployment = match stream.read_u64().await {
         Ok(len) => len,
         Err(e) => {
-            stream.write_u8(HANDSHAKE_MALFORMED).await.unwrap();
+            METRICS.handshake(HandshakeOutcome::Malformed);
+            reject(&mut stream, HANDSHAKE_MALFORMED).await;
             return Err(format!("malformed handshake last deployment: {e}"));
         }
     };
     log::debug!("read last_deployment={last_deployment}");
 
-    log::debug!("start:function_fetcher.fetch");
+    let mut metadata = if version >= 2 {
+        match read_metadata(&mut stream).await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                METRICS.handshake(HandshakeOutcome::Malformed);
+                reject(&mut stream, HANDSHAKE_MALFORMED).await;
+                return Err(format!("malformed handshake metadata: {e}"));
+            }
+        }
+    } else {
+        HashMap::new()
+    };
+    log::debug!("read {} metadata entries", metadata.len());
+
+    if let Some(auth) = auth {
+        let message =
+            auth::signed_message(version, function_uuid_bytes, last_deployment, &metadata);
+        if let Err(e) = auth.verify(&metadata, &message, nonces) {
+            METRICS.handshake(HandshakeOutcome::Unauthorized);
+            reject(&mut stream, HANDSHAKE_UNAUTHORIZED).await;
+            return Err(format!("unauthorized gateway: {e}"));
+        }
+    }
+    // Guests can read the metadata, but never the gateway credentials
+    metadata.retain(|key, _| !auth::CREDENTIAL_KEYS.contains(&key.as_str()));
+
+    if let Some(traceparent) = metadata.get(TRACEPARENT_KEY) {
+        match SpanContext::from_traceparent(traceparent) {
+            Some(parent) => invocation_span.set_remote_parent(parent),
+            None => log::debug!("ignoring invalid traceparent={traceparent}"),
+        }
+    }
+
+    let mut span = invocation_span.child("handshake").started_at(started_at);
+    span.set_attribute("nur.function_uuid", function_uuid.to_string());
+    span.set_attribute("nur.handshake.version", version as i64);
+
+    let Some(permit) = admission.try_acquire(function_uuid) else {
+        METRICS.handshake(HandshakeOutcome::Overloaded);
+        span.set_error("overloaded");
+        reject(&mut stream, HANDSHAKE_OVERLOADED).await;
+        return Err(format!(
+            "concurrency limit reached, rejecting function {function_uuid}"
+        ));
+    };
+
+    let mut fetch_span = span.child("fetch");
     let fetched_func = match function_fetcher
         .fetch(&function_uuid, last_deployment)
         .await
@@ -73,21 +164,74 @@ where
         Err(e) => {
             // TODO: send error back
             // Probably worth implementing a default handler for this kind of cases
-            stream.write_u8(HANDSHAKE_NOT_FOUND).await.unwrap();
+            METRICS.handshake(HandshakeOutcome::NotFound);
+            fetch_span.set_error(format!("{e:?}"));
+            span.set_error("function not found");
+            reject(&mut stream, HANDSHAKE_NOT_FOUND).await;
             return Err(format!("unable to fetch function {function_uuid}: {e:?}"));
         }
     };
-    log::debug!("finish:function_fetcher.fetch handshake OK");
+    fetch_span.set_attribute("nur.cache.tier", fetched_func.tier.label());
+    fetch_span.set_attribute("nur.precompiled", fetched_func.is_precompiled as i64);
+    drop(fetch_span);
 
     // Handshake OK
-    stream.write_u8(HANDSHAKE_OK).await.unwrap();
+    if let Err(e) = stream.write_u8(HANDSHAKE_OK).await {
+        span.set_error("gateway gone");
+        return Err(format!("unable to confirm the handshake: {e}"));
+    }
+    METRICS.handshake(HandshakeOutcome::Ok);
 
     Ok(HandshakeSuccess {
         function_uuid,
         fetched_func,
+        last_deployment,
+        metadata,
+        permit,
     })
 }
 
+/// Sends a rejection `status`. The gateway may be gone already, which is only logged.
+async fn reject<R>(stream: &mut R, status: u8)
+where
+    R: AsyncWriteExt + Unpin,
+{
+    if let Err(e) = stream.write_u8(status).await {
+        log::warn!("Unable to send handshake status {status}: {e}");
+    }
+}
+
+async fn read_metadata<R>(stream: &mut R) -> Result<HashMap<String, String>, String>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let entries = stream.read_u16().await.map_err(|e| e.to_string())?;
+    if entries > MAX_METADATA_ENTRIES {
+        return Err(format!("too many metadata entries ({entries})"));
+    }
+
+    let mut metadata = HashMap::with_capacity(entries as usize);
+    for _ in 0..entries {
+        let key = read_string(stream).await?;
+        let value = read_string(stream).await?;
+        metadata.insert(key.to_ascii_lowercase(), value);
+    }
+    Ok(metadata)
+}
+
+async fn read_string<R>(stream: &mut R) -> Result<String, String>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let len = stream.read_u16().await.map_err(|e| e.to_string())?;
+    let mut bytes = vec![0; len as usize];
+    stream
+        .read_exact(&mut bytes)
+        .await
+        .map_err(|e| e.to_string())?;
+    String::from_utf8(bytes).map_err(|e| e.to_string())
+}
+
 fn uuid_from_be_bytes(bytes: &mut [u8; 16]) -> String {
     format!(
         "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
@@ -112,25 +256,21 @@ fn uuid_from_be_bytes(bytes: &mut [u8; 16]) -> String {
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
     use tokio::io::DuplexStream;
 
     use super::*;
-    use crate::fetcher::FunctionFetch;
-
-    struct FunctionFetcherStub {}
-    impl FunctionFetch for FunctionFetcherStub {
-        async fn fetch(
-            &self,
-            _function_uuid: impl AsRef<Uuid>,
-            _last_deployment_timestamp: u64,
-        ) -> Result<FetchedFunction, fetcher::FetchFunctionError> {
-            Ok(FetchedFunction::from_wasm(Arc::from(vec![0; 1])))
-        }
-    }
+    use crate::fetcher::MemoryFunctionFetcher;
+    use crate::trace::SpanKind;
 
     const TEST_UUID: u128 = 22471393830047846750117075429135178262;
 
+    /// Knows [TEST_UUID] only
+    fn function_fetcher() -> MemoryFunctionFetcher {
+        let function_fetcher = MemoryFunctionFetcher::default();
+        function_fetcher.insert(Uuid::from_u128(TEST_UUID), &[0; 1]);
+        function_fetcher
+    }
+
     fn setup() {
         crate::logger::build_logger().init();
     }
@@ -138,7 +278,7 @@ mod tests {
     #[tokio::test]
     async fn test_handshake_v1() {
         setup();
-        let function_fetcher = FunctionFetcherStub {};
+        let function_fetcher = function_fetcher();
 
         let (mut gateway, worker): (DuplexStream, DuplexStream) = tokio::io::duplex(64);
 
@@ -152,11 +292,211 @@ mod tests {
         // Simulate the gateway writing to us
         gateway.write_all(&gateway_handshake).await.unwrap();
 
-        handle_handshake(worker, function_fetcher).await.unwrap();
+        handle_handshake(
+            worker,
+            &function_fetcher,
+            &Admission::new(0, 0),
+            None,
+            &Nonces::default(),
+            &mut Span::root("invocation", SpanKind::Server),
+        )
+        .await
+        .unwrap();
 
         // Result should be OK
         let result = gateway.read_u8().await.unwrap();
         log::info!("handshake ended. result is {result}");
         assert_eq!(result, HANDSHAKE_OK);
     }
+
+    #[tokio::test]
+    async fn test_handshake_v2_metadata() {
+        let function_fetcher = function_fetcher();
+
+        let (mut gateway, worker): (DuplexStream, DuplexStream) = tokio::io::duplex(256);
+
+        let mut gateway_handshake = Vec::<u8>::new();
+        gateway_handshake.push(2); // version 2
+        gateway_handshake.extend_from_slice(&TEST_UUID.to_be_bytes());
+        gateway_handshake.extend_from_slice(&0_u64.to_be_bytes());
+        gateway_handshake.extend_from_slice(&1_u16.to_be_bytes()); // 1 metadata entry
+        for s in ["X-Request-Id", "abc123"] {
+            gateway_handshake.extend_from_slice(&(s.len() as u16).to_be_bytes());
+            gateway_handshake.extend_from_slice(s.as_bytes());
+        }
+
+        gateway.write_all(&gateway_handshake).await.unwrap();
+
+        let handshake = handle_handshake(
+            worker,
+            &function_fetcher,
+            &Admission::new(0, 0),
+            None,
+            &Nonces::default(),
+            &mut Span::root("invocation", SpanKind::Server),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(gateway.read_u8().await.unwrap(), HANDSHAKE_OK);
+        assert_eq!(
+            handshake.metadata.get("x-request-id").map(String::as_str),
+            Some("abc123")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handshake_v2_truncated_metadata() {
+        let function_fetcher = function_fetcher();
+
+        let (mut gateway, worker): (DuplexStream, DuplexStream) = tokio::io::duplex(256);
+
+        let mut gateway_handshake = Vec::<u8>::new();
+        gateway_handshake.push(2);
+        gateway_handshake.extend_from_slice(&TEST_UUID.to_be_bytes());
+        gateway_handshake.extend_from_slice(&0_u64.to_be_bytes());
+        gateway_handshake.extend_from_slice(&1_u16.to_be_bytes());
+        gateway_handshake.extend_from_slice(&10_u16.to_be_bytes()); // key is never sent
+
+        gateway.write_all(&gateway_handshake).await.unwrap();
+        gateway.shutdown().await.unwrap();
+
+        let result = handle_handshake(
+            worker,
+            &function_fetcher,
+            &Admission::new(0, 0),
+            None,
+            &Nonces::default(),
+            &mut Span::root("invocation", SpanKind::Server),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(gateway.read_u8().await.unwrap(), HANDSHAKE_MALFORMED);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_overloaded() {
+        let (mut gateway, worker): (DuplexStream, DuplexStream) = tokio::io::duplex(64);
+
+        let mut gateway_handshake = Vec::<u8>::new();
+        gateway_handshake.push(1);
+        gateway_handshake.extend_from_slice(&TEST_UUID.to_be_bytes());
+        gateway_handshake.extend_from_slice(&0_u64.to_be_bytes());
+        gateway.write_all(&gateway_handshake).await.unwrap();
+
+        let admission = Admission::new(0, 1);
+        let _running = admission.try_acquire(Uuid::from_u128(TEST_UUID)).unwrap();
+
+        let result = handle_handshake(
+            worker,
+            function_fetcher(),
+            &admission,
+            None,
+            &Nonces::default(),
+            &mut Span::root("invocation", SpanKind::Server),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(gateway.read_u8().await.unwrap(), HANDSHAKE_OVERLOADED);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_overloaded_gateway_gone() {
+        let (mut gateway, worker): (DuplexStream, DuplexStream) = tokio::io::duplex(64);
+
+        let mut gateway_handshake = Vec::<u8>::new();
+        gateway_handshake.push(1);
+        gateway_handshake.extend_from_slice(&TEST_UUID.to_be_bytes());
+        gateway_handshake.extend_from_slice(&0_u64.to_be_bytes());
+        gateway.write_all(&gateway_handshake).await.unwrap();
+        // The status can't be sent, which must not panic
+        drop(gateway);
+
+        let admission = Admission::new(1, 0);
+        let _running = admission.try_acquire(Uuid::new_v4()).unwrap();
+        let result = handle_handshake(
+            worker,
+            function_fetcher(),
+            &admission,
+            None,
+            &Nonces::default(),
+            &mut Span::root("invocation", SpanKind::Server),
+        )
+        .await;
+
+        assert!(matches!(result, Err(e) if e.starts_with("concurrency limit reached")),);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_not_found() {
+        let function_fetcher = function_fetcher();
+        let (mut gateway, worker): (DuplexStream, DuplexStream) = tokio::io::duplex(64);
+
+        let function_uuid = Uuid::new_v4();
+        let mut gateway_handshake = Vec::<u8>::new();
+        gateway_handshake.push(1);
+        gateway_handshake.extend_from_slice(function_uuid.as_bytes());
+        gateway_handshake.extend_from_slice(&42_u64.to_be_bytes());
+        gateway.write_all(&gateway_handshake).await.unwrap();
+
+        let result = handle_handshake(
+            worker,
+            &function_fetcher,
+            &Admission::new(0, 0),
+            None,
+            &Nonces::default(),
+            &mut Span::root("invocation", SpanKind::Server),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(gateway.read_u8().await.unwrap(), HANDSHAKE_NOT_FOUND);
+        assert_eq!(function_fetcher.fetches(), [(function_uuid, 42)]);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_unauthorized() {
+        let function_fetcher = function_fetcher();
+        let config = crate::config::Config {
+            gateway_auth_keys: Some("gw=secret".to_string()),
+            ..Default::default()
+        };
+        let auth = GatewayAuth::from_config(&config).unwrap().unwrap();
+
+        for token in ["wrong", "secret"] {
+            let (mut gateway, worker): (DuplexStream, DuplexStream) = tokio::io::duplex(256);
+            let mut gateway_handshake = Vec::<u8>::new();
+            gateway_handshake.push(2);
+            gateway_handshake.extend_from_slice(&TEST_UUID.to_be_bytes());
+            gateway_handshake.extend_from_slice(&0_u64.to_be_bytes());
+            gateway_handshake.extend_from_slice(&1_u16.to_be_bytes());
+            for s in ["Authorization".to_string(), format!("Bearer {token}")] {
+                gateway_handshake.extend_from_slice(&(s.len() as u16).to_be_bytes());
+                gateway_handshake.extend_from_slice(s.as_bytes());
+            }
+            gateway.write_all(&gateway_handshake).await.unwrap();
+
+            let result = handle_handshake(
+                worker,
+                &function_fetcher,
+                &Admission::new(0, 0),
+                Some(&auth),
+                &Nonces::default(),
+                &mut Span::root("invocation", SpanKind::Server),
+            )
+            .await;
+
+            let expected = if token == "secret" {
+                HANDSHAKE_OK
+            } else {
+                HANDSHAKE_UNAUTHORIZED
+            };
+            assert_eq!(result.is_ok(), expected == HANDSHAKE_OK);
+            assert_eq!(gateway.read_u8().await.unwrap(), expected);
+        }
+        // The function is only fetched for the authorized gateway
+        assert_eq!(function_fetcher.fetches().len(), 1);
+    }
 }
@@ -0,0 +1,162 @@
+//! Minimal HTTP/1.1 server for the worker's own operational endpoints.
+//! Every response closes the connection, so no keep-alive handling is needed.
+
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+const MAX_REQUEST_HEAD_LEN: usize = 8 * 1024;
+/// Connections served at once, later ones wait in the listen backlog
+const MAX_CONNECTIONS: usize = 64;
+/// Time a client gets to send its request head
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+}
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Returns the first value of `name` in the query string. Values are not percent-decoded.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .as_deref()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+impl HttpResponse {
+    pub fn ok(content_type: &'static str, body: Vec<u8>) -> Self {
+        HttpResponse {
+            status: 200,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn json(status: u16, value: serde_json::Value) -> Self {
+        HttpResponse {
+            status,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        HttpResponse::text(404, "not found\n")
+    }
+
+    pub fn text(status: u16, body: &str) -> Self {
+        HttpResponse {
+            status,
+            content_type: "text/plain",
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+/// Accepts connections until the listener fails, answering each request with `handler`.
+/// At most [MAX_CONNECTIONS] are served at once, so slow clients can't pile up tasks.
+pub async fn serve<H, F>(listener: TcpListener, handler: H) -> io::Result<()>
+where
+    H: Fn(HttpRequest) -> F + Clone + Send + Sync + 'static,
+    F: Future<Output = HttpResponse> + Send,
+{
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        // Never closed
+        let permit = connections.clone().acquire_owned().await.unwrap();
+        let (socket, addr) = listener.accept().await?;
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(e) = handle_conn(socket, handler).await {
+                log::debug!("http: connection with {addr} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_conn<H, F>(mut socket: TcpStream, handler: H) -> io::Result<()>
+where
+    H: Fn(HttpRequest) -> F,
+    F: Future<Output = HttpResponse>,
+{
+    let request = tokio::time::timeout(READ_TIMEOUT, read_request(&mut socket))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request head not received"))??;
+    let response = match request {
+        Some(req) => handler(req).await,
+        None => HttpResponse::text(400, "bad request\n"),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(&response.body).await?;
+    socket.shutdown().await
+}
+
+async fn read_request(socket: &mut TcpStream) -> io::Result<Option<HttpRequest>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0_u8; 1024];
+
+    loop {
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf) {
+            Ok(httparse::Status::Complete(_)) => {
+                let target = req.path.unwrap_or("/");
+                let (path, query) = match target.split_once('?') {
+                    Some((path, query)) => (path, Some(query.to_string())),
+                    None => (target, None),
+                };
+                return Ok(Some(HttpRequest {
+                    method: req.method.unwrap_or_default().to_string(),
+                    path: path.to_string(),
+                    query,
+                }));
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_REQUEST_HEAD_LEN => {}
+            _ => return Ok(None),
+        }
+    }
+}
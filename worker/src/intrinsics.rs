@@ -1,30 +1,71 @@
-use std::ops::Range;
+use std::future::Future;
+#[cfg(test)]
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use wasmer::{AsStoreRef, FunctionEnvMut};
 
 pub struct NurFunctionEnv {
     pub memory: Option<wasmer::Memory>,
     pub channel_tx: flume::Sender<NurWasmMessage>,
+    pub outbound: crate::outbound::OutboundHttp,
+    pub kv: crate::kv::KvNamespace,
+    pub env: std::sync::Arc<crate::secrets::FunctionEnvVars>,
+    pub clock: HostClock,
+    pub context: crate::context::InvocationContext,
+    /// Longest single `nur_sleep_ms` call
+    pub max_sleep: Duration,
 }
 
+/// Time and entropy behind `nur_now_ms`, `nur_random_fill` and `nur_sleep_ms`
+#[derive(Clone)]
+pub enum HostClock {
+    /// Wall clock, OS entropy and real sleeps
+    System,
+    /// Deterministic values for tests
+    #[cfg(test)]
+    Fixed(Arc<FixedClock>),
+}
+
+/// Only moves when the guest sleeps, which returns right away.
+/// Random bytes come from a xorshift generator seeded at creation.
+#[cfg(test)]
+pub struct FixedClock {
+    now_ms: AtomicU64,
+    rng: AtomicU64,
+}
+
+/// Returned by host functions given a range outside of the guest memory
+const RANGE_OUT_OF_BOUNDS: i32 = -1;
+
+/// Returned by `nur_random_fill` when the host has no entropy to give
+const ENTROPY_UNAVAILABLE: i32 = -2;
+
 pub enum NurWasmMessage {
     Abort,
-    LogMessage { log: String },
-    SendData { data: Vec<u8> },
+    LogMessage {
+        log: String,
+    },
+    SendData {
+        data: Vec<u8>,
+    },
+    ResponseStart {
+        status: u16,
+        headers: Vec<(String, String)>,
+    },
+    ResponseBody {
+        data: Vec<u8>,
+    },
 }
 
 pub fn nur_log(env: FunctionEnvMut<NurFunctionEnv>, ptr: i32, len: i32) {
     log::trace!("nur_log({ptr}, {len})");
     let data = env.data();
-    let store = env.as_store_ref();
-    let memory = data.memory.as_ref().unwrap();
-    let memory_view = memory.view(&store);
-
-    let memory_slice = memory_view
-        .copy_range_to_vec(Range {
-            start: ptr as u64,
-            end: (ptr + len) as u64,
-        })
-        .unwrap();
+    let Ok(memory_slice) = read_guest(&env, ptr, len) else {
+        log::error!("nur_log: Range &{ptr}+{len} is out of bounds");
+        return;
+    };
 
     let msg = String::from_utf8_lossy(memory_slice.as_slice());
 
@@ -40,16 +81,10 @@ pub fn nur_log(env: FunctionEnvMut<NurFunctionEnv>, ptr: i32, len: i32) {
 pub fn nur_send(env: FunctionEnvMut<NurFunctionEnv>, ptr: i32, len: i32) {
     log::trace!("nur_send({ptr}, {len})");
     let data = env.data();
-    let store = env.as_store_ref();
-    let memory = data.memory.as_ref().unwrap();
-    let memory_view = memory.view(&store);
-
-    let memory_slice = memory_view
-        .copy_range_to_vec(Range {
-            start: ptr as u64,
-            end: (ptr + len) as u64,
-        })
-        .unwrap();
+    let Ok(memory_slice) = read_guest(&env, ptr, len) else {
+        log::error!("nur_send: Range &{ptr}+{len} is out of bounds");
+        return;
+    };
 
     data.channel_tx
         .send(NurWasmMessage::SendData {
@@ -60,6 +95,57 @@ pub fn nur_send(env: FunctionEnvMut<NurFunctionEnv>, ptr: i32, len: i32) {
         });
 }
 
+/// Starts the response of a `handle_request` guest. See [crate::abi] for the headers encoding.
+/// Returns 0, or -1 when the headers range is out of bounds.
+pub fn nur_response_start(
+    env: FunctionEnvMut<NurFunctionEnv>,
+    status: i32,
+    headers_ptr: i32,
+    headers_len: i32,
+) -> i32 {
+    log::trace!("nur_response_start({status}, {headers_ptr}, {headers_len})");
+    let data = env.data();
+    let Ok(memory_slice) = read_guest(&env, headers_ptr, headers_len) else {
+        return RANGE_OUT_OF_BOUNDS;
+    };
+
+    let headers = crate::abi::decode_headers(&memory_slice).unwrap_or_else(|e| {
+        log::error!("nur_response_start: Dropping malformed headers: {e}");
+        Vec::new()
+    });
+    let status = u16::try_from(status)
+        .ok()
+        .filter(|status| (100..1000).contains(status))
+        .unwrap_or_else(|| {
+            log::error!("nur_response_start: Invalid status {status}, answering 500");
+            500
+        });
+
+    data.channel_tx
+        .send(NurWasmMessage::ResponseStart { status, headers })
+        .unwrap_or_else(|e| {
+            log::error!("nur_response_start: Failed to send response through channel: {e}");
+        });
+    0
+}
+
+/// Appends a chunk to the body of a `handle_request` guest response.
+/// Returns 0, or -1 when the range is out of bounds.
+pub fn nur_response_write(env: FunctionEnvMut<NurFunctionEnv>, ptr: i32, len: i32) -> i32 {
+    log::trace!("nur_response_write({ptr}, {len})");
+    let data = env.data();
+    let Ok(memory_slice) = read_guest(&env, ptr, len) else {
+        return RANGE_OUT_OF_BOUNDS;
+    };
+
+    data.channel_tx
+        .send(NurWasmMessage::ResponseBody { data: memory_slice })
+        .unwrap_or_else(|e| {
+            log::error!("nur_response_write: Failed to send data through channel: {e}");
+        });
+    0
+}
+
 /// Aborts with the given message described by a fat ointer in memory.
 pub fn nur_end(mut env: FunctionEnvMut<NurFunctionEnv>) {
     log::trace!("nur_end()");
@@ -71,3 +157,168 @@ pub fn nur_end(mut env: FunctionEnvMut<NurFunctionEnv>) {
             log::error!("nur_end: Failed to send end message through channel: {e}");
         });
 }
+
+impl HostClock {
+    /// Milliseconds since the UNIX epoch
+    pub fn now_ms(&self) -> u64 {
+        match self {
+            HostClock::System => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            #[cfg(test)]
+            HostClock::Fixed(clock) => clock.now_ms.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn fill(&self, buf: &mut [u8]) -> Result<(), String> {
+        match self {
+            HostClock::System => getrandom::fill(buf).map_err(|e| e.to_string()),
+            #[cfg(test)]
+            HostClock::Fixed(clock) => {
+                for chunk in buf.chunks_mut(8) {
+                    let mut x = clock.rng.load(Ordering::SeqCst);
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                    clock.rng.store(x, Ordering::SeqCst);
+                    chunk.copy_from_slice(&x.to_le_bytes()[..chunk.len()]);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn sleep(&self, duration: Duration) {
+        match self {
+            HostClock::System => tokio::time::sleep(duration).await,
+            #[cfg(test)]
+            HostClock::Fixed(clock) => {
+                clock
+                    .now_ms
+                    .fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl FixedClock {
+    pub fn new(now_ms: u64, seed: u64) -> Self {
+        FixedClock {
+            now_ms: AtomicU64::new(now_ms),
+            // xorshift never leaves 0
+            rng: AtomicU64::new(seed.max(1)),
+        }
+    }
+}
+
+/// Milliseconds since the UNIX epoch
+pub fn nur_now_ms(env: FunctionEnvMut<NurFunctionEnv>) -> i64 {
+    log::trace!("nur_now_ms()");
+    env.data().clock.now_ms() as i64
+}
+
+/// Fills `[ptr, ptr + len)` with random bytes. Returns 0, -1 when the range is out of bounds, or
+/// -2 when the host entropy source fails.
+pub fn nur_random_fill(env: FunctionEnvMut<NurFunctionEnv>, ptr: i32, len: i32) -> i32 {
+    log::trace!("nur_random_fill({ptr}, {len})");
+    let data = env.data();
+    let store = env.as_store_ref();
+    let view = data.memory.as_ref().unwrap().view(&store);
+
+    // Checked before allocating, the guest controls len
+    let start = ptr as u32 as u64;
+    if len < 0 || start + len as u64 > view.data_size() {
+        return RANGE_OUT_OF_BOUNDS;
+    }
+    let mut buf = vec![0; len as usize];
+    if let Err(e) = data.clock.fill(&mut buf) {
+        log::error!("nur_random_fill: Failed to get random bytes: {e}");
+        return ENTROPY_UNAVAILABLE;
+    }
+    match view.write(start, &buf) {
+        Ok(()) => 0,
+        Err(_) => RANGE_OUT_OF_BOUNDS,
+    }
+}
+
+/// Sleeps for up to `max_sleep_ms`. The guest keeps its thread, see [block_on] for why other
+/// invocations still run.
+pub fn nur_sleep_ms(env: FunctionEnvMut<NurFunctionEnv>, ms: i32) {
+    log::trace!("nur_sleep_ms({ms})");
+    let data = env.data();
+    let duration = Duration::from_millis(ms.max(0) as u64).min(data.max_sleep);
+    block_on(data.clock.sleep(duration));
+}
+
+/// Copies `[ptr, ptr + len)` out of the guest memory. Unlike `copy_range_to_vec`, which
+/// truncates the range to the memory size, the whole range must be in bounds.
+pub fn read_guest(
+    env: &FunctionEnvMut<NurFunctionEnv>,
+    ptr: i32,
+    len: i32,
+) -> Result<Vec<u8>, wasmer::MemoryAccessError> {
+    let memory = env.data().memory.as_ref().unwrap();
+    let view = memory.view(env);
+    let start = ptr as u32 as u64;
+    let end = start + len as u32 as u64;
+    if len < 0 || end > view.data_size() {
+        return Err(wasmer::MemoryAccessError::HeapOutOfBounds);
+    }
+    view.copy_range_to_vec(start..end)
+}
+
+/// Writes `value` at `buf_ptr` when it fits in `buf_len`. Returns the length of `value` either
+/// way, so that guests can call again with a large enough buffer.
+pub fn write_if_fits(
+    env: &FunctionEnvMut<NurFunctionEnv>,
+    value: &[u8],
+    buf_ptr: i32,
+    buf_len: i32,
+) -> Result<i32, wasmer::MemoryAccessError> {
+    if value.len() <= buf_len.max(0) as usize {
+        let memory = env.data().memory.as_ref().unwrap();
+        memory.view(env).write(buf_ptr as u64, value)?;
+    }
+    Ok(value.len() as i32)
+}
+
+/// Waits for `future` from a synchronous host function. The calling thread is parked, and
+/// `block_in_place` hands its queued tasks to another worker thread meanwhile. That panics on a
+/// current-thread runtime, which [crate::server::ExecutionState::new] refuses.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(future))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fixed_clock() {
+        let clock = HostClock::Fixed(Arc::new(FixedClock::new(1_000, 42)));
+        assert_eq!(clock.now_ms(), 1_000);
+        clock.sleep(Duration::from_secs(60)).await;
+        assert_eq!(clock.now_ms(), 61_000);
+
+        let mut a = [0; 12];
+        let mut b = [0; 12];
+        clock.fill(&mut a).unwrap();
+        HostClock::Fixed(Arc::new(FixedClock::new(0, 42)))
+            .fill(&mut b)
+            .unwrap();
+        assert_eq!(a, b);
+        assert_ne!(a, [0; 12]);
+    }
+
+    #[test]
+    fn test_system_clock() {
+        let clock = HostClock::System;
+        let mut buf = [0; 32];
+        clock.fill(&mut buf).unwrap();
+        assert_ne!(buf, [0; 32]);
+        // 2020-01-01
+        assert!(clock.now_ms() > 1_577_836_800_000);
+    }
+}
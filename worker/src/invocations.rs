@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// Registry of the connections currently being served by this worker
+#[derive(Clone, Default)]
+pub struct Invocations {
+    inner: Arc<Mutex<HashMap<Uuid, InvocationInfo>>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct InvocationInfo {
+    pub peer_addr: SocketAddr,
+    /// Unknown until the handshake completes
+    pub function_uuid: Option<Uuid>,
+    pub started_at: SystemTime,
+}
+
+/// Removes its invocation from the registry when dropped
+pub struct InvocationGuard {
+    id: Uuid,
+    invocations: Invocations,
+}
+
+impl Invocations {
+    pub fn start(&self, peer_addr: SocketAddr) -> InvocationGuard {
+        let id = Uuid::new_v4();
+        self.inner.lock().unwrap().insert(
+            id,
+            InvocationInfo {
+                peer_addr,
+                function_uuid: None,
+                started_at: SystemTime::now(),
+            },
+        );
+        InvocationGuard {
+            id,
+            invocations: self.clone(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<(Uuid, InvocationInfo)> {
+        let inner = self.inner.lock().unwrap();
+        inner.iter().map(|(id, info)| (*id, info.clone())).collect()
+    }
+}
+
+impl InvocationGuard {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn set_function_uuid(&self, function_uuid: Uuid) {
+        if let Some(info) = self.invocations.inner.lock().unwrap().get_mut(&self.id) {
+            info.function_uuid = Some(function_uuid);
+        }
+    }
+}
+
+impl Drop for InvocationGuard {
+    fn drop(&mut self) {
+        self.invocations.inner.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guard_unregisters_invocation() {
+        let invocations = Invocations::default();
+        let guard = invocations.start("127.0.0.1:1234".parse().unwrap());
+        let function_uuid = Uuid::new_v4();
+        guard.set_function_uuid(function_uuid);
+
+        let snapshot = invocations.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].1.function_uuid, Some(function_uuid));
+
+        drop(guard);
+        assert!(invocations.snapshot().is_empty());
+    }
+}
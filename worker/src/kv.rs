@@ -0,0 +1,480 @@
+//! Per-function key-value storage exposed through the `nur_kv_*` imports.
+//!
+//! - `nur_kv_get(key_ptr, key_len, buf_ptr, buf_len) -> len`: writes the value and returns its
+//!   length. When `buf_len` is too small nothing is written, call again with the returned length.
+//! - `nur_kv_set(key_ptr, key_len, value_ptr, value_len) -> 0`
+//! - `nur_kv_delete(key_ptr, key_len) -> 1 | 0`: whether the key existed
+//! - `nur_kv_list(prefix_ptr, prefix_len, buf_ptr, buf_len) -> len`: keys starting with the
+//!   prefix, encoded as `count` followed by `len | key` entries (little-endian u32s), with the
+//!   same sizing rules as `nur_kv_get`
+//!
+//! Errors are negative [KvError] codes. Keys are UTF-8 and namespaced by function uuid,
+//! so functions never see each other's data.
+
+use crate::intrinsics::{NurFunctionEnv, block_on, read_guest};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+use wasmer::FunctionEnvMut;
+
+pub const MAX_KEY_BYTES: usize = 512;
+const MAX_LIST_KEYS: i64 = 1000;
+
+/// Returned to guests as negative numbers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KvError {
+    NotFound = -1,
+    KeyTooLarge = -2,
+    ValueTooLarge = -3,
+    Unavailable = -4,
+    InvalidKey = -5,
+    /// A key, value or buffer range is outside of the guest memory
+    OutOfBounds = -6,
+}
+
+pub type KvFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, KvError>> + Send + 'a>>;
+
+pub trait KvStore: Send + Sync {
+    fn get<'a>(&'a self, namespace: &'a Uuid, key: &'a str) -> KvFuture<'a, Option<Vec<u8>>>;
+
+    fn set<'a>(&'a self, namespace: &'a Uuid, key: &'a str, value: Vec<u8>) -> KvFuture<'a, ()>;
+
+    /// Returns whether the key existed
+    fn delete<'a>(&'a self, namespace: &'a Uuid, key: &'a str) -> KvFuture<'a, bool>;
+
+    /// Keys starting with `prefix`, sorted, at most `limit`
+    fn list<'a>(
+        &'a self,
+        namespace: &'a Uuid,
+        prefix: &'a str,
+        limit: i64,
+    ) -> KvFuture<'a, Vec<String>>;
+}
+
+/// Stores values in the `function_kv` table:
+///
+/// ```sql
+/// CREATE TABLE function_kv (
+///     function_id uuid NOT NULL,
+///     key text NOT NULL,
+///     value bytea NOT NULL,
+///     updated_at timestamptz NOT NULL DEFAULT now(),
+///     PRIMARY KEY (function_id, key)
+/// );
+/// ```
+pub struct PostgresKvStore {
+    client_pool: deadpool_postgres::Pool,
+}
+
+/// Process-local store, lost on restart
+#[derive(Default)]
+pub struct MemoryKvStore {
+    namespaces: Mutex<HashMap<Uuid, BTreeMap<String, Vec<u8>>>>,
+}
+
+impl PostgresKvStore {
+    pub fn new(client_pool: deadpool_postgres::Pool) -> Self {
+        PostgresKvStore { client_pool }
+    }
+
+    async fn client(&self) -> Result<deadpool_postgres::Object, KvError> {
+        self.client_pool.get().await.map_err(|e| {
+            log::error!("kv: Failed to get a Postgres connection: {e}");
+            KvError::Unavailable
+        })
+    }
+}
+
+fn unavailable(e: tokio_postgres::Error) -> KvError {
+    log::error!("kv: Postgres query failed: {e}");
+    KvError::Unavailable
+}
+
+impl KvStore for PostgresKvStore {
+    fn get<'a>(&'a self, namespace: &'a Uuid, key: &'a str) -> KvFuture<'a, Option<Vec<u8>>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            let stmt = client
+                .prepare_cached("SELECT value FROM function_kv WHERE function_id = $1 AND key = $2")
+                .await
+                .map_err(unavailable)?;
+            let row = client
+                .query_opt(&stmt, &[namespace, &key])
+                .await
+                .map_err(unavailable)?;
+            Ok(row.map(|row| row.get(0)))
+        })
+    }
+
+    fn set<'a>(&'a self, namespace: &'a Uuid, key: &'a str, value: Vec<u8>) -> KvFuture<'a, ()> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            let stmt = client
+                .prepare_cached(
+                    "INSERT INTO function_kv(function_id, key, value) VALUES ($1, $2, $3) \
+                     ON CONFLICT (function_id, key) DO UPDATE SET value = $3, updated_at = now()",
+                )
+                .await
+                .map_err(unavailable)?;
+            client
+                .execute(&stmt, &[namespace, &key, &value])
+                .await
+                .map_err(unavailable)?;
+            Ok(())
+        })
+    }
+
+    fn delete<'a>(&'a self, namespace: &'a Uuid, key: &'a str) -> KvFuture<'a, bool> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            let stmt = client
+                .prepare_cached("DELETE FROM function_kv WHERE function_id = $1 AND key = $2")
+                .await
+                .map_err(unavailable)?;
+            let deleted = client
+                .execute(&stmt, &[namespace, &key])
+                .await
+                .map_err(unavailable)?;
+            Ok(deleted > 0)
+        })
+    }
+
+    fn list<'a>(
+        &'a self,
+        namespace: &'a Uuid,
+        prefix: &'a str,
+        limit: i64,
+    ) -> KvFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            // left() instead of LIKE, so that '%' and '_' in prefixes are not wildcards
+            let stmt = client
+                .prepare_cached(
+                    "SELECT key FROM function_kv WHERE function_id = $1 \
+                     AND left(key, char_length($2)) = $2 ORDER BY key LIMIT $3",
+                )
+                .await
+                .map_err(unavailable)?;
+            let rows = client
+                .query(&stmt, &[namespace, &prefix, &limit])
+                .await
+                .map_err(unavailable)?;
+            Ok(rows.into_iter().map(|row| row.get(0)).collect())
+        })
+    }
+}
+
+impl KvStore for MemoryKvStore {
+    fn get<'a>(&'a self, namespace: &'a Uuid, key: &'a str) -> KvFuture<'a, Option<Vec<u8>>> {
+        let namespaces = self.namespaces.lock().unwrap();
+        let value = namespaces
+            .get(namespace)
+            .and_then(|values| values.get(key))
+            .cloned();
+        Box::pin(async move { Ok(value) })
+    }
+
+    fn set<'a>(&'a self, namespace: &'a Uuid, key: &'a str, value: Vec<u8>) -> KvFuture<'a, ()> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        namespaces
+            .entry(*namespace)
+            .or_default()
+            .insert(key.to_string(), value);
+        Box::pin(async move { Ok(()) })
+    }
+
+    fn delete<'a>(&'a self, namespace: &'a Uuid, key: &'a str) -> KvFuture<'a, bool> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        let deleted = namespaces
+            .get_mut(namespace)
+            .is_some_and(|values| values.remove(key).is_some());
+        Box::pin(async move { Ok(deleted) })
+    }
+
+    fn list<'a>(
+        &'a self,
+        namespace: &'a Uuid,
+        prefix: &'a str,
+        limit: i64,
+    ) -> KvFuture<'a, Vec<String>> {
+        let namespaces = self.namespaces.lock().unwrap();
+        let keys = namespaces
+            .get(namespace)
+            .map(|values| {
+                values
+                    .range(prefix.to_string()..)
+                    .take_while(|(key, _)| key.starts_with(prefix))
+                    .take(limit as usize)
+                    .map(|(key, _)| key.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Box::pin(async move { Ok(keys) })
+    }
+}
+
+/// The view of a [KvStore] given to a single invocation
+pub struct KvNamespace {
+    store: Arc<dyn KvStore>,
+    function_uuid: Uuid,
+    max_value_bytes: usize,
+    /// Last result too large for the guest buffer, so the retry does not hit the store again
+    last_get: Option<(String, Vec<u8>)>,
+    last_list: Option<(String, Vec<u8>)>,
+}
+
+impl KvNamespace {
+    pub fn new(store: Arc<dyn KvStore>, function_uuid: Uuid, max_value_bytes: usize) -> Self {
+        KvNamespace {
+            store,
+            function_uuid,
+            max_value_bytes,
+            last_get: None,
+            last_list: None,
+        }
+    }
+
+    pub async fn get(&mut self, key: &str) -> Result<Vec<u8>, KvError> {
+        check_key(key)?;
+        if let Some((last_key, value)) = self.last_get.take()
+            && last_key == key
+        {
+            return Ok(value);
+        }
+        self.store
+            .get(&self.function_uuid, key)
+            .await?
+            .ok_or(KvError::NotFound)
+    }
+
+    pub async fn set(&mut self, key: &str, value: Vec<u8>) -> Result<(), KvError> {
+        check_key(key)?;
+        if value.len() > self.max_value_bytes {
+            return Err(KvError::ValueTooLarge);
+        }
+        self.last_get = None;
+        self.store.set(&self.function_uuid, key, value).await
+    }
+
+    pub async fn delete(&mut self, key: &str) -> Result<bool, KvError> {
+        check_key(key)?;
+        self.last_get = None;
+        self.store.delete(&self.function_uuid, key).await
+    }
+
+    /// Keys starting with `prefix`, already encoded for the guest
+    pub async fn list(&mut self, prefix: &str) -> Result<Vec<u8>, KvError> {
+        if prefix.len() > MAX_KEY_BYTES {
+            return Err(KvError::KeyTooLarge);
+        }
+        if let Some((last_prefix, keys)) = self.last_list.take()
+            && last_prefix == prefix
+        {
+            return Ok(keys);
+        }
+        let keys = self
+            .store
+            .list(&self.function_uuid, prefix, MAX_LIST_KEYS)
+            .await?;
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(keys.len() as u32).to_le_bytes());
+        for key in keys {
+            buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            buf.extend_from_slice(key.as_bytes());
+        }
+        Ok(buf)
+    }
+}
+
+fn check_key(key: &str) -> Result<(), KvError> {
+    match key.len() {
+        0 => Err(KvError::InvalidKey),
+        len if len > MAX_KEY_BYTES => Err(KvError::KeyTooLarge),
+        _ => Ok(()),
+    }
+}
+
+/// Storage behind the `nur_kv_*` imports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KvBackend {
+    Postgres,
+    Memory,
+}
+
+impl FromStr for KvBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "postgres" => Ok(KvBackend::Postgres),
+            "memory" => Ok(KvBackend::Memory),
+            _ => Err("expected postgres or memory".to_string()),
+        }
+    }
+}
+
+/// Builds the store named by `backend`
+pub fn store(backend: KvBackend, client_pool: deadpool_postgres::Pool) -> Arc<dyn KvStore> {
+    match backend {
+        KvBackend::Postgres => Arc::new(PostgresKvStore::new(client_pool)),
+        KvBackend::Memory => Arc::new(MemoryKvStore::default()),
+    }
+}
+
+fn read_string(
+    env: &FunctionEnvMut<NurFunctionEnv>,
+    ptr: i32,
+    len: i32,
+) -> Result<String, KvError> {
+    let bytes = read_guest(env, ptr, len).map_err(|_| KvError::OutOfBounds)?;
+    String::from_utf8(bytes).map_err(|_| KvError::InvalidKey)
+}
+
+/// Writes `result` when it fits in `buf_len`, keeping it in `last` otherwise
+fn write_sized(
+    env: &mut FunctionEnvMut<NurFunctionEnv>,
+    result: Result<Vec<u8>, KvError>,
+    buf_ptr: i32,
+    buf_len: i32,
+) -> Result<(i32, Option<Vec<u8>>), KvError> {
+    let value = result?;
+    if value.len() > buf_len.max(0) as usize {
+        return Ok((value.len() as i32, Some(value)));
+    }
+    let (data, store) = env.data_and_store_mut();
+    let memory = data.memory.as_ref().unwrap();
+    memory
+        .view(&store)
+        .write(buf_ptr as u64, &value)
+        .map_err(|_| KvError::OutOfBounds)?;
+    Ok((value.len() as i32, None))
+}
+
+pub fn nur_kv_get(
+    mut env: FunctionEnvMut<NurFunctionEnv>,
+    key_ptr: i32,
+    key_len: i32,
+    buf_ptr: i32,
+    buf_len: i32,
+) -> i32 {
+    log::trace!("nur_kv_get({key_ptr}, {key_len}, {buf_ptr}, {buf_len})");
+    let key = match read_string(&env, key_ptr, key_len) {
+        Ok(key) => key,
+        Err(e) => return e as i32,
+    };
+    let result = block_on(env.data_mut().kv.get(&key));
+    match write_sized(&mut env, result, buf_ptr, buf_len) {
+        Ok((len, pending)) => {
+            env.data_mut().kv.last_get = pending.map(|value| (key, value));
+            len
+        }
+        Err(e) => e as i32,
+    }
+}
+
+pub fn nur_kv_set(
+    mut env: FunctionEnvMut<NurFunctionEnv>,
+    key_ptr: i32,
+    key_len: i32,
+    value_ptr: i32,
+    value_len: i32,
+) -> i32 {
+    log::trace!("nur_kv_set({key_ptr}, {key_len}, {value_ptr}, {value_len})");
+    let key = match read_string(&env, key_ptr, key_len) {
+        Ok(key) => key,
+        Err(e) => return e as i32,
+    };
+    if value_len.max(0) as usize > env.data().kv.max_value_bytes {
+        return KvError::ValueTooLarge as i32;
+    }
+    let value = match read_guest(&env, value_ptr, value_len) {
+        Ok(value) => value,
+        Err(_) => return KvError::OutOfBounds as i32,
+    };
+    match block_on(env.data_mut().kv.set(&key, value)) {
+        Ok(()) => 0,
+        Err(e) => e as i32,
+    }
+}
+
+pub fn nur_kv_delete(mut env: FunctionEnvMut<NurFunctionEnv>, key_ptr: i32, key_len: i32) -> i32 {
+    log::trace!("nur_kv_delete({key_ptr}, {key_len})");
+    let key = match read_string(&env, key_ptr, key_len) {
+        Ok(key) => key,
+        Err(e) => return e as i32,
+    };
+    match block_on(env.data_mut().kv.delete(&key)) {
+        Ok(deleted) => deleted as i32,
+        Err(e) => e as i32,
+    }
+}
+
+pub fn nur_kv_list(
+    mut env: FunctionEnvMut<NurFunctionEnv>,
+    prefix_ptr: i32,
+    prefix_len: i32,
+    buf_ptr: i32,
+    buf_len: i32,
+) -> i32 {
+    log::trace!("nur_kv_list({prefix_ptr}, {prefix_len}, {buf_ptr}, {buf_len})");
+    let prefix = match read_string(&env, prefix_ptr, prefix_len) {
+        Ok(prefix) => prefix,
+        Err(e) => return e as i32,
+    };
+    let result = block_on(env.data_mut().kv.list(&prefix));
+    match write_sized(&mut env, result, buf_ptr, buf_len) {
+        Ok((len, pending)) => {
+            env.data_mut().kv.last_list = pending.map(|keys| (prefix, keys));
+            len
+        }
+        Err(e) => e as i32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_namespaces_are_isolated() {
+        let store: Arc<dyn KvStore> = Arc::new(MemoryKvStore::default());
+        let mut cows = KvNamespace::new(store.clone(), Uuid::new_v4(), 16);
+        let mut goats = KvNamespace::new(store, Uuid::new_v4(), 16);
+
+        cows.set("name", b"lola".to_vec()).await.unwrap();
+        cows.set("names/1", b"pepa".to_vec()).await.unwrap();
+        cows.set("other", b"mu".to_vec()).await.unwrap();
+
+        assert_eq!(cows.get("name").await.unwrap(), b"lola");
+        assert_eq!(goats.get("name").await, Err(KvError::NotFound));
+
+        let keys = cows.list("name").await.unwrap();
+        assert_eq!(&keys[..4], &2_u32.to_le_bytes());
+        assert_eq!(&keys[4..8], &4_u32.to_le_bytes());
+        assert_eq!(&keys[8..12], b"name");
+        assert_eq!(goats.list("").await.unwrap(), 0_u32.to_le_bytes());
+
+        assert!(cows.delete("name").await.unwrap());
+        assert!(!cows.delete("name").await.unwrap());
+        assert_eq!(cows.get("name").await, Err(KvError::NotFound));
+    }
+
+    #[tokio::test]
+    async fn test_size_limits() {
+        let mut kv = KvNamespace::new(Arc::new(MemoryKvStore::default()), Uuid::new_v4(), 4);
+
+        assert_eq!(
+            kv.set("cow", b"muuuu".to_vec()).await,
+            Err(KvError::ValueTooLarge)
+        );
+        assert_eq!(
+            kv.set(&"m".repeat(MAX_KEY_BYTES + 1), Vec::new()).await,
+            Err(KvError::KeyTooLarge)
+        );
+        assert_eq!(kv.get("").await, Err(KvError::InvalidKey));
+    }
+}
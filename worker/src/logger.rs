@@ -1,16 +1,56 @@
 use core::fmt;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{OnceLock, RwLock};
 
 use env_logger::fmt::{Color, Style, StyledValue};
 use log::Level;
 
 static MAX_MODULE_WIDTH: AtomicUsize = AtomicUsize::new(0);
 
+/// The installed logger, replaced by [set_filter]
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+
+struct ReloadableLogger(RwLock<env_logger::Logger>);
+
+impl log::Log for ReloadableLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.0.read().unwrap().flush()
+    }
+}
+
+/// Installs the logger, filtered by `RUST_LOG` until [set_filter] is called
+pub fn init() {
+    let logger = LOGGER.get_or_init(|| ReloadableLogger(RwLock::new(build_logger().build())));
+    log::set_max_level(logger.0.read().unwrap().filter());
+    log::set_logger(logger).expect("The logger was already set");
+}
+
+/// Applies `filter` on top of `RUST_LOG`, or `RUST_LOG` alone when [None]
+pub fn set_filter(filter: Option<&str>) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let mut builder = build_logger();
+    if let Some(filter) = filter {
+        builder.parse_filters(filter);
+    }
+    let built = builder.build();
+    log::set_max_level(built.filter());
+    *logger.0.write().unwrap() = built;
+}
+
 pub fn build_logger() -> env_logger::Builder {
     let mut builder = env_logger::Builder::new();
 
-    let pkg_name = crate::env::CARGO_PKG_NAME.clone();
-    let pkg_name: &'static str = pkg_name.leak();
+    let pkg_name = env!("CARGO_CRATE_NAME");
     let pkg_name_len = pkg_name.len();
 
     builder.format(move |f, record| {
@@ -1,40 +1,88 @@
-use std::str::FromStr;
+use crate::config::Config;
+use crate::metrics::METRICS;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
 use uuid::Uuid;
 
+/// Why a [LogsService] could not store a log, whatever its backend
+#[derive(Debug)]
+pub enum LogsServiceError {
+    /// The backend can't be reached right now
+    Unavailable(String),
+    /// The backend is up but failed to store the log
+    Rejected(String),
+}
+
+impl fmt::Display for LogsServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogsServiceError::Unavailable(e) => write!(f, "log sink unavailable: {e}"),
+            LogsServiceError::Rejected(e) => write!(f, "log rejected: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for LogsServiceError {}
+
+impl From<deadpool_postgres::PoolError> for LogsServiceError {
+    fn from(e: deadpool_postgres::PoolError) -> Self {
+        LogsServiceError::Unavailable(e.to_string())
+    }
+}
+
+impl From<tokio_postgres::Error> for LogsServiceError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        if e.is_closed() {
+            LogsServiceError::Unavailable(e.to_string())
+        } else {
+            LogsServiceError::Rejected(e.to_string())
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct SupabaseLogService {
     client_pool: deadpool_postgres::Pool,
 }
 
 impl SupabaseLogService {
-    pub fn from_env() -> Self {
-        let pg_config = tokio_postgres::Config::from_str(&crate::env::POSTGRES_URL)
-            .expect("Invalid POSTGRES_URL variable");
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let postgres_url = crate::config::required("postgres_url", &config.postgres_url)?;
+        let (pg_config, tls) = crate::tls::postgres_config(config, postgres_url)?;
 
         let mgr_config = deadpool_postgres::ManagerConfig {
             recycling_method: deadpool_postgres::RecyclingMethod::Fast,
         };
 
-        let mgr =
-            deadpool_postgres::Manager::from_config(pg_config, tokio_postgres::NoTls, mgr_config);
+        let mgr = deadpool_postgres::Manager::from_config(pg_config, tls, mgr_config);
 
         let client_pool = deadpool_postgres::Pool::builder(mgr)
             .max_size(16)
             .build()
             .unwrap();
 
-        SupabaseLogService { client_pool }
+        Ok(SupabaseLogService { client_pool })
     }
 
-    pub async fn check_connection(&self) -> Result<(), deadpool_postgres::PoolError> {
-        // Just to ensure the connection is established
-        self.client_pool.get().await.map(|_| ())
+    /// Shared with the other Postgres backed services
+    pub fn client_pool(&self) -> deadpool_postgres::Pool {
+        self.client_pool.clone()
     }
 }
 
 impl LogsService for SupabaseLogService {
-    async fn send(&self, function_uuid: &Uuid, message: &str) -> Result<(), tokio_postgres::Error> {
-        let client = self.client_pool.get().await.unwrap();
+    async fn check_connection(&self) -> Result<(), LogsServiceError> {
+        // Just to ensure the connection is established
+        let _client = self.client_pool.get().await?;
+        Ok(())
+    }
+
+    async fn send(&self, function_uuid: &Uuid, message: &str) -> Result<(), LogsServiceError> {
+        let client = self.client_pool.get().await?;
         let stmt = client
             .prepare_cached("INSERT INTO function_logs(function_id, message) VALUES ($1, $2)")
             .await?;
@@ -46,5 +94,155 @@ impl LogsService for SupabaseLogService {
 }
 
 pub trait LogsService {
-    async fn send(&self, function_id: &Uuid, message: &str) -> Result<(), tokio_postgres::Error>;
+    fn send(
+        &self,
+        function_id: &Uuid,
+        message: &str,
+    ) -> impl Future<Output = Result<(), LogsServiceError>> + Send;
+
+    /// Whether logs can be sent right now, always for backends without a connection
+    fn check_connection(&self) -> impl Future<Output = Result<(), LogsServiceError>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Prints function logs with the worker's own logger, used by the [crate::dev] runner
+pub struct TerminalLogService {
+    /// Shown instead of the function uuid
+    names: HashMap<Uuid, String>,
+}
+
+impl TerminalLogService {
+    pub fn new(names: HashMap<Uuid, String>) -> Self {
+        TerminalLogService { names }
+    }
+}
+
+impl LogsService for TerminalLogService {
+    async fn send(&self, function_uuid: &Uuid, message: &str) -> Result<(), LogsServiceError> {
+        match self.names.get(function_uuid) {
+            Some(name) => log::info!(target: "guest", "[{name}] {}", message.trim_end()),
+            None => log::info!(target: "guest", "[{function_uuid}] {}", message.trim_end()),
+        }
+        Ok(())
+    }
+}
+
+/// Keeps every log in memory, for assertions
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryLogsService {
+    records: std::sync::Mutex<Vec<(Uuid, String)>>,
+}
+
+#[cfg(test)]
+impl MemoryLogsService {
+    /// Messages logged by `function_uuid`, in order
+    pub fn of(&self, function_uuid: Uuid) -> Vec<String> {
+        let records = self.records.lock().unwrap();
+        records
+            .iter()
+            .filter(|(uuid, _)| *uuid == function_uuid)
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl LogsService for MemoryLogsService {
+    async fn send(&self, function_uuid: &Uuid, message: &str) -> Result<(), LogsServiceError> {
+        self.records
+            .lock()
+            .unwrap()
+            .push((*function_uuid, message.to_string()));
+        Ok(())
+    }
+}
+
+pub struct LogRecord {
+    pub function_uuid: Uuid,
+    pub message: String,
+}
+
+/// Bounded queue of function logs, drained into a [LogsService] by a fixed set of workers.
+/// When the queue is full new logs are dropped instead of piling up unbounded tasks.
+#[derive(Clone)]
+pub struct LogQueue {
+    tx: flume::Sender<LogRecord>,
+    /// Logs queued or being sent right now
+    pending: Arc<AtomicUsize>,
+    idle: Arc<Notify>,
+}
+
+impl LogQueue {
+    pub fn spawn<L>(logs_service: Arc<L>, capacity: usize, workers: usize) -> Self
+    where
+        L: LogsService + Send + Sync + 'static,
+    {
+        let (tx, rx) = flume::bounded::<LogRecord>(capacity);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let idle = Arc::new(Notify::new());
+
+        for _ in 0..workers.max(1) {
+            let rx = rx.clone();
+            let logs_service = logs_service.clone();
+            let pending = pending.clone();
+            let idle = idle.clone();
+            tokio::spawn(async move {
+                while let Ok(record) = rx.recv_async().await {
+                    METRICS.log_queue_depth.dec();
+                    let function_uuid = record.function_uuid;
+                    logs_service
+                        .send(&function_uuid, &record.message)
+                        .await
+                        .unwrap_or_else(|e| {
+                            METRICS.logs_dropped.inc();
+                            log::error!(
+                                "logs_service.send: Failed to send log for function_uuid={function_uuid}: {e}"
+                            );
+                        });
+                    if pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        idle.notify_waiters();
+                    }
+                }
+            });
+        }
+
+        LogQueue { tx, pending, idle }
+    }
+
+    /// Waits until every queued log has been sent, or `timeout` elapses.
+    /// Returns the number of logs still pending.
+    pub async fn flush(&self, timeout: Duration) -> usize {
+        let wait_idle = async {
+            loop {
+                // Registered before checking, so a notification in between is not missed
+                let notified = self.idle.notified();
+                if self.pending.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        let _ = tokio::time::timeout(timeout, wait_idle).await;
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    pub fn push(&self, function_uuid: Uuid, message: String) {
+        // Counted before sending, so a worker never sees the record before the depth does
+        METRICS.log_queue_depth.inc();
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        match self.tx.try_send(LogRecord {
+            function_uuid,
+            message,
+        }) {
+            Ok(()) => {}
+            Err(e) => {
+                METRICS.log_queue_depth.dec();
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                METRICS.logs_dropped.inc();
+                log::warn!("Dropping log for function_uuid={function_uuid}: {e}");
+            }
+        }
+    }
 }
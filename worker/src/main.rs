@@ -1,33 +1,161 @@
-use crate::{fetcher::FunctionFetcher, logs_service::SupabaseLogService};
+use crate::config::Config;
+use crate::fetcher::FunctionFetcher;
+use crate::logs_service::{LogsService, SupabaseLogService};
 use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
 
-mod env;
+mod abi;
+mod admin;
+mod admission;
+mod auth;
+mod component;
+mod config;
+mod context;
+mod dev;
 mod fetcher;
 mod handshake;
+mod http;
 mod intrinsics;
+mod invocations;
+mod kv;
 mod logger;
 mod logs_service;
+mod metrics;
+mod nurfile;
+mod outbound;
+mod reload;
+mod secrets;
 mod server;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod trace;
+mod wasi;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let _ = dotenvy::dotenv();
-    logger::build_logger().init();
+    logger::init();
 
-    let server_addr = (env::HOST.clone(), *env::PORT);
+    let cli_args: Vec<String> = std::env::args().skip(1).collect();
+    let (config, args) = match Config::load(&cli_args) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            log::error!("Invalid configuration: {e}");
+            std::process::exit(2);
+        }
+    };
+    logger::set_filter(config.log_level.as_deref());
+    match args.first().map(String::as_str) {
+        Some("dev") => return Ok(dev::run(&config, &args[1..]).await?),
+        Some("up") => return Ok(dev::up(&config, &args[1..]).await?),
+        Some(command) => return Err(format!("unknown command {command:?}").into()),
+        None => {}
+    }
+    if let Err(e) = config.validate_worker() {
+        log::error!("Invalid configuration: {e}");
+        std::process::exit(2);
+    }
+
+    if let Some(endpoint) = config.otel_exporter_otlp_endpoint.clone() {
+        log::info!("🔭 Exporting traces to {endpoint}");
+        trace::init(endpoint, config.otel_service_name.clone());
+    }
 
     log::info!("📒 Connecting to log service...");
-    let logs_service = SupabaseLogService::from_env();
+    let logs_service = Arc::new(SupabaseLogService::new(&config)?);
     logs_service.check_connection().await?;
     log::info!("✅ Log service connected successfully");
 
     log::info!("⌛️ Starting Nur worker...");
-    let function_fetcher = FunctionFetcher::from_env().await?;
-    let server = server::Server::new(&server_addr, function_fetcher, logs_service).await?;
+    let function_fetcher = Arc::new(
+        FunctionFetcher::new(
+            &config,
+            secrets::provider_from_config(&config, logs_service.client_pool())?,
+        )
+        .await?,
+    );
+    let invocations = invocations::Invocations::default();
+    let admission = admission::Admission::new(
+        config.max_concurrent_invocations,
+        config.max_concurrent_per_function,
+    );
+    let server = server::Server::new(
+        &config,
+        function_fetcher.clone(),
+        logs_service.clone(),
+        invocations.clone(),
+        admission.clone(),
+        kv::store(config.kv_backend, logs_service.client_pool()),
+    )
+    .await?;
+
+    let metrics_addr = (config.host.clone(), config.metrics_port);
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(&metrics_addr).await {
+            log::error!("Metrics listener at {metrics_addr:?} stopped: {e}");
+        }
+    });
+
+    let admin_addr = (config.admin_host.clone(), config.admin_port);
+    let admin_state = admin::AdminState {
+        function_fetcher,
+        logs_service,
+        invocations,
+    };
+    tokio::spawn(async move {
+        if let Err(e) = admin::serve(&admin_addr, admin_state).await {
+            log::error!("Admin listener at {admin_addr:?} stopped: {e}");
+        }
+    });
+
+    tokio::spawn(reload::watch(
+        cli_args,
+        config.clone(),
+        server.settings(),
+        admission,
+    ));
+    if let Some(tls) = server.tls() {
+        log::info!("🔒 Gateway connections use TLS");
+        tokio::spawn(tls.watch());
+    }
 
-    log::info!("⚒️ Ready to listen at {server_addr:?}");
+    log::info!("⚒️ Ready to listen at {}", server.local_addr()?);
 
-    server.listen_forever_and_ever_amen().await?;
+    let summary = server
+        .listen_forever_and_ever_amen(
+            shutdown_signal(),
+            Duration::from_secs(config.drain_timeout_secs),
+            Duration::from_secs(config.log_flush_timeout_secs),
+        )
+        .await?;
+
+    log::info!(
+        "👋 Bye! served={} drained={} aborted={} logs_lost={}",
+        summary.served,
+        summary.drained,
+        summary.aborted,
+        summary.logs_lost
+    );
 
     Ok(())
 }
+
+/// Resolves on SIGTERM (orchestrators) or SIGINT (terminal)
+async fn shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            log::error!("Unable to listen for SIGTERM: {e}");
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = sigterm.recv() => log::info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => log::info!("Received SIGINT"),
+    }
+}
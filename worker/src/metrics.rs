@@ -0,0 +1,365 @@
+use crate::http::{HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::net::{TcpListener, ToSocketAddrs};
+
+lazy_static! {
+    /// Process-wide worker metrics, rendered in the Prometheus text format by [serve]
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Default Prometheus buckets, in seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakeOutcome {
+    Ok,
+    Malformed,
+    Unsupported,
+    NotFound,
+    Overloaded,
+    Unauthorized,
+}
+
+/// Where a [crate::fetcher::FetchedFunction] was served from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FetchTier {
+    L1,
+    L2,
+    S3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModuleLoad {
+    Compile,
+    Deserialize,
+}
+
+impl HandshakeOutcome {
+    const ALL: [HandshakeOutcome; 6] = [
+        HandshakeOutcome::Ok,
+        HandshakeOutcome::Malformed,
+        HandshakeOutcome::Unsupported,
+        HandshakeOutcome::NotFound,
+        HandshakeOutcome::Overloaded,
+        HandshakeOutcome::Unauthorized,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            HandshakeOutcome::Ok => "ok",
+            HandshakeOutcome::Malformed => "malformed",
+            HandshakeOutcome::Unsupported => "unsupported",
+            HandshakeOutcome::NotFound => "not_found",
+            HandshakeOutcome::Overloaded => "overloaded",
+            HandshakeOutcome::Unauthorized => "unauthorized",
+        }
+    }
+}
+
+impl FetchTier {
+    const ALL: [FetchTier; 3] = [FetchTier::L1, FetchTier::L2, FetchTier::S3];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            FetchTier::L1 => "l1",
+            FetchTier::L2 => "l2",
+            FetchTier::S3 => "s3",
+        }
+    }
+}
+
+impl ModuleLoad {
+    const ALL: [ModuleLoad; 2] = [ModuleLoad::Compile, ModuleLoad::Deserialize];
+
+    fn label(self) -> &'static str {
+        match self {
+            ModuleLoad::Compile => "compile",
+            ModuleLoad::Deserialize => "deserialize",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        // Buckets are stored non-cumulative, [Histogram::render] adds them up
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{sep}le=\"{le}\"}} {cumulative}"
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {count}");
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {sum}");
+        let _ = writeln!(out, "{name}_count{labels} {count}");
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    handshakes: [Counter; 6],
+    fetches: [Counter; 3],
+    fetch_latency: [Histogram; 3],
+    pub fetch_errors: Counter,
+    module_load: [Histogram; 2],
+    pub active_instances: Gauge,
+    pub guest_execution: Histogram,
+    pub bytes_received: Counter,
+    pub bytes_sent: Counter,
+    pub log_queue_depth: Gauge,
+    pub logs_dropped: Counter,
+}
+
+impl Metrics {
+    pub fn handshake(&self, outcome: HandshakeOutcome) {
+        self.handshakes[outcome as usize].inc();
+    }
+
+    pub fn fetch(&self, tier: FetchTier, elapsed: Duration) {
+        self.fetches[tier as usize].inc();
+        self.fetch_latency[tier as usize].observe(elapsed);
+    }
+
+    pub fn module_load(&self, kind: ModuleLoad, elapsed: Duration) {
+        self.module_load[kind as usize].observe(elapsed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::with_capacity(4096);
+
+        header(
+            &mut out,
+            "nur_handshakes_total",
+            "counter",
+            "Handshakes by outcome",
+        );
+        for outcome in HandshakeOutcome::ALL {
+            let _ = writeln!(
+                out,
+                "nur_handshakes_total{{outcome=\"{}\"}} {}",
+                outcome.label(),
+                self.handshakes[outcome as usize].get()
+            );
+        }
+
+        header(
+            &mut out,
+            "nur_fetches_total",
+            "counter",
+            "Functions fetched by cache tier",
+        );
+        for tier in FetchTier::ALL {
+            let _ = writeln!(
+                out,
+                "nur_fetches_total{{tier=\"{}\"}} {}",
+                tier.label(),
+                self.fetches[tier as usize].get()
+            );
+        }
+
+        header(
+            &mut out,
+            "nur_fetch_errors_total",
+            "counter",
+            "Failed function fetches",
+        );
+        let _ = writeln!(out, "nur_fetch_errors_total {}", self.fetch_errors.get());
+
+        header(
+            &mut out,
+            "nur_fetch_seconds",
+            "histogram",
+            "Function fetch latency by cache tier",
+        );
+        for tier in FetchTier::ALL {
+            self.fetch_latency[tier as usize].render(
+                &mut out,
+                "nur_fetch_seconds",
+                &format!("tier=\"{}\"", tier.label()),
+            );
+        }
+
+        header(
+            &mut out,
+            "nur_module_load_seconds",
+            "histogram",
+            "WASM module compile or deserialize time",
+        );
+        for kind in ModuleLoad::ALL {
+            self.module_load[kind as usize].render(
+                &mut out,
+                "nur_module_load_seconds",
+                &format!("kind=\"{}\"", kind.label()),
+            );
+        }
+
+        header(
+            &mut out,
+            "nur_active_instances",
+            "gauge",
+            "WASM instances currently running",
+        );
+        let _ = writeln!(out, "nur_active_instances {}", self.active_instances.get());
+
+        header(
+            &mut out,
+            "nur_guest_execution_seconds",
+            "histogram",
+            "Time spent inside guest calls",
+        );
+        self.guest_execution
+            .render(&mut out, "nur_guest_execution_seconds", "");
+
+        header(
+            &mut out,
+            "nur_bytes_received_total",
+            "counter",
+            "Bytes read from gateway connections",
+        );
+        let _ = writeln!(
+            out,
+            "nur_bytes_received_total {}",
+            self.bytes_received.get()
+        );
+
+        header(
+            &mut out,
+            "nur_bytes_sent_total",
+            "counter",
+            "Bytes written to gateway connections",
+        );
+        let _ = writeln!(out, "nur_bytes_sent_total {}", self.bytes_sent.get());
+
+        header(
+            &mut out,
+            "nur_log_queue_depth",
+            "gauge",
+            "Function logs waiting to be sent",
+        );
+        let _ = writeln!(out, "nur_log_queue_depth {}", self.log_queue_depth.get());
+
+        header(
+            &mut out,
+            "nur_logs_dropped_total",
+            "counter",
+            "Function logs dropped",
+        );
+        let _ = writeln!(out, "nur_logs_dropped_total {}", self.logs_dropped.get());
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Serves `GET /metrics` until the listener fails
+pub async fn serve<A: ToSocketAddrs>(addr: A) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    crate::http::serve(listener, |req: HttpRequest| async move {
+        match (req.method.as_str(), req.path.as_str()) {
+            ("GET", "/metrics") => {
+                HttpResponse::ok("text/plain; version=0.0.4", METRICS.render().into_bytes())
+            }
+            _ => HttpResponse::not_found(),
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_histogram_is_cumulative() {
+        let metrics = Metrics::default();
+        metrics.fetch(FetchTier::L2, Duration::from_millis(3));
+        metrics.fetch(FetchTier::L2, Duration::from_millis(30));
+        metrics.handshake(HandshakeOutcome::NotFound);
+
+        let out = metrics.render();
+        assert!(out.contains("nur_handshakes_total{outcome=\"not_found\"} 1"));
+        assert!(out.contains("nur_fetches_total{tier=\"l2\"} 2"));
+        assert!(out.contains("nur_fetch_seconds_bucket{tier=\"l2\",le=\"0.005\"} 1"));
+        assert!(out.contains("nur_fetch_seconds_bucket{tier=\"l2\",le=\"0.05\"} 2"));
+        assert!(out.contains("nur_fetch_seconds_count{tier=\"l2\"} 2"));
+        assert!(out.contains("nur_fetch_seconds_count{tier=\"l1\"} 0"));
+    }
+}
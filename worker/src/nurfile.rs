@@ -0,0 +1,116 @@
+//! Parses `nurfile.yaml`, the list of functions of a project:
+//!
+//! ```yaml
+//! functions:
+//!   - name: hello-world
+//!     directory: /examples/hello_world
+//!     template: rust
+//!     build:
+//!       command: cargo build --target wasm32-unknown-unknown --release
+//!       output: /target/wasm32-unknown-unknown/release/hello_world.wasm
+//! ```
+//!
+//! `directory` is relative to the nurfile and `output` to `directory`, with or without a
+//! leading `/`. `template` is only used by the deploy tooling.
+
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+pub struct Nurfile {
+    pub functions: Vec<NurfileFunction>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NurfileFunction {
+    pub name: String,
+    pub directory: String,
+    pub build: NurfileBuild,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NurfileBuild {
+    pub command: String,
+    pub output: String,
+}
+
+impl Nurfile {
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        serde_yaml::from_str(contents).map_err(|e| format!("invalid nurfile: {e}"))
+    }
+
+    pub async fn load(path: &Path) -> Result<Self, String> {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        Nurfile::parse(&contents)
+    }
+}
+
+impl NurfileFunction {
+    /// Directory the build command runs in
+    pub fn directory(&self, root: &Path) -> PathBuf {
+        root.join(self.directory.trim_start_matches('/'))
+    }
+
+    /// The built module
+    pub fn output(&self, root: &Path) -> PathBuf {
+        self.directory(root)
+            .join(self.build.output.trim_start_matches('/'))
+    }
+
+    /// Runs the build command through `sh`, its output goes straight to the terminal
+    pub async fn build(&self, root: &Path) -> Result<(), String> {
+        let status = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(&self.build.command)
+            .current_dir(self.directory(root))
+            .status()
+            .await
+            .map_err(|e| format!("Failed to run the build of {}: {e}", self.name))?;
+        if !status.success() {
+            return Err(format!("Build of {} failed: {status}", self.name));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_resolve() {
+        let nurfile = Nurfile::parse(
+            r#"
+functions:
+  - name: 🐄 cowsay
+    directory: /examples/cowsay
+    template: rust
+    build:
+      command: cargo build --target wasm32-unknown-unknown --release
+      output: /target/wasm32-unknown-unknown/release/cowsay.wasm
+  - name: nur-test
+    directory: examples/hello_world
+    build:
+      command: make
+      output: hello.wasm
+"#,
+        )
+        .unwrap();
+
+        let root = Path::new("/repo");
+        let cowsay = &nurfile.functions[0];
+        assert_eq!(cowsay.name, "🐄 cowsay");
+        assert_eq!(
+            cowsay.output(root),
+            Path::new("/repo/examples/cowsay/target/wasm32-unknown-unknown/release/cowsay.wasm")
+        );
+        assert_eq!(
+            nurfile.functions[1].output(root),
+            Path::new("/repo/examples/hello_world/hello.wasm")
+        );
+
+        assert!(Nurfile::parse("functions: 42").is_err());
+    }
+}
@@ -0,0 +1,437 @@
+//! Outbound HTTP requests made by guests through the `nur_http_*` imports.
+//!
+//! - `nur_http_fetch(req_ptr, req_len) -> handle`: the request is encoded like in [crate::abi],
+//!   with the absolute url in place of the path. Returns a handle, or a negative [FetchError].
+//! - `nur_http_status(handle) -> status`
+//! - `nur_http_headers(handle, buf_ptr, buf_len) -> len`: writes the encoded response headers.
+//!   When `buf_len` is too small nothing is written, call again with the returned length.
+//! - `nur_http_read(handle, buf_ptr, buf_len) -> n`: streams the body, 0 once it is over
+//! - `nur_http_close(handle)`: frees the response, also done when the invocation ends
+//!
+//! Functions can only reach the hosts allowed by the [EgressPolicy], and redirects are never
+//! followed. The guest is blocked while waiting for the network.
+
+use crate::abi::{self, GuestRequest};
+use crate::config::Config;
+use crate::intrinsics::{NurFunctionEnv, block_on, read_guest, write_if_fits};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use uuid::Uuid;
+use wasmer::FunctionEnvMut;
+
+const MAX_OPEN_RESPONSES: usize = 16;
+
+/// Returned to guests as negative numbers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FetchError {
+    Denied = -1,
+    InvalidRequest = -2,
+    Timeout = -3,
+    TooLarge = -4,
+    Connection = -5,
+    BadHandle = -6,
+    TooManyResponses = -7,
+    /// A request or buffer range is outside of the guest memory
+    OutOfBounds = -8,
+}
+
+/// Hosts each function may reach. Rules are written as
+/// `*=api.example.com,*.internal;<function_uuid>=httpbin.org`, where `*` applies to every function.
+/// Host patterns match exactly or, when starting with `*.`, any subdomain.
+#[derive(Default)]
+pub struct EgressPolicy {
+    all_functions: Vec<String>,
+    per_function: HashMap<Uuid, Vec<String>>,
+}
+
+/// Shared by every invocation
+pub struct OutboundClient {
+    client: reqwest::Client,
+    policy: EgressPolicy,
+    timeout: Duration,
+    max_response_bytes: usize,
+}
+
+/// Responses opened by a single invocation
+pub struct OutboundHttp {
+    client: Arc<OutboundClient>,
+    function_uuid: Uuid,
+    responses: HashMap<i32, PendingResponse>,
+    next_handle: i32,
+}
+
+struct PendingResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: reqwest::Response,
+    /// Part of the last chunk that did not fit in the guest buffer
+    buffered: Vec<u8>,
+    received: usize,
+    deadline: Instant,
+}
+
+impl EgressPolicy {
+    pub fn parse(rules: &str) -> Result<Self, String> {
+        let mut policy = EgressPolicy::default();
+        for rule in rules
+            .split(';')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+        {
+            let (function, hosts) = rule
+                .split_once('=')
+                .ok_or_else(|| format!("invalid egress rule {rule:?}, expected function=hosts"))?;
+            let hosts = hosts
+                .split(',')
+                .map(|host| host.trim().to_lowercase())
+                .filter(|host| !host.is_empty());
+            match function.trim() {
+                "*" => policy.all_functions.extend(hosts),
+                uuid => {
+                    let uuid = Uuid::parse_str(uuid).map_err(|e| {
+                        format!("invalid function uuid {uuid:?} in egress rule: {e}")
+                    })?;
+                    policy.per_function.entry(uuid).or_default().extend(hosts);
+                }
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn allows(&self, function_uuid: &Uuid, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.all_functions
+            .iter()
+            .chain(self.per_function.get(function_uuid).into_iter().flatten())
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|subdomain| subdomain.ends_with('.')),
+                None => *pattern == host,
+            })
+    }
+}
+
+impl OutboundClient {
+    pub fn new(policy: EgressPolicy, timeout: Duration, max_response_bytes: usize) -> Self {
+        let client = reqwest::Client::builder()
+            // A redirect could point outside the allowlist
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("Failed to build the outbound HTTP client");
+        OutboundClient {
+            client,
+            policy,
+            timeout,
+            max_response_bytes,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let policy = match config.http_egress_allowlist.as_deref() {
+            Some(rules) => EgressPolicy::parse(rules)?,
+            None => EgressPolicy::default(),
+        };
+        Ok(OutboundClient::new(
+            policy,
+            Duration::from_millis(config.http_fetch_timeout_ms),
+            config.http_fetch_max_response_bytes,
+        ))
+    }
+}
+
+impl OutboundHttp {
+    pub fn new(client: Arc<OutboundClient>, function_uuid: Uuid) -> Self {
+        OutboundHttp {
+            client,
+            function_uuid,
+            responses: HashMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    /// Sends `request` and waits for the response head
+    pub async fn fetch(&mut self, request: GuestRequest) -> Result<i32, FetchError> {
+        if self.responses.len() >= MAX_OPEN_RESPONSES {
+            return Err(FetchError::TooManyResponses);
+        }
+
+        let url = reqwest::Url::parse(&request.path).map_err(|_| FetchError::InvalidRequest)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(FetchError::InvalidRequest);
+        }
+        let host = url.host_str().ok_or(FetchError::InvalidRequest)?;
+        if !self.client.policy.allows(&self.function_uuid, host) {
+            log::info!("Function {} denied egress to {host}", self.function_uuid);
+            return Err(FetchError::Denied);
+        }
+
+        let method = reqwest::Method::from_bytes(request.method.as_bytes())
+            .map_err(|_| FetchError::InvalidRequest)?;
+        let mut builder = self.client.client.request(method, url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+
+        let deadline = Instant::now() + self.client.timeout;
+        let response = tokio::time::timeout_at(deadline, builder.body(request.body).send())
+            .await
+            .map_err(|_| FetchError::Timeout)?
+            .map_err(|e| {
+                log::debug!("nur_http_fetch: {e}");
+                if e.is_builder() {
+                    FetchError::InvalidRequest
+                } else {
+                    FetchError::Connection
+                }
+            })?;
+
+        if response
+            .content_length()
+            .is_some_and(|len| len as usize > self.client.max_response_bytes)
+        {
+            return Err(FetchError::TooLarge);
+        }
+
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.responses.insert(
+            handle,
+            PendingResponse {
+                status: response.status().as_u16(),
+                headers: response
+                    .headers()
+                    .iter()
+                    .map(|(name, value)| {
+                        (
+                            name.to_string(),
+                            String::from_utf8_lossy(value.as_bytes()).to_string(),
+                        )
+                    })
+                    .collect(),
+                body: response,
+                buffered: Vec::new(),
+                received: 0,
+                deadline,
+            },
+        );
+        Ok(handle)
+    }
+
+    pub fn status(&self, handle: i32) -> Result<u16, FetchError> {
+        let response = self.responses.get(&handle).ok_or(FetchError::BadHandle)?;
+        Ok(response.status)
+    }
+
+    pub fn headers(&self, handle: i32) -> Result<Vec<u8>, FetchError> {
+        let response = self.responses.get(&handle).ok_or(FetchError::BadHandle)?;
+        Ok(abi::encode_headers(&response.headers))
+    }
+
+    /// Returns up to `max_len` bytes of the body, empty once it is over
+    pub async fn read(&mut self, handle: i32, max_len: usize) -> Result<Vec<u8>, FetchError> {
+        let max_response_bytes = self.client.max_response_bytes;
+        let response = self
+            .responses
+            .get_mut(&handle)
+            .ok_or(FetchError::BadHandle)?;
+
+        if response.buffered.is_empty() {
+            let chunk = tokio::time::timeout_at(response.deadline, response.body.chunk())
+                .await
+                .map_err(|_| FetchError::Timeout)?
+                .map_err(|e| {
+                    log::debug!("nur_http_read: {e}");
+                    FetchError::Connection
+                })?;
+            let Some(chunk) = chunk else {
+                return Ok(Vec::new());
+            };
+            response.received += chunk.len();
+            if response.received > max_response_bytes {
+                return Err(FetchError::TooLarge);
+            }
+            response.buffered = chunk.to_vec();
+        }
+
+        let n = max_len.min(response.buffered.len());
+        let rest = response.buffered.split_off(n);
+        Ok(std::mem::replace(&mut response.buffered, rest))
+    }
+
+    pub fn close(&mut self, handle: i32) -> bool {
+        self.responses.remove(&handle).is_some()
+    }
+}
+
+pub fn nur_http_fetch(mut env: FunctionEnvMut<NurFunctionEnv>, ptr: i32, len: i32) -> i32 {
+    log::trace!("nur_http_fetch({ptr}, {len})");
+    let Ok(bytes) = read_guest(&env, ptr, len) else {
+        return FetchError::OutOfBounds as i32;
+    };
+    let request = match abi::decode_request(&bytes) {
+        Ok(request) => request,
+        Err(e) => {
+            log::debug!("nur_http_fetch: invalid request: {e}");
+            return FetchError::InvalidRequest as i32;
+        }
+    };
+
+    block_on(env.data_mut().outbound.fetch(request)).unwrap_or_else(|e| e as i32)
+}
+
+pub fn nur_http_status(env: FunctionEnvMut<NurFunctionEnv>, handle: i32) -> i32 {
+    log::trace!("nur_http_status({handle})");
+    match env.data().outbound.status(handle) {
+        Ok(status) => status as i32,
+        Err(e) => e as i32,
+    }
+}
+
+pub fn nur_http_headers(
+    env: FunctionEnvMut<NurFunctionEnv>,
+    handle: i32,
+    ptr: i32,
+    len: i32,
+) -> i32 {
+    log::trace!("nur_http_headers({handle}, {ptr}, {len})");
+    let headers = match env.data().outbound.headers(handle) {
+        Ok(headers) => headers,
+        Err(e) => return e as i32,
+    };
+    write_if_fits(&env, &headers, ptr, len).unwrap_or(FetchError::OutOfBounds as i32)
+}
+
+pub fn nur_http_read(
+    mut env: FunctionEnvMut<NurFunctionEnv>,
+    handle: i32,
+    ptr: i32,
+    len: i32,
+) -> i32 {
+    log::trace!("nur_http_read({handle}, {ptr}, {len})");
+    let (data, store) = env.data_and_store_mut();
+    let chunk = match block_on(data.outbound.read(handle, len.max(0) as usize)) {
+        Ok(chunk) => chunk,
+        Err(e) => return e as i32,
+    };
+
+    let memory = data.memory.as_ref().unwrap();
+    match memory.view(&store).write(ptr as u64, &chunk) {
+        Ok(()) => chunk.len() as i32,
+        Err(_) => FetchError::OutOfBounds as i32,
+    }
+}
+
+pub fn nur_http_close(mut env: FunctionEnvMut<NurFunctionEnv>, handle: i32) {
+    log::trace!("nur_http_close({handle})");
+    env.data_mut().outbound.close(handle);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{HttpRequest, HttpResponse};
+    use tokio::net::TcpListener;
+
+    const FUNCTION_UUID: Uuid = Uuid::from_u128(0x0f0e0d0c_0b0a_0908_0706_050403020100);
+
+    /// Serves `/hello`, `/big` (2 KiB) and `/slow` (answers after 500ms) on a random port
+    async fn mock_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(crate::http::serve(
+            listener,
+            |req: HttpRequest| async move {
+                match req.path.as_str() {
+                    "/hello" => HttpResponse::text(200, "mu!"),
+                    "/big" => HttpResponse::ok("text/plain", vec![b'm'; 2048]),
+                    "/slow" => {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        HttpResponse::text(200, "zzz")
+                    }
+                    _ => HttpResponse::not_found(),
+                }
+            },
+        ));
+        format!("http://{addr}")
+    }
+
+    fn outbound(allowlist: &str) -> OutboundHttp {
+        let client = OutboundClient::new(
+            EgressPolicy::parse(allowlist).unwrap(),
+            Duration::from_millis(200),
+            1024,
+        );
+        OutboundHttp::new(Arc::new(client), FUNCTION_UUID)
+    }
+
+    fn get(url: String) -> GuestRequest {
+        GuestRequest {
+            method: "GET".to_string(),
+            path: url,
+            headers: vec![("x-cow".to_string(), "lola".to_string())],
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_egress_policy() {
+        let policy = EgressPolicy::parse(&format!(
+            "*=api.nur.dev, *.internal; {FUNCTION_UUID}=cows.org"
+        ))
+        .unwrap();
+        assert!(policy.allows(&FUNCTION_UUID, "api.nur.dev"));
+        assert!(policy.allows(&FUNCTION_UUID, "logs.internal"));
+        assert!(policy.allows(&FUNCTION_UUID, "COWS.org"));
+        assert!(!policy.allows(&FUNCTION_UUID, "internal"));
+        assert!(!policy.allows(&FUNCTION_UUID, "evilinternal"));
+        assert!(!policy.allows(&Uuid::nil(), "cows.org"));
+
+        assert!(EgressPolicy::parse("cows.org").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_stream_body() {
+        let base = mock_server().await;
+        let mut http = outbound("*=127.0.0.1");
+
+        let handle = http.fetch(get(format!("{base}/hello"))).await.unwrap();
+        assert_eq!(http.status(handle).unwrap(), 200);
+        let headers = abi::decode_headers(&http.headers(handle).unwrap()).unwrap();
+        assert!(headers.contains(&("content-type".to_string(), "text/plain".to_string())));
+
+        assert_eq!(http.read(handle, 2).await.unwrap(), b"mu");
+        assert_eq!(http.read(handle, 2).await.unwrap(), b"!");
+        assert!(http.read(handle, 2).await.unwrap().is_empty());
+
+        assert!(http.close(handle));
+        assert_eq!(http.status(handle), Err(FetchError::BadHandle));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_limits() {
+        let base = mock_server().await;
+
+        let mut http = outbound("*=localhost");
+        assert_eq!(
+            http.fetch(get(format!("{base}/hello"))).await,
+            Err(FetchError::Denied)
+        );
+
+        let mut http = outbound("*=127.0.0.1");
+        assert_eq!(
+            http.fetch(get(format!("{base}/big"))).await,
+            Err(FetchError::TooLarge)
+        );
+        assert_eq!(
+            http.fetch(get(format!("{base}/slow"))).await,
+            Err(FetchError::Timeout)
+        );
+        assert_eq!(
+            http.fetch(get("file:///etc/passwd".to_string())).await,
+            Err(FetchError::InvalidRequest)
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Applies configuration changes without a restart. The config is loaded again whenever its
+//! file changes or the worker gets a SIGHUP; invalid configs are logged and ignored.
+//!
+//! Only [RELOADABLE] settings take effect right away: each invocation reads them once as it
+//! starts, so those already running keep the values they started with and no connection is
+//! dropped. New concurrency limits apply to the next handshakes, running invocations keep their
+//! slot. Every other change is reported as needing a restart.
+
+use crate::admission::Admission;
+use crate::auth::GatewayAuth;
+use crate::config::{Config, Limits};
+use crate::outbound::OutboundClient;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{SignalKind, signal};
+
+/// How often the config file is checked for changes
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Settings applied on reload, the rest need a restart
+pub const RELOADABLE: &[&str] = &[
+    "max_request_body_bytes",
+    "kv_max_value_bytes",
+    "max_sleep_ms",
+    "http_egress_allowlist",
+    "http_fetch_timeout_ms",
+    "http_fetch_max_response_bytes",
+    "log_level",
+    "gateway_auth_keys",
+    "gateway_auth_max_skew_secs",
+    "max_concurrent_invocations",
+    "max_concurrent_per_function",
+];
+
+/// Everything an invocation reads from the config as it starts
+pub struct InvocationSettings {
+    pub limits: Limits,
+    pub outbound: Arc<OutboundClient>,
+    /// Keys checked in the handshake, [None] to accept any gateway
+    pub auth: Option<GatewayAuth>,
+}
+
+/// The [InvocationSettings] in use, swapped as a whole on reload
+#[derive(Clone)]
+pub struct LiveSettings(Arc<RwLock<Arc<InvocationSettings>>>);
+
+impl InvocationSettings {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        Ok(InvocationSettings {
+            limits: config.limits(),
+            outbound: Arc::new(OutboundClient::from_config(config)?),
+            auth: GatewayAuth::from_config(config)?,
+        })
+    }
+}
+
+impl LiveSettings {
+    pub fn new(settings: InvocationSettings) -> Self {
+        LiveSettings(Arc::new(RwLock::new(Arc::new(settings))))
+    }
+
+    /// The current settings, unaffected by later reloads
+    pub fn get(&self) -> Arc<InvocationSettings> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn set(&self, settings: InvocationSettings) {
+        *self.0.write().unwrap() = Arc::new(settings);
+    }
+}
+
+/// Reloads `config` from `args` on SIGHUP or when its file changes, forever
+pub async fn watch(
+    args: Vec<String>,
+    mut config: Config,
+    live: LiveSettings,
+    admission: Admission,
+) {
+    let file = Config::file(&args).unwrap_or_else(|e| {
+        log::error!("Unable to find the config file, only SIGHUP reloads: {e}");
+        None
+    });
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            log::error!("Unable to listen for SIGHUP: {e}");
+            None
+        }
+    };
+    let mut modified = modified_at(file.as_deref()).await;
+    let mut interval = tokio::time::interval(RELOAD_POLL_INTERVAL);
+
+    loop {
+        tokio::select! {
+            Some(()) = async { hangup.as_mut()?.recv().await } => {
+                log::info!("Received SIGHUP, reloading configuration");
+            }
+            _ = interval.tick(), if file.is_some() => {
+                let now = modified_at(file.as_deref()).await;
+                if now == modified {
+                    continue;
+                }
+                modified = now;
+                log::info!("Config file changed, reloading configuration");
+            }
+            // Neither a file nor SIGHUP to watch
+            else => return,
+        }
+
+        match Config::load(&args) {
+            Ok((reloaded, _)) => apply(&mut config, reloaded, &live, &admission),
+            Err(e) => log::error!("Invalid configuration, keeping the previous one: {e}"),
+        }
+    }
+}
+
+/// Swaps in the [RELOADABLE] settings of `reloaded` and copies them into `config`, so the
+/// others are reported again until the worker restarts
+fn apply(config: &mut Config, reloaded: Config, live: &LiveSettings, admission: &Admission) {
+    let changed = config.changed(&reloaded);
+    if changed.is_empty() {
+        log::info!("Configuration unchanged");
+        return;
+    }
+    let settings = match InvocationSettings::new(&reloaded) {
+        Ok(settings) => settings,
+        Err(e) => {
+            log::error!("Invalid configuration, keeping the previous one: {e}");
+            return;
+        }
+    };
+
+    live.set(settings);
+    admission.set_limits(
+        reloaded.max_concurrent_invocations,
+        reloaded.max_concurrent_per_function,
+    );
+    crate::logger::set_filter(reloaded.log_level.as_deref());
+    for name in changed.iter().filter(|name| !RELOADABLE.contains(name)) {
+        log::warn!("{name} changed, restart the worker to apply it");
+    }
+    log::info!("🔁 Reloaded configuration, changed: {}", changed.join(", "));
+    config.update(&reloaded, RELOADABLE);
+}
+
+async fn modified_at(path: Option<&Path>) -> Option<SystemTime> {
+    tokio::fs::metadata(path?).await.ok()?.modified().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let mut config = Config::default();
+        let live = LiveSettings::new(InvocationSettings::new(&config).unwrap());
+        let admission = Admission::new(0, 0);
+        let running = live.get();
+
+        let reloaded = Config {
+            max_sleep_ms: 10,
+            max_concurrent_invocations: 1,
+            port: 7000,
+            ..Config::default()
+        };
+        apply(&mut config, reloaded.clone(), &live, &admission);
+
+        assert_eq!(live.get().limits.max_sleep_ms, 10);
+        // Invocations already running keep their settings
+        assert_eq!(running.limits.max_sleep_ms, Config::default().max_sleep_ms);
+        let _permit = admission.try_acquire(uuid::Uuid::new_v4()).unwrap();
+        assert!(admission.try_acquire(uuid::Uuid::new_v4()).is_none());
+
+        // The port is not applied, so it is still reported as changed
+        assert_eq!(config.max_sleep_ms, 10);
+        assert_eq!(config.port, Config::default().port);
+        assert_eq!(config.changed(&reloaded), ["port"]);
+    }
+}
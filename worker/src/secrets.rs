@@ -0,0 +1,246 @@
+//! Per-function configuration exposed through the `nur_env_get` import.
+//!
+//! - `nur_env_get(name_ptr, name_len, buf_ptr, buf_len) -> len`: writes the value and returns its
+//!   length. When `buf_len` is too small nothing is written, call again with the returned length.
+//!
+//! Errors are negative [EnvError] codes. Values are loaded by a [SecretsProvider] together with
+//! the module in [crate::fetcher::FunctionFetcher] and must never be logged: [FunctionEnvVars]
+//! only prints variable names.
+
+use crate::config::Config;
+use crate::intrinsics::{NurFunctionEnv, read_guest, write_if_fits};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use uuid::Uuid;
+use wasmer::FunctionEnvMut;
+
+/// Returned to guests as negative numbers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnvError {
+    NotFound = -1,
+    InvalidName = -2,
+    /// The name or buffer range is outside of the guest memory
+    OutOfBounds = -3,
+}
+
+/// Variables of a single function
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct FunctionEnvVars(HashMap<String, String>);
+
+pub type SecretsFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<FunctionEnvVars, String>> + Send + 'a>>;
+
+pub trait SecretsProvider: Send + Sync {
+    fn load<'a>(&'a self, function_uuid: &'a Uuid) -> SecretsFuture<'a>;
+}
+
+/// Reads variables from the `function_env` table:
+///
+/// ```sql
+/// CREATE TABLE function_env (
+///     function_id uuid NOT NULL,
+///     name text NOT NULL,
+///     value text NOT NULL,
+///     PRIMARY KEY (function_id, name)
+/// );
+/// ```
+pub struct PostgresSecretsProvider {
+    client_pool: deadpool_postgres::Pool,
+}
+
+/// Reads variables from a JSON file keyed by function uuid, `{"<uuid>": {"NAME": "value"}}`.
+/// The file is read on every load, so edits apply to the next module fetch.
+pub struct FileSecretsProvider {
+    path: String,
+}
+
+/// Gives every function the worker variables starting with `prefix`, without the prefix
+pub struct EnvSecretsProvider {
+    prefix: String,
+}
+
+impl FunctionEnvVars {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+}
+
+impl FromIterator<(String, String)> for FunctionEnvVars {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        FunctionEnvVars(iter.into_iter().collect())
+    }
+}
+
+impl fmt::Debug for FunctionEnvVars {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<&String> = self.0.keys().collect();
+        names.sort();
+        f.debug_set().entries(names).finish()
+    }
+}
+
+impl PostgresSecretsProvider {
+    pub fn new(client_pool: deadpool_postgres::Pool) -> Self {
+        PostgresSecretsProvider { client_pool }
+    }
+}
+
+impl SecretsProvider for PostgresSecretsProvider {
+    fn load<'a>(&'a self, function_uuid: &'a Uuid) -> SecretsFuture<'a> {
+        Box::pin(async move {
+            let client = self
+                .client_pool
+                .get()
+                .await
+                .map_err(|e| format!("failed to get a Postgres connection: {e}"))?;
+            let stmt = client
+                .prepare_cached("SELECT name, value FROM function_env WHERE function_id = $1")
+                .await
+                .map_err(|e| format!("failed to prepare function_env query: {e}"))?;
+            let rows = client
+                .query(&stmt, &[function_uuid])
+                .await
+                .map_err(|e| format!("failed to query function_env: {e}"))?;
+            Ok(rows
+                .into_iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect())
+        })
+    }
+}
+
+impl FileSecretsProvider {
+    pub fn new(path: String) -> Self {
+        FileSecretsProvider { path }
+    }
+}
+
+impl SecretsProvider for FileSecretsProvider {
+    fn load<'a>(&'a self, function_uuid: &'a Uuid) -> SecretsFuture<'a> {
+        Box::pin(async move {
+            let contents = tokio::fs::read(&self.path)
+                .await
+                .map_err(|e| format!("failed to read {}: {e}", self.path))?;
+            // Deliberately not including serde's error, it may quote a value
+            let mut functions: HashMap<String, HashMap<String, String>> =
+                serde_json::from_slice(&contents).map_err(|_| {
+                    format!("{} is not a map of function uuids to variables", self.path)
+                })?;
+            Ok(FunctionEnvVars(
+                functions
+                    .remove(&function_uuid.to_string())
+                    .unwrap_or_default(),
+            ))
+        })
+    }
+}
+
+impl EnvSecretsProvider {
+    pub fn new(prefix: String) -> Self {
+        EnvSecretsProvider { prefix }
+    }
+}
+
+impl SecretsProvider for EnvSecretsProvider {
+    fn load<'a>(&'a self, _function_uuid: &'a Uuid) -> SecretsFuture<'a> {
+        let vars = std::env::vars()
+            .filter_map(|(name, value)| {
+                let name = name.strip_prefix(&self.prefix)?;
+                (!name.is_empty()).then(|| (name.to_string(), value))
+            })
+            .collect();
+        Box::pin(async move { Ok(vars) })
+    }
+}
+
+/// Source of the variables behind `nur_env_get`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecretsBackend {
+    Env,
+    Postgres,
+    File,
+}
+
+impl FromStr for SecretsBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "env" => Ok(SecretsBackend::Env),
+            "postgres" => Ok(SecretsBackend::Postgres),
+            "file" => Ok(SecretsBackend::File),
+            _ => Err("expected env, postgres or file".to_string()),
+        }
+    }
+}
+
+/// Builds the provider named by `secrets_backend`
+pub fn provider_from_config(
+    config: &Config,
+    client_pool: deadpool_postgres::Pool,
+) -> Result<Arc<dyn SecretsProvider>, String> {
+    match config.secrets_backend {
+        SecretsBackend::Env => Ok(Arc::new(EnvSecretsProvider::new(
+            config.secrets_env_prefix.clone(),
+        ))),
+        SecretsBackend::Postgres => Ok(Arc::new(PostgresSecretsProvider::new(client_pool))),
+        SecretsBackend::File => {
+            let path = crate::config::required("secrets_file", &config.secrets_file)?;
+            Ok(Arc::new(FileSecretsProvider::new(path.to_string())))
+        }
+    }
+}
+
+pub fn nur_env_get(
+    env: FunctionEnvMut<NurFunctionEnv>,
+    name_ptr: i32,
+    name_len: i32,
+    buf_ptr: i32,
+    buf_len: i32,
+) -> i32 {
+    log::trace!("nur_env_get({name_ptr}, {name_len}, {buf_ptr}, {buf_len})");
+    let Ok(name) = read_guest(&env, name_ptr, name_len) else {
+        return EnvError::OutOfBounds as i32;
+    };
+    let Ok(name) = String::from_utf8(name) else {
+        return EnvError::InvalidName as i32;
+    };
+    let Some(value) = env.data().env.get(&name) else {
+        return EnvError::NotFound as i32;
+    };
+    write_if_fits(&env, value.as_bytes(), buf_ptr, buf_len).unwrap_or(EnvError::OutOfBounds as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_provider() {
+        let function_uuid = Uuid::new_v4();
+        let path = std::env::temp_dir().join(format!("nur-secrets-{function_uuid}.json"));
+        std::fs::write(
+            &path,
+            format!(r#"{{"{function_uuid}": {{"API_KEY": "mu-123"}}}}"#),
+        )
+        .unwrap();
+        let provider = FileSecretsProvider::new(path.to_string_lossy().to_string());
+
+        let vars = provider.load(&function_uuid).await.unwrap();
+        assert_eq!(vars.get("API_KEY"), Some("mu-123"));
+        assert_eq!(format!("{vars:?}"), r#"{"API_KEY"}"#);
+        assert_eq!(
+            provider.load(&Uuid::new_v4()).await.unwrap(),
+            FunctionEnvVars::default()
+        );
+
+        std::fs::write(&path, format!(r#"{{"{function_uuid}": "mu-123"}}"#)).unwrap();
+        let e = provider.load(&function_uuid).await.unwrap_err();
+        assert!(!e.contains("mu-123"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,211 +1,621 @@
-use crate::fetcher::FunctionFetcher;
+use crate::abi::GuestAbi;
+use crate::admission::Admission;
+use crate::auth::Nonces;
+use crate::config::Config;
+use crate::context::{self, InvocationContext};
+use crate::fetcher::{FetchedFunction, FunctionFetch};
 use crate::handshake::handle_handshake;
-use crate::logs_service::{LogsService, SupabaseLogService};
-use crate::{fetcher, intrinsics};
+use crate::invocations::Invocations;
+use crate::kv::{self, KvNamespace, KvStore};
+use crate::logs_service::{LogQueue, LogsService};
+use crate::metrics::{METRICS, ModuleLoad};
+use crate::outbound::{self, OutboundHttp};
+use crate::reload::{InvocationSettings, LiveSettings};
+use crate::tls::GatewayTls;
+use crate::trace::{Span, SpanContext, SpanKind};
+use crate::wasi::{self, MemFs};
+use crate::{abi, component, intrinsics, secrets};
+use std::future::Future;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
 use std::{io, net::SocketAddr};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::ToSocketAddrs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::select;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_rustls::TlsAcceptor;
 use wasmer::{FunctionEnv, Instance, Module, Store, imports};
 
 // static WASM: &'static [u8] = include_bytes!("../test.wasm");
 
-const EXPORTED_POLL_HANDLER_SYMBOL_NAME: &str = "poll_stream";
 const EXPORTED_ALLOC_SYMBOL_NAME: &str = "alloc";
+/// Optional, frees the buffers handed to `poll_stream`
+const EXPORTED_DEALLOC_SYMBOL_NAME: &str = "dealloc";
 
-pub struct Server {
+/// Longest a gateway is given to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts gateway connections and runs the functions fetched through `F`
+pub struct Server<F> {
     listener: tokio::net::TcpListener,
-    function_fetcher: Arc<FunctionFetcher>,
-    logs_service: Arc<SupabaseLogService>,
+    /// [None] for plaintext connections
+    tls: Option<GatewayTls>,
+    state: ServerState<F>,
+}
+
+/// Everything a gateway connection needs, cheap to clone
+struct ServerState<F> {
+    function_fetcher: Arc<F>,
+    invocations: Invocations,
+    admission: Admission,
+    /// Nonces of signed handshakes, kept across reloads of the gateway keys
+    nonces: Nonces,
+    execution: ExecutionState,
+}
+
+// Not derived, which would require `F: Clone`
+impl<F> Clone for ServerState<F> {
+    fn clone(&self) -> Self {
+        ServerState {
+            function_fetcher: self.function_fetcher.clone(),
+            invocations: self.invocations.clone(),
+            admission: self.admission.clone(),
+            nonces: self.nonces.clone(),
+            execution: self.execution.clone(),
+        }
+    }
+}
+
+/// Everything [run_invocation] needs, cheap to clone
+#[derive(Clone)]
+pub struct ExecutionState {
+    pub log_queue: LogQueue,
+    /// Filesystem of WASI guests, [None] when WASI is disabled
+    wasi_fs: Option<Arc<MemFs>>,
+    kv_store: Arc<dyn KvStore>,
+    /// Limits and outbound HTTP, read once per invocation
+    settings: LiveSettings,
+    /// Behind `nur_now_ms`, `nur_random_fill` and `nur_sleep_ms`
+    clock: intrinsics::HostClock,
+}
+
+#[derive(Debug)]
+pub struct ShutdownSummary {
+    /// Connections accepted since startup
+    pub served: u64,
+    /// In-flight invocations that finished before the drain deadline
+    pub drained: usize,
+    /// In-flight invocations cut at the drain deadline
+    pub aborted: usize,
+    /// Logs that could not be flushed before exiting
+    pub logs_lost: usize,
+}
+
+/// Keeps [crate::metrics::Metrics::active_instances] accurate on every exit path
+struct ActiveInstanceGuard;
+
+/// Aborts the task once dropped, so that the tasks of an invocation never outlive it
+struct AbortOnDrop(JoinHandle<()>);
+
+impl ActiveInstanceGuard {
+    fn new() -> Self {
+        METRICS.active_instances.inc();
+        ActiveInstanceGuard
+    }
 }
 
-impl Server {
-    pub async fn new<A: ToSocketAddrs>(
-        addr: A,
-        function_fetcher: FunctionFetcher,
-        logs_service: SupabaseLogService,
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl Drop for ActiveInstanceGuard {
+    fn drop(&mut self) {
+        METRICS.active_instances.dec();
+    }
+}
+
+impl ExecutionState {
+    pub fn new(
+        config: &Config,
+        log_queue: LogQueue,
+        kv_store: Arc<dyn KvStore>,
     ) -> io::Result<Self> {
+        // Host functions block their thread with intrinsics::block_on
+        let flavor = tokio::runtime::Handle::try_current().map(|handle| handle.runtime_flavor());
+        if !matches!(flavor, Ok(tokio::runtime::RuntimeFlavor::MultiThread)) {
+            return Err(io::Error::other(
+                "guests need to run on a multi-threaded tokio runtime",
+            ));
+        }
+
+        let wasi_fs = if config.wasi_enabled {
+            let fs = match config.wasi_root_dir.as_ref() {
+                Some(root) => MemFs::from_dir(root)?,
+                None => MemFs::default(),
+            };
+            log::info!("🧩 WASI enabled, {} files in guest filesystem", fs.len());
+            Some(Arc::new(fs))
+        } else {
+            None
+        };
+
+        let settings = InvocationSettings::new(config).map_err(io::Error::other)?;
+
+        Ok(ExecutionState {
+            log_queue,
+            wasi_fs,
+            kv_store,
+            settings: LiveSettings::new(settings),
+            clock: intrinsics::HostClock::System,
+        })
+    }
+}
+
+impl<F: FunctionFetch + Send + Sync + 'static> Server<F> {
+    /// Binds to `host:port` of `config`
+    pub async fn new<L: LogsService + Send + Sync + 'static>(
+        config: &Config,
+        function_fetcher: Arc<F>,
+        logs_service: Arc<L>,
+        invocations: Invocations,
+        admission: Admission,
+        kv_store: Arc<dyn KvStore>,
+    ) -> io::Result<Self> {
+        let execution = ExecutionState::new(
+            config,
+            LogQueue::spawn(logs_service, config.log_queue_capacity, config.log_workers),
+            kv_store,
+        )?;
+
         Ok(Server {
-            listener: tokio::net::TcpListener::bind(&addr).await?,
-            function_fetcher: Arc::new(function_fetcher),
-            logs_service: Arc::new(logs_service),
+            listener: tokio::net::TcpListener::bind((config.host.as_str(), config.port)).await?,
+            tls: GatewayTls::from_config(config).map_err(io::Error::other)?,
+            state: ServerState {
+                function_fetcher,
+                invocations,
+                admission,
+                nonces: Nonces::default(),
+                execution,
+            },
         })
     }
 
-    pub async fn listen_forever_and_ever_amen(self) -> io::Result<()> {
+    /// Gives guests `clock` instead of the system one
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: intrinsics::HostClock) -> Self {
+        self.state.execution.clock = clock;
+        self
+    }
+
+    /// Swapped by [crate::reload::watch]
+    pub fn settings(&self) -> LiveSettings {
+        self.state.execution.settings.clone()
+    }
+
+    /// Reloaded by [GatewayTls::watch], [None] for plaintext connections
+    pub fn tls(&self) -> Option<GatewayTls> {
+        self.tls.clone()
+    }
+
+    /// Address the gateway connects to, useful when binding to port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves gateway connections until `shutdown` resolves, then drains in-flight
+    /// invocations for up to `drain_timeout` and flushes pending logs for up to `log_flush_timeout`.
+    pub async fn listen_forever_and_ever_amen(
+        self,
+        shutdown: impl Future<Output = ()>,
+        drain_timeout: Duration,
+        log_flush_timeout: Duration,
+    ) -> io::Result<ShutdownSummary> {
+        let mut connections = JoinSet::new();
+        let mut served: u64 = 0;
+        tokio::pin!(shutdown);
+
         loop {
-            let (socket, addr) = self.listener.accept().await?;
-            log::info!("💌 Gateway request started {addr}");
-            let function_fetcher = self.function_fetcher.clone();
-            let logs_service = self.logs_service.clone();
+            select! {
+                accepted = self.listener.accept() => {
+                    let (socket, addr) = accepted?;
+                    log::info!("💌 Gateway request started {addr}");
+                    served += 1;
+                    let state = self.state.clone();
+                    match &self.tls {
+                        None => connections.spawn(Server::handle_conn(socket, addr, state)),
+                        Some(tls) => connections.spawn(Server::handle_tls_conn(
+                            tls.acceptor(),
+                            socket,
+                            addr,
+                            state,
+                        )),
+                    };
+                }
+                // Reap finished connections so the set does not grow forever
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                _ = &mut shutdown => break,
+            }
+        }
 
-            tokio::spawn(Server::handle_conn(
-                socket,
-                addr,
-                function_fetcher,
-                logs_service,
-            ));
+        // Stop accepting right away, the gateway retries on another worker
+        drop(self.listener);
+
+        let in_flight = connections.len();
+        log::info!("🛑 Shutting down, draining {in_flight} in-flight invocations...");
+        let drained = tokio::time::timeout(drain_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+
+        let aborted = connections.len();
+        if drained.is_err() {
+            log::warn!("Drain deadline reached, aborting {aborted} invocations");
+            connections.shutdown().await;
         }
+
+        let logs_lost = self
+            .state
+            .execution
+            .log_queue
+            .flush(log_flush_timeout)
+            .await;
+
+        Ok(ShutdownSummary {
+            served,
+            drained: in_flight - aborted,
+            aborted,
+            logs_lost,
+        })
     }
 
-    async fn handle_conn(
+    /// Completes the TLS handshake, which fails for gateways without a trusted certificate
+    /// when client certificates are required
+    async fn handle_tls_conn(
+        acceptor: TlsAcceptor,
         socket: tokio::net::TcpStream,
         addr: SocketAddr,
-        function_fetcher: Arc<fetcher::FunctionFetcher>,
-        logs_service: Arc<SupabaseLogService>,
+        state: ServerState<F>,
     ) {
+        match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+            Ok(Ok(stream)) => Server::handle_conn(stream, addr, state).await,
+            Ok(Err(e)) => log::warn!("TLS handshake with {addr} failed: {e}"),
+            Err(_) => log::warn!("TLS handshake with {addr} timed out"),
+        }
+    }
+
+    async fn handle_conn<S>(socket: S, addr: SocketAddr, state: ServerState<F>)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let ServerState {
+            function_fetcher,
+            invocations,
+            admission,
+            nonces,
+            execution,
+        } = state;
+        let invocation = invocations.start(addr);
         let mut socket = socket;
-        log::debug!("start:handle_handshake");
-        let handshake = match handle_handshake(&mut socket, &*function_fetcher).await {
+        let mut invocation_span = Span::root("invocation", SpanKind::Server);
+        invocation_span.set_attribute("net.peer.addr", addr.to_string());
+
+        // Gateway keys as of the handshake, the invocation reads the other settings again
+        let settings = execution.settings.get();
+        let handshake = match handle_handshake(
+            &mut socket,
+            function_fetcher.as_ref(),
+            &admission,
+            settings.auth.as_ref(),
+            &nonces,
+            &mut invocation_span,
+        )
+        .await
+        {
             Ok(h) => h,
             Err(e) => {
                 log::error!("error:handle_handshake for addr={addr}: {e}");
+                invocation_span.set_error(e);
                 return;
             }
         };
-        log::debug!("finish:handle_handshake");
 
         let function_uuid = handshake.function_uuid;
-        let fetched_func = handshake.fetched_func;
+        let _permit = handshake.permit;
+        invocation.set_function_uuid(function_uuid);
+        invocation_span.set_attribute("nur.function_uuid", function_uuid.to_string());
+        if let Some(request_id) = handshake.metadata.get("x-request-id") {
+            invocation_span.set_attribute("nur.gateway.request_id", request_id.as_str());
+        }
+        let context = InvocationContext::new(
+            function_uuid,
+            handshake.last_deployment,
+            addr,
+            invocation.id(),
+            handshake.metadata,
+        );
 
-        let (mut socket_read_half, mut socket_write_half) = socket.into_split();
+        run_invocation(
+            socket,
+            addr,
+            execution,
+            handshake.fetched_func,
+            context,
+            invocation_span,
+        )
+        .await;
+    }
+}
 
-        log::debug!("start:wasm_module_instantiating");
-        let mut store = Store::default();
-        let module = if fetched_func.is_precompiled {
-            // SAFETY: We ourselves are the only responsible to precompile WASM modules.
-            // No external agent can mark a module as precompiled. See [FunctionFetcher::fetch].
-            match unsafe { Module::deserialize(&store, fetched_func.wasm_bytes.as_ref()) } {
-                Ok(module) => module,
-                Err(e) => {
-                    log::error!("Failed to deserialize WebAssembly module: {e}");
-                    // TODO: send error back. Probably worth implementing a default error handler
-                    return;
-                }
-            }
-        } else {
-            match Module::new(&store, fetched_func.wasm_bytes) {
-                Ok(module) => module,
-                Err(e) => {
-                    log::error!("Failed to compile WebAssembly module: {e}");
-                    // TODO: send error back. Probably worth implementing a default error handler
-                    return;
-                }
-            }
-        };
+/// Runs `fetched_func` against the HTTP request arriving on `socket`, once the function is known.
+/// Shared by gateway connections and the [crate::dev] runner.
+pub async fn run_invocation<S>(
+    socket: S,
+    addr: SocketAddr,
+    execution: ExecutionState,
+    fetched_func: FetchedFunction,
+    context: InvocationContext,
+    invocation_span: Span,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let ExecutionState {
+        log_queue,
+        wasi_fs,
+        kv_store,
+        settings,
+        clock,
+    } = execution;
+    let settings = settings.get();
+    let limits = settings.limits;
+    let function_uuid = context.function_uuid;
+    let invocation_ctx = invocation_span.context();
 
-        let (msg_tx, msg_rx) = flume::unbounded::<intrinsics::NurWasmMessage>();
+    let (mut socket_read_half, mut socket_write_half) = tokio::io::split(socket);
 
-        let func_env = FunctionEnv::new(
-            &mut store,
-            intrinsics::NurFunctionEnv {
-                memory: None,
-                channel_tx: msg_tx,
-            },
-        );
-
-        let import_object = imports! {
-            "nur" => {
-                "nur_log" => wasmer::Function::new_typed_with_env(&mut store, &func_env, intrinsics::nur_log),
-                "nur_send" => wasmer::Function::new_typed_with_env(&mut store, &func_env, intrinsics::nur_send),
-                "nur_end" => wasmer::Function::new_typed_with_env(&mut store, &func_env, intrinsics::nur_end),
-            },
-        };
-
-        let instance = match Instance::new(&mut store, &module, &import_object) {
-            Ok(instance) => instance,
+    let mut store = Store::default();
+    let mut load_span = invocation_span.child("module_load");
+    load_span.set_attribute(
+        "nur.module_load.kind",
+        if fetched_func.is_precompiled {
+            "deserialize"
+        } else {
+            "compile"
+        },
+    );
+    let load_started_at = Instant::now();
+    let module = if fetched_func.is_precompiled {
+        // SAFETY: We ourselves are the only responsible to precompile WASM modules.
+        // No external agent can mark a module as precompiled. See [FunctionFetcher::fetch].
+        match unsafe { Module::deserialize(&store, fetched_func.wasm_bytes.as_ref()) } {
+            Ok(module) => {
+                METRICS.module_load(ModuleLoad::Deserialize, load_started_at.elapsed());
+                module
+            }
             Err(e) => {
-                // TODO: send error back
-                log::error!("Failed to instantiate WebAssembly module: {e}");
+                log::error!("Failed to deserialize WebAssembly module: {e}");
+                load_span.set_error(e.to_string());
+                // TODO: send error back. Probably worth implementing a default error handler
                 return;
             }
-        };
-
-        let instance_memory = match instance.exports.get_memory("memory") {
-            Ok(mem) => mem.clone(),
+        }
+    } else if abi::is_component(&fetched_func.wasm_bytes) {
+        let module = component::core_module(&fetched_func.wasm_bytes)
+            .and_then(|core_module| Module::new(&store, core_module).map_err(|e| e.to_string()));
+        match module {
+            Ok(module) => {
+                METRICS.module_load(ModuleLoad::Compile, load_started_at.elapsed());
+                module
+            }
             Err(e) => {
-                log::error!("Unable to get WASM memory. Aborting: {e}");
+                log::error!("Failed to compile WebAssembly component: {e}");
+                load_span.set_error(e);
+                // TODO: send error back. Probably worth implementing a default error handler
                 return;
             }
-        };
+        }
+    } else {
+        match Module::new(&store, fetched_func.wasm_bytes) {
+            Ok(module) => {
+                METRICS.module_load(ModuleLoad::Compile, load_started_at.elapsed());
+                module
+            }
+            Err(e) => {
+                log::error!("Failed to compile WebAssembly module: {e}");
+                load_span.set_error(e.to_string());
+                // TODO: send error back. Probably worth implementing a default error handler
+                return;
+            }
+        }
+    };
+
+    drop(load_span);
+
+    let mut instantiate_span = invocation_span.child("instantiate");
+    let (msg_tx, msg_rx) = flume::unbounded::<intrinsics::NurWasmMessage>();
+    let host_tx = msg_tx.clone();
+
+    let wasi_env =
+        wasi_fs.map(|fs| FunctionEnv::new(&mut store, wasi::WasiEnv::new(msg_tx.clone(), fs)));
+    let func_env = FunctionEnv::new(
+        &mut store,
+        intrinsics::NurFunctionEnv {
+            memory: None,
+            channel_tx: msg_tx,
+            outbound: OutboundHttp::new(settings.outbound.clone(), function_uuid),
+            kv: KvNamespace::new(kv_store, function_uuid, limits.kv_max_value_bytes),
+            env: fetched_func.env.clone(),
+            clock,
+            context,
+            max_sleep: Duration::from_millis(limits.max_sleep_ms),
+        },
+    );
+
+    let mut import_object = imports! {
+        "nur" => {
+            "nur_log" => wasmer::Function::new_typed_with_env(&mut store, &func_env, intrinsics::nur_log),
+            "nur_send" => wasmer::Function::new_typed_with_env(&mut store, &func_env, intrinsics::nur_send),
+            "nur_end" => wasmer::Function::new_typed_with_env(&mut store, &func_env, intrinsics::nur_end),
+            "nur_response_start" => wasmer::Function::new_typed_with_env(&mut store, &func_env, intrinsics::nur_response_start),
+            "nur_response_write" => wasmer::Function::new_typed_with_env(&mut store, &func_env, intrinsics::nur_response_write),
+            "nur_http_fetch" => wasmer::Function::new_typed_with_env(&mut store, &func_env, outbound::nur_http_fetch),
+            "nur_http_status" => wasmer::Function::new_typed_with_env(&mut store, &func_env, outbound::nur_http_status),
+            "nur_http_headers" => wasmer::Function::new_typed_with_env(&mut store, &func_env, outbound::nur_http_headers),
+            "nur_http_read" => wasmer::Function::new_typed_with_env(&mut store, &func_env, outbound::nur_http_read),
+            "nur_http_close" => wasmer::Function::new_typed_with_env(&mut store, &func_env, outbound::nur_http_close),
+            "nur_kv_get" => wasmer::Function::new_typed_with_env(&mut store, &func_env, kv::nur_kv_get),
+            "nur_kv_set" => wasmer::Function::new_typed_with_env(&mut store, &func_env, kv::nur_kv_set),
+            "nur_kv_delete" => wasmer::Function::new_typed_with_env(&mut store, &func_env, kv::nur_kv_delete),
+            "nur_kv_list" => wasmer::Function::new_typed_with_env(&mut store, &func_env, kv::nur_kv_list),
+            "nur_context_get" => wasmer::Function::new_typed_with_env(&mut store, &func_env, context::nur_context_get),
+            "nur_env_get" => wasmer::Function::new_typed_with_env(&mut store, &func_env, secrets::nur_env_get),
+            "nur_now_ms" => wasmer::Function::new_typed_with_env(&mut store, &func_env, intrinsics::nur_now_ms),
+            "nur_random_fill" => wasmer::Function::new_typed_with_env(&mut store, &func_env, intrinsics::nur_random_fill),
+            "nur_sleep_ms" => wasmer::Function::new_typed_with_env(&mut store, &func_env, intrinsics::nur_sleep_ms),
+        },
+    };
+    if let Some(wasi_env) = &wasi_env {
+        let wasi_exports = wasi::exports(&mut store, wasi_env);
+        import_object.register_namespace(wasi::WASI_MODULE_NAME, wasi_exports);
+    }
+
+    let instance = match Instance::new(&mut store, &module, &import_object) {
+        Ok(instance) => instance,
+        Err(e) => {
+            // TODO: send error back
+            log::error!("Failed to instantiate WebAssembly module: {e}");
+            instantiate_span.set_error(e.to_string());
+            return;
+        }
+    };
+
+    let instance_memory = match instance.exports.get_memory("memory") {
+        Ok(mem) => mem.clone(),
+        Err(e) => {
+            log::error!("Unable to get WASM memory. Aborting: {e}");
+            return;
+        }
+    };
 
-        func_env.as_mut(&mut store).memory = Some(instance_memory.clone());
+    func_env.as_mut(&mut store).memory = Some(instance_memory.clone());
+    if let Some(wasi_env) = &wasi_env {
+        wasi_env.as_mut(&mut store).memory = Some(instance_memory.clone());
 
-        let wasm_poll_stream = match instance
+        if let Ok(initialize) = instance
             .exports
-            .get_function(EXPORTED_POLL_HANDLER_SYMBOL_NAME)
+            .get_function(wasi::EXPORTED_INITIALIZE_SYMBOL_NAME)
+            && let Err(e) = initialize.call(&mut store, &[])
         {
-            Ok(func) => func.clone(),
-            Err(e) => {
-                log::error!(
-                    "Failed to get exported function '{}': {}",
-                    EXPORTED_POLL_HANDLER_SYMBOL_NAME,
-                    e
-                );
-                return;
-            }
-        };
+            log::error!(
+                "Call error: {}(): {e}",
+                wasi::EXPORTED_INITIALIZE_SYMBOL_NAME
+            );
+            instantiate_span.set_error(e.to_string());
+            return;
+        }
+    }
 
-        let wasm_alloc = match instance.exports.get_function(EXPORTED_ALLOC_SYMBOL_NAME) {
-            Ok(func) => func.clone(),
-            Err(e) => {
-                log::error!(
-                    "Failed to get exported function '{}': {}",
-                    EXPORTED_ALLOC_SYMBOL_NAME,
-                    e
-                );
-                return;
-            }
-        };
-        log::debug!("end:wasm_module_instantiating");
+    let Some(guest_abi) = GuestAbi::detect(&instance) else {
+        log::error!(
+            "Module exports neither '{}', '{}' nor '{}'",
+            component::EXPORTED_HANDLE_SYMBOL_NAME,
+            abi::EXPORTED_REQUEST_HANDLER_SYMBOL_NAME,
+            abi::EXPORTED_POLL_HANDLER_SYMBOL_NAME
+        );
+        return;
+    };
+    // detect() already checked the export
+    let wasm_handler = instance
+        .exports
+        .get_function(guest_abi.handler_symbol_name())
+        .unwrap()
+        .clone();
 
-        let mut buf = vec![0; 1024];
+    let wasm_alloc = match instance.exports.get_function(EXPORTED_ALLOC_SYMBOL_NAME) {
+        Ok(func) => Some(func.clone()),
+        // Components allocate with their `cabi_realloc` instead
+        Err(_) if guest_abi == GuestAbi::Component => None,
+        Err(e) => {
+            log::error!(
+                "Failed to get exported function '{}': {}",
+                EXPORTED_ALLOC_SYMBOL_NAME,
+                e
+            );
+            return;
+        }
+    };
+    let wasm_dealloc = instance
+        .exports
+        .get_function(EXPORTED_DEALLOC_SYMBOL_NAME)
+        .ok()
+        .cloned();
+    drop(instantiate_span);
 
-        let wasm_aborted = Arc::new(AtomicBool::new(false));
+    let _active_instance = ActiveInstanceGuard::new();
 
-        let wasm_aborted1 = wasm_aborted.clone();
-        let listen_wasm_messages_task = tokio::spawn(async move {
-            loop {
-                match msg_rx.recv_async().await {
-                    Ok(intrinsics::NurWasmMessage::Abort) => {
-                        wasm_aborted1.store(true, std::sync::atomic::Ordering::SeqCst);
-                        let _ = socket_write_half.shutdown().await;
-                        // abort wasm program
-                        log::info!("Aborting connection with {addr}");
-                        break;
+    let wasm_aborted = Arc::new(AtomicBool::new(false));
+
+    let wasm_aborted1 = wasm_aborted.clone();
+    let mut listen_wasm_messages_task = AbortOnDrop(tokio::spawn(async move {
+        let mut response = (guest_abi != GuestAbi::PollStream).then(abi::ResponseEncoder::default);
+        loop {
+            let data = match msg_rx.recv_async().await {
+                Ok(intrinsics::NurWasmMessage::Abort) => {
+                    wasm_aborted1.store(true, std::sync::atomic::Ordering::SeqCst);
+                    if let Some(response) = response.as_mut() {
+                        let data = response.finish();
+                        write_data(&mut socket_write_half, &data, addr, invocation_ctx).await;
                     }
-                    Ok(intrinsics::NurWasmMessage::SendData { data }) => {
-                        if let Err(e) = socket_write_half.write(&data).await {
-                            log::error!("Failed to send data to {addr}: {e}");
-                            break;
+                    let _ = socket_write_half.shutdown().await;
+                    // abort wasm program
+                    log::info!("Aborting connection with {addr}");
+                    break;
+                }
+                Ok(intrinsics::NurWasmMessage::SendData { data }) => data,
+                Ok(intrinsics::NurWasmMessage::ResponseStart { status, headers }) => {
+                    match response.as_mut() {
+                        Some(response) => response.start(status, &headers),
+                        None => {
+                            log::warn!("Ignoring nur_response_start() from a poll_stream guest");
+                            continue;
                         }
                     }
-                    Ok(intrinsics::NurWasmMessage::LogMessage { log }) => {
-                        let logs_service = logs_service.clone();
-                        log::trace!("log_str: {log}");
-                        // Detach log sending
-                        tokio::spawn(async move {
-                            logs_service
-                                .send(&function_uuid, &log)
-                                .await
-                                .unwrap_or_else(|e| {
-                                    log::error!(
-                                        "logs_service.send: Failed to send log for function_uuid={function_uuid}: {e}"
-                                    );
-                                });
-                        });
+                }
+                Ok(intrinsics::NurWasmMessage::ResponseBody { data }) => match response.as_mut() {
+                    Some(response) => response.write(&data),
+                    None => {
+                        log::warn!("Ignoring nur_response_write() from a poll_stream guest");
+                        continue;
                     }
-                    Err(flume::RecvError::Disconnected) => {
-                        log::info!("Channel closed, aborting connection with {addr}");
-                        break;
+                },
+                Ok(intrinsics::NurWasmMessage::LogMessage { log }) => {
+                    log::trace!("log_str: {log}");
+                    log_queue.push(function_uuid, log);
+                    continue;
+                }
+                Err(flume::RecvError::Disconnected) => {
+                    if let Some(response) = response.as_mut() {
+                        let data = response.finish();
+                        write_data(&mut socket_write_half, &data, addr, invocation_ctx).await;
                     }
+                    // Also sends the TLS close_notify, without it clients see a truncated response
+                    let _ = socket_write_half.shutdown().await;
+                    log::info!("Channel closed, aborting connection with {addr}");
+                    break;
                 }
+            };
+            if !write_data(&mut socket_write_half, &data, addr, invocation_ctx).await {
+                break;
             }
-        });
+        }
+    }));
 
-        let read_socket_task = tokio::spawn(async move {
+    let mut guest_task = AbortOnDrop(match guest_abi {
+        GuestAbi::PollStream => tokio::spawn(async move {
+            // Checked above for every ABI but components
+            let wasm_alloc = wasm_alloc.unwrap();
+            let mut buf = vec![0; 1024];
             loop {
                 let read_n = socket_read_half.read(&mut buf).await;
                 match read_n {
@@ -215,12 +625,17 @@ impl Server {
                             return;
                         }
                         // We send an empty poll to indicate that the request has been closed
-                        match wasm_poll_stream
-                            .call(&mut store, &[wasmer::Value::I32(0), wasmer::Value::I32(0)])
-                        {
+                        let mut span = Span::child_of(invocation_ctx, "poll_stream");
+                        span.set_attribute("nur.bytes", 0);
+                        let call_started_at = Instant::now();
+                        let result = wasm_handler
+                            .call(&mut store, &[wasmer::Value::I32(0), wasmer::Value::I32(0)]);
+                        METRICS.guest_execution.observe(call_started_at.elapsed());
+                        match result {
                             Ok(_) => {}
                             Err(e) => {
                                 log::error!("Call error: poll_stream(0, 0): {e}");
+                                span.set_error(e.to_string());
                                 return;
                             }
                         };
@@ -228,37 +643,19 @@ impl Server {
                     }
                     Ok(n) => {
                         log::debug!("read {n} bytes from socket {addr}");
+                        METRICS.bytes_received.add(n as u64);
                         if wasm_aborted.load(std::sync::atomic::Ordering::SeqCst) {
                             return;
                         }
-                        let ptr = match wasm_alloc.call(&mut store, &[wasmer::Value::I32(n as i32)])
-                        {
+                        let ptr_num = match write_to_guest(
+                            &mut store,
+                            &wasm_alloc,
+                            &instance_memory,
+                            &buf[..n],
+                        ) {
                             Ok(ptr) => ptr,
                             Err(e) => {
-                                log::error!("Call error: alloc({n}): {e}");
-                                return;
-                            }
-                        };
-
-                        let ptr = &ptr[0];
-
-                        let ptr_num = *match ptr {
-                            wasmer::Value::I32(num) => num,
-                            _ => {
-                                log::error!(
-                                    "Expected I32 return value from alloc function. Aborting."
-                                );
-                                return;
-                            }
-                        };
-
-                        match instance_memory
-                            .view(&store)
-                            .write(ptr_num as u64, &buf[..n])
-                        {
-                            Ok(_) => {}
-                            Err(e) => {
-                                log::error!("Failed to write to WASM memory at &{ptr_num}: {}", e);
+                                log::error!("{e}");
                                 return;
                             }
                         };
@@ -266,16 +663,33 @@ impl Server {
                         if wasm_aborted.load(std::sync::atomic::Ordering::SeqCst) {
                             return;
                         }
-                        match wasm_poll_stream.call(
+                        let mut span = Span::child_of(invocation_ctx, "poll_stream");
+                        span.set_attribute("nur.bytes", n as i64);
+                        let call_started_at = Instant::now();
+                        let result = wasm_handler.call(
                             &mut store,
                             &[wasmer::Value::I32(ptr_num), wasmer::Value::I32(n as i32)],
-                        ) {
+                        );
+                        METRICS.guest_execution.observe(call_started_at.elapsed());
+                        match result {
                             Ok(_) => {}
                             Err(e) => {
                                 log::error!("Call error: poll_stream({ptr_num}, {n}): {e}",);
+                                span.set_error(e.to_string());
                                 return;
                             }
                         };
+
+                        // The guest copied whatever it needed during the poll
+                        if let Some(wasm_dealloc) = &wasm_dealloc
+                            && let Err(e) = wasm_dealloc.call(
+                                &mut store,
+                                &[wasmer::Value::I32(ptr_num), wasmer::Value::I32(n as i32)],
+                            )
+                        {
+                            log::error!("Call error: dealloc({ptr_num}, {n}): {e}");
+                            return;
+                        }
                     }
                     Err(e) => {
                         log::error!("Error reading from socket: {}", e);
@@ -283,15 +697,642 @@ impl Server {
                     }
                 };
             }
-        });
+        }),
+        GuestAbi::HandleRequest => tokio::spawn(async move {
+            let Some(request) = read_request(
+                &mut socket_read_half,
+                limits.max_request_body_bytes,
+                addr,
+                &host_tx,
+            )
+            .await
+            else {
+                return;
+            };
 
-        select! {
-            _ = listen_wasm_messages_task => {
-                log::debug!("listen_wasm_messages_task done for {addr}");
-            },
-            _ = read_socket_task => {
-                log::debug!("read_socket_task done for {addr}");
+            // Checked above for every ABI but components
+            let wasm_alloc = wasm_alloc.unwrap();
+            let encoded = abi::encode_request(&request);
+            let ptr_num = match write_to_guest(&mut store, &wasm_alloc, &instance_memory, &encoded)
+            {
+                Ok(ptr) => ptr,
+                Err(e) => {
+                    log::error!("{e}");
+                    return;
+                }
+            };
+
+            let mut span = Span::child_of(invocation_ctx, "handle_request");
+            span.set_attribute("http.method", request.method.as_str());
+            span.set_attribute("nur.bytes", encoded.len() as i64);
+            let call_started_at = Instant::now();
+            let result = wasm_handler.call(
+                &mut store,
+                &[
+                    wasmer::Value::I32(ptr_num),
+                    wasmer::Value::I32(encoded.len() as i32),
+                ],
+            );
+            METRICS.guest_execution.observe(call_started_at.elapsed());
+            if let Err(e) = result {
+                log::error!(
+                    "Call error: handle_request({ptr_num}, {}): {e}",
+                    encoded.len()
+                );
+                span.set_error(e.to_string());
+            }
+            // Dropping the store closes the channel, which finishes the response
+        }),
+        GuestAbi::Component => tokio::spawn(async move {
+            let Some(request) = read_request(
+                &mut socket_read_half,
+                limits.max_request_body_bytes,
+                addr,
+                &host_tx,
+            )
+            .await
+            else {
+                return;
+            };
+
+            let mut span = Span::child_of(invocation_ctx, "handle");
+            span.set_attribute("http.method", request.method.as_str());
+            span.set_attribute("nur.bytes", request.body.len() as i64);
+            let call_started_at = Instant::now();
+            let result = component::handle(&mut store, &instance, &request);
+            METRICS.guest_execution.observe(call_started_at.elapsed());
+            match result {
+                Ok(response) => {
+                    let _ = host_tx.send(intrinsics::NurWasmMessage::ResponseStart {
+                        status: response.status,
+                        headers: response.headers,
+                    });
+                    let _ = host_tx.send(intrinsics::NurWasmMessage::ResponseBody {
+                        data: response.body,
+                    });
+                }
+                Err(e) => {
+                    log::error!("{e}");
+                    span.set_error(e);
+                }
+            }
+            // Dropping the store closes the channel, which finishes the response
+        }),
+    });
+
+    // The guest may still run after the response is sent, and the response may still be
+    // written after the guest returns. Awaiting both keeps the invocation in flight, so
+    // draining waits for them.
+    let reader = guest_task.0.abort_handle();
+    let _ = tokio::join!(
+        async {
+            let _ = (&mut listen_wasm_messages_task.0).await;
+            if guest_abi == GuestAbi::PollStream {
+                // Nothing the guest sends is forwarded anymore, and a poll_stream guest would
+                // otherwise wait for the client to hang up. A running poll finishes first.
+                reader.abort();
             }
+        },
+        &mut guest_task.0
+    );
+    log::debug!("Invocation tasks done for {addr}");
+}
+
+/// Reads the request of a `handle_request` or component guest. Requests the worker can't
+/// hand over are answered right away, and [None] is returned.
+async fn read_request<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_body_len: usize,
+    addr: SocketAddr,
+    host_tx: &flume::Sender<intrinsics::NurWasmMessage>,
+) -> Option<abi::GuestRequest> {
+    match abi::read_request(reader, max_body_len).await {
+        Ok(request) => Some(request),
+        Err(e) => {
+            log::info!("Unable to read request from {addr}: {e:?}");
+            if let Some((status, body)) = e.response() {
+                let headers = vec![
+                    ("content-type".to_string(), "text/plain".to_string()),
+                    ("content-length".to_string(), body.len().to_string()),
+                ];
+                let _ = host_tx.send(intrinsics::NurWasmMessage::ResponseStart { status, headers });
+                let _ = host_tx.send(intrinsics::NurWasmMessage::ResponseBody {
+                    data: body.into_bytes(),
+                });
+            }
+            let _ = host_tx.send(intrinsics::NurWasmMessage::Abort);
+            None
         }
     }
 }
+
+/// Copies `data` into a buffer allocated by the guest, returning its address
+fn write_to_guest(
+    store: &mut Store,
+    alloc: &wasmer::Function,
+    memory: &wasmer::Memory,
+    data: &[u8],
+) -> Result<i32, String> {
+    let len = data.len();
+    let ptr = alloc
+        .call(store, &[wasmer::Value::I32(len as i32)])
+        .map_err(|e| format!("Call error: alloc({len}): {e}"))?;
+
+    let Some(wasmer::Value::I32(ptr)) = ptr.first() else {
+        return Err("Expected I32 return value from alloc function. Aborting.".to_string());
+    };
+
+    memory
+        .view(store)
+        .write(*ptr as u64, data)
+        .map_err(|e| format!("Failed to write to WASM memory at &{ptr}: {e}"))?;
+    Ok(*ptr)
+}
+
+/// Writes `data` to the client, returning false once the connection is unusable
+async fn write_data(
+    socket: &mut (impl AsyncWrite + Unpin),
+    data: &[u8],
+    addr: SocketAddr,
+    invocation_ctx: Option<SpanContext>,
+) -> bool {
+    if data.is_empty() {
+        return true;
+    }
+    let mut span = Span::child_of(invocation_ctx, "response_write");
+    match socket.write_all(data).await {
+        Ok(()) => {
+            METRICS.bytes_sent.add(data.len() as u64);
+            span.set_attribute("nur.bytes", data.len() as i64);
+            true
+        }
+        Err(e) => {
+            log::error!("Failed to send data to {addr}: {e}");
+            span.set_error(e.to_string());
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::intrinsics::{FixedClock, HostClock};
+    use crate::kv::MemoryKvStore;
+    use crate::logs_service::{LogQueue, MemoryLogsService};
+    use crate::server::ExecutionState;
+    use crate::test_support::{FakeGateway, TestCerts, TestWorker, fixtures};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_hello_world() {
+        let worker = TestWorker::start().await;
+        let function_uuid = worker.deploy(&fixtures::hello_world());
+
+        let response = worker
+            .gateway
+            .invoke(
+                function_uuid,
+                &[("x-request-id", "req-1")],
+                b"GET /cows HTTP/1.1\r\nhost: nur\r\n\r\n",
+            )
+            .await;
+        assert_eq!(response.handshake_status, 0);
+        assert_eq!(response.status(), Some(200));
+        assert!(response.body().contains(r#"{"msg": "Hello world, wasm!"}"#));
+
+        assert_eq!(worker.fetcher.fetches(), [(function_uuid, 0)]);
+        let logs = worker.logs.clone();
+        let summary = worker.stop().await;
+        assert_eq!(summary.served, 1);
+        assert_eq!(summary.logs_lost, 0);
+        assert_eq!(logs.of(function_uuid), ["Look, there is a request!"]);
+    }
+
+    #[tokio::test]
+    async fn test_requires_multi_thread_runtime() {
+        let log_queue = LogQueue::spawn(Arc::new(MemoryLogsService::default()), 16, 1);
+        let kv_store = Arc::new(MemoryKvStore::default());
+        let Err(e) = ExecutionState::new(&Config::default(), log_queue, kv_store) else {
+            panic!("expected the current-thread runtime to be refused");
+        };
+        assert_eq!(
+            e.to_string(),
+            "guests need to run on a multi-threaded tokio runtime"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_response_out_of_bounds() {
+        let worker = TestWorker::start().await;
+        // Traps unless both out of bounds calls return -1, answering a 502 then
+        let function_uuid = worker.deploy(
+            r#"(module
+                (import "nur" "nur_response_start" (func $start (param i32 i32 i32) (result i32)))
+                (import "nur" "nur_response_write" (func $write (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "handle_request") (param i32 i32)
+                    (if (i32.ne (call $write (i32.const 65530) (i32.const 100)) (i32.const -1))
+                        (then unreachable))
+                    (if (i32.ne
+                            (call $start (i32.const 200) (i32.const 0x7fffffff) (i32.const 0x7fffffff))
+                            (i32.const -1))
+                        (then unreachable))
+                    ;; Four zero bytes at 0, no headers
+                    (drop (call $start (i32.const 204) (i32.const 0) (i32.const 4))))
+            )"#,
+        );
+
+        let response = worker
+            .gateway
+            .invoke(function_uuid, &[], b"GET / HTTP/1.1\r\n\r\n")
+            .await;
+        assert_eq!(response.status(), Some(204));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_kv_out_of_bounds() {
+        let worker = TestWorker::start().await;
+        // Traps unless every out of bounds key, value or buffer returns -6, answering a 502 then
+        let function_uuid = worker.deploy(
+            r#"(module
+                (import "nur" "nur_kv_get" (func $get (param i32 i32 i32 i32) (result i32)))
+                (import "nur" "nur_kv_set" (func $set (param i32 i32 i32 i32) (result i32)))
+                (import "nur" "nur_response_start" (func $start (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 16) "cow")
+                (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "handle_request") (param i32 i32)
+                    (if (i32.ne (call $set (i32.const 16) (i32.const 3) (i32.const 65530) (i32.const 100))
+                            (i32.const -6))
+                        (then unreachable))
+                    (if (i32.ne
+                            (call $set (i32.const 16) (i32.const 3) (i32.const 0x7fffffff) (i32.const 0x7fff))
+                            (i32.const -6))
+                        (then unreachable))
+                    (if (i32.ne (call $get (i32.const 65530) (i32.const 100) (i32.const 0) (i32.const 0))
+                            (i32.const -6))
+                        (then unreachable))
+                    (drop (call $set (i32.const 16) (i32.const 3) (i32.const 16) (i32.const 3)))
+                    (if (i32.ne (call $get (i32.const 16) (i32.const 3) (i32.const -8) (i32.const 16))
+                            (i32.const -6))
+                        (then unreachable))
+                    ;; Four zero bytes at 0, no headers
+                    (drop (call $start (i32.const 204) (i32.const 0) (i32.const 4))))
+            )"#,
+        );
+
+        let response = worker
+            .gateway
+            .invoke(function_uuid, &[], b"GET / HTTP/1.1\r\n\r\n")
+            .await;
+        assert_eq!(response.status(), Some(204));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_http_fetch_out_of_bounds() {
+        let worker = TestWorker::start().await;
+        // Traps unless both out of bounds requests return -8, answering a 502 then
+        let function_uuid = worker.deploy(
+            r#"(module
+                (import "nur" "nur_http_fetch" (func $fetch (param i32 i32) (result i32)))
+                (import "nur" "nur_response_start" (func $start (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "handle_request") (param i32 i32)
+                    (if (i32.ne (call $fetch (i32.const 65530) (i32.const 100)) (i32.const -8))
+                        (then unreachable))
+                    (if (i32.ne (call $fetch (i32.const 0x7fffffff) (i32.const 0x7fff)) (i32.const -8))
+                        (then unreachable))
+                    ;; Four zero bytes at 0, no headers
+                    (drop (call $start (i32.const 204) (i32.const 0) (i32.const 4))))
+            )"#,
+        );
+
+        let response = worker
+            .gateway
+            .invoke(function_uuid, &[], b"GET / HTTP/1.1\r\n\r\n")
+            .await;
+        assert_eq!(response.status(), Some(204));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_env_get_out_of_bounds() {
+        let worker = TestWorker::start().await;
+        // Traps unless both out of bounds names return -3, answering a 502 then
+        let function_uuid = worker.deploy(
+            r#"(module
+                (import "nur" "nur_env_get" (func $get (param i32 i32 i32 i32) (result i32)))
+                (import "nur" "nur_response_start" (func $start (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "handle_request") (param i32 i32)
+                    (if (i32.ne (call $get (i32.const 65530) (i32.const 100) (i32.const 0) (i32.const 0))
+                            (i32.const -3))
+                        (then unreachable))
+                    (if (i32.ne (call $get (i32.const 0x7fffffff) (i32.const 0x7fff) (i32.const 0) (i32.const 0))
+                            (i32.const -3))
+                        (then unreachable))
+                    ;; Four zero bytes at 0, no headers
+                    (drop (call $start (i32.const 204) (i32.const 0) (i32.const 4))))
+            )"#,
+        );
+
+        let response = worker
+            .gateway
+            .invoke(function_uuid, &[], b"GET / HTTP/1.1\r\n\r\n")
+            .await;
+        assert_eq!(response.status(), Some(204));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_context_get_out_of_bounds() {
+        let worker = TestWorker::start().await;
+        // Traps unless out of bounds keys and buffers return -2, answering a 502 then
+        let function_uuid = worker.deploy(
+            r#"(module
+                (import "nur" "nur_context_get" (func $get (param i32 i32 i32 i32) (result i32)))
+                (import "nur" "nur_response_start" (func $start (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 16) "function_uuid")
+                (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "handle_request") (param i32 i32)
+                    (if (i32.ne (call $get (i32.const 65530) (i32.const 100) (i32.const 0) (i32.const 0))
+                            (i32.const -2))
+                        (then unreachable))
+                    (if (i32.ne (call $get (i32.const 0x7fffffff) (i32.const 0x7fff) (i32.const 0) (i32.const 0))
+                            (i32.const -2))
+                        (then unreachable))
+                    (if (i32.ne (call $get (i32.const 16) (i32.const 13) (i32.const -8) (i32.const 64))
+                            (i32.const -2))
+                        (then unreachable))
+                    ;; Four zero bytes at 0, no headers
+                    (drop (call $start (i32.const 204) (i32.const 0) (i32.const 4))))
+            )"#,
+        );
+
+        let response = worker
+            .gateway
+            .invoke(function_uuid, &[], b"GET / HTTP/1.1\r\n\r\n")
+            .await;
+        assert_eq!(response.status(), Some(204));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_time_and_randomness() {
+        let clock = HostClock::Fixed(Arc::new(FixedClock::new(1_000, 42)));
+        let worker = TestWorker::start_with_clock(Config::default(), clock.clone()).await;
+        // Answers with the time before and after sleeping a minute, then 8 random bytes
+        let function_uuid = worker.deploy(
+            r#"(module
+                (import "nur" "nur_response_write" (func $write (param i32 i32) (result i32)))
+                (import "nur" "nur_now_ms" (func $now (result i64)))
+                (import "nur" "nur_random_fill" (func $random (param i32 i32) (result i32)))
+                (import "nur" "nur_sleep_ms" (func $sleep (param i32)))
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "handle_request") (param i32 i32)
+                    (i64.store (i32.const 0) (call $now))
+                    (call $sleep (i32.const 60000))
+                    (i64.store (i32.const 8) (call $now))
+                    (if (i32.ne (call $random (i32.const 65530) (i32.const 100)) (i32.const -1))
+                        (then unreachable))
+                    (if (i32.ne (call $random (i32.const 0) (i32.const -1)) (i32.const -1))
+                        (then unreachable))
+                    (drop (call $random (i32.const 16) (i32.const 8)))
+                    (drop (call $write (i32.const 0) (i32.const 24))))
+            )"#,
+        );
+
+        let response = worker
+            .gateway
+            .invoke(function_uuid, &[], b"GET / HTTP/1.1\r\n\r\n")
+            .await;
+        assert_eq!(response.status(), Some(200));
+        let mut expected = 1_000_u64.to_le_bytes().to_vec();
+        // Sleeps are capped at MAX_SLEEP_MS
+        expected.extend_from_slice(&31_000_u64.to_le_bytes());
+        let mut random = [0; 8];
+        HostClock::Fixed(Arc::new(FixedClock::new(0, 42)))
+            .fill(&mut random)
+            .unwrap();
+        expected.extend_from_slice(&random);
+        let head_len = response
+            .bytes
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .unwrap()
+            + 4;
+        // A single chunk of 0x18 bytes
+        let body = &response.bytes[head_len..];
+        assert!(body.starts_with(b"18\r\n"));
+        assert_eq!(&body[4..28], expected);
+        assert_eq!(clock.now_ms(), 31_000);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_component() {
+        let worker = TestWorker::start().await;
+        let wat = fixtures::hello_component();
+        let component = wasmer::wat2wasm(wat.as_bytes()).unwrap();
+        let function_uuid = uuid::Uuid::new_v4();
+        worker.fetcher.insert(function_uuid, &component);
+
+        let response = worker
+            .gateway
+            .invoke(function_uuid, &[], b"GET /cows?name=lola HTTP/1.1\r\n\r\n")
+            .await;
+        assert_eq!(response.status(), Some(200));
+        let text = String::from_utf8_lossy(&response.bytes).to_lowercase();
+        assert!(text.contains("content-type: text/plain\r\n"));
+        assert_eq!(response.body(), "f\r\n/cows?name=lola\r\n0\r\n\r\n");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_poll_stream_examples() {
+        let worker = TestWorker::start().await;
+        let echo_server = worker.deploy(&fixtures::echo_server());
+        let cowsay = worker.deploy(&fixtures::cowsay());
+
+        let request = b"POST /echo HTTP/1.1\r\ncontent-length: 3\r\n\r\nmu!";
+        let response = worker.gateway.invoke(echo_server, &[], request).await;
+        assert_eq!(response.status(), Some(200));
+        assert_eq!(response.body().as_bytes(), request);
+
+        let response = worker
+            .gateway
+            .invoke(cowsay, &[], b"GET / HTTP/1.1\r\n\r\n")
+            .await;
+        assert_eq!(response.status(), Some(200));
+        assert!(response.body().contains("< muu! intenta con POST! >"));
+
+        let logs = worker.logs.clone();
+        worker.stop().await;
+        assert_eq!(logs.of(echo_server).len(), 1);
+        assert!(logs.of(cowsay).is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_drain_waits_for_guest() {
+        let worker = TestWorker::start().await;
+        // Keeps running for a while after answering
+        let function_uuid = worker.deploy(
+            r#"(module
+                (import "nur" "nur_send" (func $send (param i32 i32)))
+                (import "nur" "nur_end" (func $end))
+                (import "nur" "nur_sleep_ms" (func $sleep (param i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "HTTP/1.1 204 No Content\r\n\r\n")
+                (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "poll_stream") (param $ptr i32) (param $len i32)
+                    (if (i32.eqz (local.get $ptr)) (then (return)))
+                    (call $send (i32.const 0) (i32.const 27))
+                    (call $end)
+                    (call $sleep (i32.const 500)))
+            )"#,
+        );
+
+        let response = worker
+            .gateway
+            .invoke(function_uuid, &[], b"GET / HTTP/1.1\r\n\r\n")
+            .await;
+        assert_eq!(response.status(), Some(204));
+
+        let started_at = Instant::now();
+        let summary = worker.stop().await;
+        assert_eq!(summary.drained, 1);
+        assert!(started_at.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_response_ends_poll_stream() {
+        let worker = TestWorker::start().await;
+        let function_uuid = worker.deploy(&fixtures::echo_server());
+
+        // Unlike FakeGateway, keeps the connection open after reading the response
+        let mut socket = TcpStream::connect(worker.gateway.worker_addr())
+            .await
+            .unwrap();
+        let mut handshake = vec![1];
+        handshake.extend_from_slice(function_uuid.as_bytes());
+        handshake.extend_from_slice(&0_u64.to_be_bytes());
+        socket.write_all(&handshake).await.unwrap();
+        assert_eq!(socket.read_u8().await.unwrap(), 0);
+        socket.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+
+        let summary = tokio::time::timeout(Duration::from_secs(5), worker.stop())
+            .await
+            .expect("the invocation outlived its response");
+        assert_eq!(summary.aborted, 0);
+        drop(socket);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_gateway_mtls() {
+        let certs = TestCerts::generate();
+        let worker = TestWorker::start_with(Config {
+            tls_cert_file: Some(certs.cert_file.clone()),
+            tls_key_file: Some(certs.key_file.clone()),
+            tls_client_ca_file: Some(certs.ca_file.clone()),
+            ..Config::default()
+        })
+        .await;
+        let function_uuid = worker.deploy(&fixtures::hello_world());
+        let addr = worker.gateway.worker_addr();
+        let request = b"GET / HTTP/1.1\r\nhost: nur\r\n\r\n";
+
+        let trusted = FakeGateway::new(addr).with_tls(certs.client_config(true));
+        let response = trusted.invoke(function_uuid, &[], request).await;
+        assert_eq!(response.handshake_status, 0);
+        assert_eq!(response.status(), Some(200));
+
+        // Without a client certificate the TLS handshake fails, the Nur one is never reached.
+        // Plaintext gateways may read a TLS alert instead of an error.
+        let anonymous = FakeGateway::new(addr).with_tls(certs.client_config(false));
+        let response = anonymous.try_invoke(function_uuid, &[], request).await;
+        assert!(!matches!(response, Ok(response) if response.handshake_status == 0));
+        let plaintext = FakeGateway::new(addr);
+        let response = plaintext.try_invoke(function_uuid, &[], request).await;
+        assert!(!matches!(response, Ok(response) if response.handshake_status == 0));
+
+        assert_eq!(worker.fetcher.fetches(), [(function_uuid, 0)]);
+        assert_eq!(worker.stop().await.served, 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_gateway_auth() {
+        let worker = TestWorker::start_with(Config {
+            gateway_auth_keys: Some("gw1=secret1;gw2=secret2".to_string()),
+            ..Config::default()
+        })
+        .await;
+        let function_uuid = worker.deploy(&fixtures::hello_world());
+        let addr = worker.gateway.worker_addr();
+        let request = b"GET / HTTP/1.1\r\nhost: nur\r\n\r\n";
+
+        let signed = FakeGateway::new(addr).with_key("gw2", "secret2");
+        let response = signed
+            .invoke(function_uuid, &[("X-Request-Id", "abc")], request)
+            .await;
+        assert_eq!(response.handshake_status, 0);
+        assert_eq!(response.status(), Some(200));
+
+        let response = worker.gateway.invoke(function_uuid, &[], request).await;
+        assert_eq!(response.handshake_status, 4);
+        let forged = FakeGateway::new(addr).with_key("gw1", "secret2");
+        let response = forged.invoke(function_uuid, &[], request).await;
+        assert_eq!(response.handshake_status, 4);
+
+        assert_eq!(worker.fetcher.fetches(), [(function_uuid, 0)]);
+        worker.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_gateway_credentials_hidden_from_guest() {
+        let worker = TestWorker::start_with(Config {
+            gateway_auth_keys: Some("gw1=secret1".to_string()),
+            ..Config::default()
+        })
+        .await;
+        // 204 when metadata.authorization is unknown and metadata.x-cow is "lola", 500 otherwise
+        let function_uuid = worker.deploy(
+            r#"(module
+                (import "nur" "nur_context_get" (func $get (param i32 i32 i32 i32) (result i32)))
+                (import "nur" "nur_response_start" (func $start (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 64) "metadata.authorization")
+                (data (i32.const 96) "metadata.x-cow")
+                (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "handle_request") (param i32 i32)
+                    (local $status i32)
+                    (local.set $status (i32.const 204))
+                    (if (i32.ne (call $get (i32.const 64) (i32.const 22) (i32.const 128) (i32.const 16))
+                            (i32.const -1))
+                        (then (local.set $status (i32.const 500))))
+                    (if (i32.ne (call $get (i32.const 96) (i32.const 14) (i32.const 128) (i32.const 16))
+                            (i32.const 4))
+                        (then (local.set $status (i32.const 500))))
+                    ;; Four zero bytes at 0, no headers
+                    (drop (call $start (local.get $status) (i32.const 0) (i32.const 4))))
+            )"#,
+        );
+
+        let response = worker
+            .gateway
+            .invoke(
+                function_uuid,
+                &[("authorization", "Bearer secret1"), ("x-cow", "lola")],
+                b"GET / HTTP/1.1\r\n\r\n",
+            )
+            .await;
+        assert_eq!(response.handshake_status, 0);
+        assert_eq!(response.status(), Some(204));
+    }
+}
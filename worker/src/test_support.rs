@@ -0,0 +1,484 @@
+//! End-to-end test helpers: a [TestWorker] running the real [Server] loop, a [FakeGateway]
+//! speaking the binary handshake to it, WAT equivalents of the `examples/` modules and
+//! [TestCerts] for TLS.
+
+use crate::admission::Admission;
+use crate::auth;
+use crate::config::Config;
+use crate::fetcher::MemoryFunctionFetcher;
+use crate::intrinsics::HostClock;
+use crate::invocations::Invocations;
+use crate::kv::MemoryKvStore;
+use crate::logs_service::MemoryLogsService;
+use crate::server::{Server, ShutdownSummary};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::oneshot;
+use tokio_rustls::TlsConnector;
+use uuid::Uuid;
+
+/// Longest a test waits for the worker to answer
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// WAT versions of the modules in `examples/`, so tests do not need a wasm32 toolchain
+pub mod fixtures {
+    use super::wat_string;
+
+    const ALLOC: &str = r#"
+        (global $next (mut i32) (i32.const 4096))
+        (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+    "#;
+
+    /// `examples/hello_world` as a `handle_request` guest
+    pub fn hello_world() -> String {
+        let headers = crate::abi::encode_headers(&[(
+            "content-type".to_string(),
+            "application/json".to_string(),
+        )]);
+        let body = r#"{"msg": "Hello world, wasm!"}"#;
+        let log = "Look, there is a request!";
+        format!(
+            r#"(module
+                (import "nur" "nur_log" (func $log (param i32 i32)))
+                (import "nur" "nur_response_start" (func $start (param i32 i32 i32) (result i32)))
+                (import "nur" "nur_response_write" (func $write (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{headers}")
+                (data (i32.const 1024) "{body}")
+                (data (i32.const 2048) "{log}")
+                {ALLOC}
+                (func (export "handle_request") (param i32 i32)
+                    (call $log (i32.const 2048) (i32.const {log_len}))
+                    (drop (call $start (i32.const 200) (i32.const 0) (i32.const {headers_len})))
+                    (drop (call $write (i32.const 1024) (i32.const {body_len}))))
+            )"#,
+            headers = wat_string(&headers),
+            headers_len = headers.len(),
+            body = wat_string(body.as_bytes()),
+            body_len = body.len(),
+            log = wat_string(log.as_bytes()),
+            log_len = log.len(),
+        )
+    }
+
+    /// `examples/echo_server`: answers with the first chunk of the request
+    pub fn echo_server() -> String {
+        let head = "HTTP/1.1 200 OK\r\nconnection: close\r\n\r\n";
+        let log = "Hola UX! El usuario nos ha enviado una petición!!";
+        format!(
+            r#"(module
+                (import "nur" "nur_log" (func $log (param i32 i32)))
+                (import "nur" "nur_send" (func $send (param i32 i32)))
+                (import "nur" "nur_end" (func $end))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{head}")
+                (data (i32.const 1024) "{log}")
+                {ALLOC}
+                (func (export "poll_stream") (param $ptr i32) (param $len i32)
+                    (if (i32.eqz (local.get $ptr)) (then (call $end) (return)))
+                    (call $log (i32.const 1024) (i32.const {log_len}))
+                    (call $send (i32.const 0) (i32.const {head_len}))
+                    (call $send (local.get $ptr) (local.get $len))
+                    (call $end))
+            )"#,
+            head = wat_string(head.as_bytes()),
+            head_len = head.len(),
+            log = wat_string(log.as_bytes()),
+            log_len = log.len(),
+        )
+    }
+
+    /// `examples/cowsay` answering a GET, which always gets the same cow
+    pub fn cowsay() -> String {
+        let message = "muu! intenta con POST!";
+        let border = message.chars().count() + 2;
+        let cow = format!(
+            " {top}\n< {message} >\n {bottom}\n        \\   ^__^\n         \\  (👀 )\\_______\n            (__)\\  ❤️   )\\/\\\n                ||----w |\n                ||     ||",
+            top = "_".repeat(border),
+            bottom = "-".repeat(border),
+        );
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{cow}",
+            cow.len()
+        );
+        format!(
+            r#"(module
+                (import "nur" "nur_send" (func $send (param i32 i32)))
+                (import "nur" "nur_end" (func $end))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{response}")
+                {ALLOC}
+                (func (export "poll_stream") (param i32 i32)
+                    (call $send (i32.const 0) (i32.const {response_len}))
+                    (call $end))
+            )"#,
+            response = wat_string(response.as_bytes()),
+            response_len = response.len(),
+        )
+    }
+
+    /// A `nur:http/incoming-handler` component answering with the request path
+    pub fn hello_component() -> String {
+        // The `content-type: text/plain` tuple at 64, pointing at both strings
+        let header: Vec<u8> = [256_u32, 12, 272, 10]
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect();
+        format!(
+            r#"(component
+                (core module $m
+                    (memory (export "memory") 1)
+                    (data (i32.const 64) "{header}")
+                    (data (i32.const 256) "content-type")
+                    (data (i32.const 272) "text/plain")
+                    (global $next (mut i32) (i32.const 4096))
+                    (func (export "cabi_realloc") (param i32 i32 i32 i32) (result i32)
+                        (local $ptr i32)
+                        (local.set $ptr (global.get $next))
+                        (global.set $next (i32.add (global.get $next) (local.get 3)))
+                        (local.get $ptr))
+                    ;; The response record is written at 0
+                    (func (export "nur:http/incoming-handler#handle")
+                        (param i32 i32) (param $path i32) (param $path_len i32) (param i32 i32 i32 i32)
+                        (result i32)
+                        (i32.store16 (i32.const 0) (i32.const 200))
+                        (i32.store (i32.const 4) (i32.const 64))
+                        (i32.store (i32.const 8) (i32.const 1))
+                        (i32.store (i32.const 12) (local.get $path))
+                        (i32.store (i32.const 16) (local.get $path_len))
+                        (i32.const 0))
+                )
+                (core instance $i (instantiate $m))
+                (type $headers (list (tuple string string)))
+                (type $request (record
+                    (field "method" string)
+                    (field "path" string)
+                    (field "headers" $headers)
+                    (field "body" (list u8))))
+                (type $response (record
+                    (field "status" u16)
+                    (field "headers" $headers)
+                    (field "body" (list u8))))
+                (func $handle (param "request" $request) (result $response)
+                    (canon lift (core func $i "nur:http/incoming-handler#handle")
+                        (memory $i "memory") (realloc (func $i "cabi_realloc"))))
+                (instance $incoming_handler
+                    (export "request" (type $request))
+                    (export "response" (type $response))
+                    (export "handle" (func $handle)))
+                (export "nur:http/incoming-handler" (instance $incoming_handler))
+            )"#,
+            header = wat_string(&header),
+        )
+    }
+}
+
+/// Escapes `bytes` for a WAT string literal
+fn wat_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("\\{byte:02x}")).collect()
+}
+
+/// A [Server] on a random local port, serving the modules given to [TestWorker::deploy]
+pub struct TestWorker {
+    pub gateway: FakeGateway,
+    pub fetcher: Arc<MemoryFunctionFetcher>,
+    pub logs: Arc<MemoryLogsService>,
+    shutdown: oneshot::Sender<()>,
+    server: tokio::task::JoinHandle<std::io::Result<ShutdownSummary>>,
+}
+
+impl TestWorker {
+    pub async fn start() -> Self {
+        TestWorker::start_with(Config::default()).await
+    }
+
+    /// Runs with `config`, on a random local port whatever its `host` and `port`
+    pub async fn start_with(config: Config) -> Self {
+        TestWorker::start_with_clock(config, HostClock::System).await
+    }
+
+    /// Like [TestWorker::start_with], giving guests `clock`
+    pub async fn start_with_clock(config: Config, clock: HostClock) -> Self {
+        let fetcher = Arc::new(MemoryFunctionFetcher::default());
+        let logs = Arc::new(MemoryLogsService::default());
+        let config = Config {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ..config
+        };
+        let server = Server::new(
+            &config,
+            fetcher.clone(),
+            logs.clone(),
+            Invocations::default(),
+            Admission::new(0, 0),
+            Arc::new(MemoryKvStore::default()),
+        )
+        .await
+        .unwrap()
+        .with_clock(clock);
+        let gateway = FakeGateway::new(server.local_addr().unwrap());
+
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let server = tokio::spawn(server.listen_forever_and_ever_amen(
+            async {
+                let _ = shutdown_rx.await;
+            },
+            RESPONSE_TIMEOUT,
+            RESPONSE_TIMEOUT,
+        ));
+
+        TestWorker {
+            gateway,
+            fetcher,
+            logs,
+            shutdown,
+            server,
+        }
+    }
+
+    /// Makes `module` (wasm or WAT) available under a new function uuid
+    pub fn deploy(&self, module: &str) -> Uuid {
+        let function_uuid = Uuid::new_v4();
+        self.fetcher.insert(function_uuid, module.as_bytes());
+        function_uuid
+    }
+
+    /// Shuts the server down, which also flushes the logs
+    pub async fn stop(self) -> ShutdownSummary {
+        let _ = self.shutdown.send(());
+        self.server.await.unwrap().unwrap()
+    }
+}
+
+/// Plays the Nur gateway: handshakes with a worker and forwards a raw HTTP request
+pub struct FakeGateway {
+    worker_addr: SocketAddr,
+    /// Connects over TLS when set
+    tls: Option<TlsConnector>,
+    /// Key id and secret signing every handshake when set, see [crate::auth]
+    key: Option<(String, String)>,
+}
+
+pub struct GatewayResponse {
+    /// Status byte of the handshake, `0` when the function is running
+    pub handshake_status: u8,
+    /// Everything the worker wrote after the handshake status
+    pub bytes: Vec<u8>,
+}
+
+impl FakeGateway {
+    pub fn new(worker_addr: SocketAddr) -> Self {
+        FakeGateway {
+            worker_addr,
+            tls: None,
+            key: None,
+        }
+    }
+
+    pub fn worker_addr(&self) -> SocketAddr {
+        self.worker_addr
+    }
+
+    /// Connects over TLS to a worker using a [TestCerts] certificate
+    pub fn with_tls(self, config: rustls::ClientConfig) -> Self {
+        FakeGateway {
+            tls: Some(TlsConnector::from(Arc::new(config))),
+            ..self
+        }
+    }
+
+    /// Signs every handshake with one of the `gateway_auth_keys` of the worker
+    pub fn with_key(self, key_id: &str, secret: &str) -> Self {
+        FakeGateway {
+            key: Some((key_id.to_string(), secret.to_string())),
+            ..self
+        }
+    }
+
+    /// Runs `function_uuid` with `request`, sending a v2 handshake when there is `metadata`
+    pub async fn invoke(
+        &self,
+        function_uuid: Uuid,
+        metadata: &[(&str, &str)],
+        request: &[u8],
+    ) -> GatewayResponse {
+        self.try_invoke(function_uuid, metadata, request)
+            .await
+            .unwrap()
+    }
+
+    /// Like [FakeGateway::invoke], for connections the worker may refuse
+    pub async fn try_invoke(
+        &self,
+        function_uuid: Uuid,
+        metadata: &[(&str, &str)],
+        request: &[u8],
+    ) -> std::io::Result<GatewayResponse> {
+        let mut metadata: Vec<_> = metadata
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        if let Some((key_id, secret)) = &self.key {
+            metadata.extend([
+                (auth::KEY_ID_KEY.to_string(), key_id.clone()),
+                (
+                    auth::TIMESTAMP_KEY.to_string(),
+                    auth::unix_now().to_string(),
+                ),
+                (auth::NONCE_KEY.to_string(), Uuid::new_v4().to_string()),
+            ]);
+            let signed = metadata
+                .iter()
+                .map(|(key, value)| (key.to_ascii_lowercase(), value.clone()))
+                .collect();
+            let message = auth::signed_message(2, function_uuid.as_bytes(), 0, &signed);
+            metadata.push((
+                auth::SIGNATURE_KEY.to_string(),
+                auth::sign(secret, &message),
+            ));
+        }
+
+        let socket = tokio::net::TcpStream::connect(self.worker_addr).await?;
+        match &self.tls {
+            None => exchange(socket, function_uuid, &metadata, request).await,
+            Some(connector) => {
+                let server_name = ServerName::try_from("localhost").unwrap();
+                let socket = connector.connect(server_name, socket).await?;
+                exchange(socket, function_uuid, &metadata, request).await
+            }
+        }
+    }
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    function_uuid: Uuid,
+    metadata: &[(String, String)],
+    request: &[u8],
+) -> std::io::Result<GatewayResponse> {
+    let version: u8 = if metadata.is_empty() { 1 } else { 2 };
+    let mut handshake = vec![version];
+    handshake.extend_from_slice(function_uuid.as_bytes());
+    handshake.extend_from_slice(&0_u64.to_be_bytes()); // last deployment
+    if version == 2 {
+        handshake.extend_from_slice(&(metadata.len() as u16).to_be_bytes());
+        for (key, value) in metadata {
+            for s in [key, value] {
+                handshake.extend_from_slice(&(s.len() as u16).to_be_bytes());
+                handshake.extend_from_slice(s.as_bytes());
+            }
+        }
+    }
+    socket.write_all(&handshake).await?;
+
+    let handshake_status = socket.read_u8().await?;
+    let mut bytes = Vec::new();
+    if handshake_status == 0 {
+        socket.write_all(request).await?;
+        tokio::time::timeout(RESPONSE_TIMEOUT, socket.read_to_end(&mut bytes))
+            .await
+            .expect("worker did not close the connection")?;
+    }
+    Ok(GatewayResponse {
+        handshake_status,
+        bytes,
+    })
+}
+
+impl GatewayResponse {
+    /// Status code of the HTTP response
+    pub fn status(&self) -> Option<u16> {
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut response = httparse::Response::new(&mut headers);
+        response.parse(&self.bytes).ok()?;
+        response.code
+    }
+
+    /// Everything after the response head
+    pub fn body(&self) -> String {
+        let text = String::from_utf8_lossy(&self.bytes);
+        match text.split_once("\r\n\r\n") {
+            Some((_, body)) => body.to_string(),
+            None => String::new(),
+        }
+    }
+}
+
+/// A CA, a `localhost` worker certificate and a gateway client certificate, written to a
+/// temporary directory
+pub struct TestCerts {
+    pub ca_file: String,
+    pub cert_file: String,
+    pub key_file: String,
+    ca: CertificateDer<'static>,
+    client_cert: CertificateDer<'static>,
+    client_key: Vec<u8>,
+}
+
+impl TestCerts {
+    pub fn generate() -> Self {
+        use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+        let dir = std::env::temp_dir().join(format!("nur-certs-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, pem: String| -> String {
+            let path: PathBuf = dir.join(name);
+            std::fs::write(&path, pem).unwrap();
+            path.to_string_lossy().to_string()
+        };
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let client_cert = CertificateParams::new(vec!["gateway".to_string()])
+            .unwrap()
+            .signed_by(&client_key, &ca, &ca_key)
+            .unwrap();
+
+        TestCerts {
+            ca_file: write("ca.pem", ca.pem()),
+            cert_file: write("cert.pem", cert.pem()),
+            key_file: write("key.pem", key.serialize_pem()),
+            ca: ca.der().clone(),
+            client_cert: client_cert.der().clone(),
+            client_key: client_key.serialize_der(),
+        }
+    }
+
+    /// Trusts the test CA and, when `with_client_cert`, presents the gateway certificate
+    pub fn client_config(&self, with_client_cert: bool) -> rustls::ClientConfig {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(self.ca.clone()).unwrap();
+        let builder = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        if with_client_cert {
+            let key = PrivateKeyDer::try_from(self.client_key.clone()).unwrap();
+            builder
+                .with_client_auth_cert(vec![self.client_cert.clone()], key)
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! TLS for the gateway listener and for the Postgres connection shared by the logs, KV and
+//! secrets services.
+//!
+//! The gateway listener serves TLS when `TLS_CERT_FILE` and `TLS_KEY_FILE` are set and, with
+//! `TLS_CLIENT_CA_FILE`, only accepts gateways presenting a certificate issued by that CA.
+//! [GatewayTls::watch] picks up renewed files without a restart.
+//!
+//! `sslmode` in `POSTGRES_URL` keeps its libpq meaning:
+//!
+//! - `disable`: plaintext.
+//! - `prefer` (default): TLS when the server supports it, plaintext otherwise.
+//! - `require`: TLS only.
+//! - `verify-ca`: TLS only, the server certificate must chain to a trusted root.
+//! - `verify-full`: like `verify-ca`, and the certificate must also match the host name.
+//!
+//! With `prefer` and `require` the certificate is only verified, like `verify-ca`, when
+//! `POSTGRES_CA_FILE` is set. Trusted roots are the bundled Mozilla roots unless
+//! `POSTGRES_CA_FILE` is set.
+
+use crate::config::Config;
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{CertificateError, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::io::BufReader;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tokio_rustls::TlsAcceptor;
+
+/// How often the gateway certificate files are checked for changes
+const CERT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// TLS of gateway connections, rebuilt whenever its files change
+#[derive(Clone)]
+pub struct GatewayTls {
+    files: GatewayTlsFiles,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+#[derive(Clone)]
+struct GatewayTlsFiles {
+    cert: String,
+    key: String,
+    /// Clients must present a certificate issued by this CA when set
+    client_ca: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SslMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+/// How much of the server certificate is checked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Verification {
+    /// Encryption only, any certificate is accepted
+    None,
+    /// The certificate chains to a trusted root, whatever its names
+    Chain,
+    /// The certificate chains to a trusted root and names the host
+    Full,
+}
+
+impl FromStr for SslMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disable" => Ok(SslMode::Disable),
+            "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            "verify-ca" => Ok(SslMode::VerifyCa),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            mode => Err(format!(
+                "invalid sslmode {mode:?}, expected disable, prefer, require, verify-ca or verify-full"
+            )),
+        }
+    }
+}
+
+impl GatewayTls {
+    /// [None] when the listener is plaintext
+    pub fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        let (Some(cert), Some(key)) = (&config.tls_cert_file, &config.tls_key_file) else {
+            return Ok(None);
+        };
+        let files = GatewayTlsFiles {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: config.tls_client_ca_file.clone(),
+        };
+        let acceptor = files.acceptor()?;
+        Ok(Some(GatewayTls {
+            files,
+            acceptor: Arc::new(RwLock::new(acceptor)),
+        }))
+    }
+
+    /// The acceptor for a new connection, connections already accepted are unaffected by reloads
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    /// Reloads the certificates whenever one of the files changes, forever.
+    /// Invalid files are logged and the previous certificates kept.
+    pub async fn watch(self) {
+        let mut modified = self.files.modified_at().await;
+        let mut interval = tokio::time::interval(CERT_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = self.files.modified_at().await;
+            if now == modified {
+                continue;
+            }
+            modified = now;
+            match self.files.acceptor() {
+                Ok(acceptor) => {
+                    *self.acceptor.write().unwrap() = acceptor;
+                    log::info!("🔁 Reloaded gateway TLS certificates");
+                }
+                Err(e) => log::error!("{e}, still using the previous gateway certificates"),
+            }
+        }
+    }
+}
+
+impl GatewayTlsFiles {
+    fn acceptor(&self) -> Result<TlsAcceptor, String> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+        let builder = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(path)? {
+                    roots
+                        .add(cert)
+                        .map_err(|e| format!("Invalid CA certificate in {path}: {e}"))?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()
+                        .map_err(|e| {
+                            format!("Failed to build the gateway certificate verifier: {e}")
+                        })?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let server_config = builder
+            .with_single_cert(load_certs(&self.cert)?, load_key(&self.key)?)
+            .map_err(|e| format!("Invalid gateway TLS certificate: {e}"))?;
+        Ok(TlsAcceptor::from(Arc::new(server_config)))
+    }
+
+    async fn modified_at(&self) -> Vec<Option<SystemTime>> {
+        let mut modified = Vec::new();
+        for path in [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            let metadata = tokio::fs::metadata(path).await.ok();
+            modified.push(metadata.and_then(|metadata| metadata.modified().ok()));
+        }
+        modified
+    }
+}
+
+/// Parses `postgres_url`, in either the URL or the `key=value` form, along with the TLS
+/// connector its `sslmode` calls for
+pub fn postgres_config(
+    config: &Config,
+    postgres_url: &str,
+) -> Result<(tokio_postgres::Config, MakeRustlsConnect), String> {
+    let (postgres_url, ssl_mode) = split_ssl_mode(postgres_url)?;
+    let mut pg_config = tokio_postgres::Config::from_str(&postgres_url)
+        .map_err(|e| format!("Invalid postgres_url: {e}"))?;
+    pg_config.ssl_mode(match ssl_mode {
+        SslMode::Disable => tokio_postgres::config::SslMode::Disable,
+        SslMode::Prefer => tokio_postgres::config::SslMode::Prefer,
+        SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => {
+            tokio_postgres::config::SslMode::Require
+        }
+    });
+
+    let verification = match ssl_mode {
+        SslMode::VerifyFull => Verification::Full,
+        SslMode::VerifyCa => Verification::Chain,
+        _ if config.postgres_ca_file.is_some() => Verification::Chain,
+        _ => Verification::None,
+    };
+    let tls_config = client_config(config, verification)?;
+    Ok((pg_config, MakeRustlsConnect::new(tls_config)))
+}
+
+/// Takes `sslmode` out of `postgres_url`, since tokio-postgres rejects `verify-ca` and `verify-full`
+fn split_ssl_mode(postgres_url: &str) -> Result<(String, SslMode), String> {
+    let mut ssl_mode = SslMode::Prefer;
+
+    let postgres_url =
+        if postgres_url.starts_with("postgres://") || postgres_url.starts_with("postgresql://") {
+            match postgres_url.split_once('?') {
+                Some((base, query)) => {
+                    let mut params = Vec::new();
+                    for param in query.split('&') {
+                        match param.strip_prefix("sslmode=") {
+                            Some(mode) => ssl_mode = mode.parse()?,
+                            None => params.push(param),
+                        }
+                    }
+                    if params.is_empty() {
+                        base.to_string()
+                    } else {
+                        format!("{base}?{}", params.join("&"))
+                    }
+                }
+                None => postgres_url.to_string(),
+            }
+        } else {
+            let mut params = Vec::new();
+            for param in postgres_url.split_whitespace() {
+                match param.strip_prefix("sslmode=") {
+                    Some(mode) => ssl_mode = mode.trim_matches('\'').parse()?,
+                    None => params.push(param),
+                }
+            }
+            params.join(" ")
+        };
+    Ok((postgres_url, ssl_mode))
+}
+
+fn client_config(
+    config: &Config,
+    verification: Verification,
+) -> Result<rustls::ClientConfig, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let mut roots = RootCertStore::empty();
+    match &config.postgres_ca_file {
+        Some(path) => {
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("Invalid CA certificate in {path}: {e}"))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let webpki = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|e| format!("Failed to build the Postgres certificate verifier: {e}"))?;
+
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PostgresCertVerifier {
+            webpki,
+            provider,
+            verification,
+        }));
+
+    match (
+        &config.postgres_client_cert_file,
+        &config.postgres_client_key_file,
+    ) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| format!("Invalid Postgres client certificate: {e}")),
+        _ => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// Certificates of a PEM file
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("Failed to open {path}: {e}"))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificate in {path}: {e}"))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in {path}"));
+    }
+    Ok(certs)
+}
+
+/// First private key of a PEM file
+pub fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("Failed to open {path}: {e}"))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("Invalid private key in {path}: {e}"))?
+        .ok_or_else(|| format!("No private key in {path}"))
+}
+
+/// Checks as much of the server certificate as the [Verification] asks for.
+/// Handshake signatures are always verified.
+#[derive(Debug)]
+struct PostgresCertVerifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    provider: Arc<CryptoProvider>,
+    verification: Verification,
+}
+
+impl ServerCertVerifier for PostgresCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.verification == Verification::None {
+            return Ok(ServerCertVerified::assertion());
+        }
+        match self.webpki.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        ) {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) if self.verification == Verification::Chain => Ok(ServerCertVerified::assertion()),
+            verified => verified,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_ssl_mode() {
+        assert_eq!(
+            split_ssl_mode("postgres://nur@db.nur.dev/nur?sslmode=verify-full").unwrap(),
+            (
+                "postgres://nur@db.nur.dev/nur".to_string(),
+                SslMode::VerifyFull
+            )
+        );
+        assert_eq!(
+            split_ssl_mode("postgresql://nur@db/nur?connect_timeout=5&sslmode=disable").unwrap(),
+            (
+                "postgresql://nur@db/nur?connect_timeout=5".to_string(),
+                SslMode::Disable
+            )
+        );
+        assert_eq!(
+            split_ssl_mode("host=db user=nur sslmode=verify-ca").unwrap(),
+            ("host=db user=nur".to_string(), SslMode::VerifyCa)
+        );
+        assert_eq!(
+            split_ssl_mode("postgres://nur@db/nur").unwrap().1,
+            SslMode::Prefer
+        );
+        assert!(split_ssl_mode("postgres://nur@db/nur?sslmode=allow").is_err());
+    }
+
+    #[test]
+    fn test_postgres_config() {
+        let config = Config::default();
+        let (pg_config, _) =
+            postgres_config(&config, "postgres://nur@db/nur?sslmode=verify-full").unwrap();
+        assert_eq!(
+            pg_config.get_ssl_mode(),
+            tokio_postgres::config::SslMode::Require
+        );
+
+        let config = Config {
+            postgres_ca_file: Some("/nonexistent/ca.pem".to_string()),
+            ..Config::default()
+        };
+        assert!(
+            postgres_config(&config, "postgres://nur@db/nur")
+                .err()
+                .unwrap()
+                .starts_with("Failed to open /nonexistent/ca.pem")
+        );
+    }
+}
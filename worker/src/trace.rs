@@ -0,0 +1,375 @@
+//! Lightweight spans exported to an OpenTelemetry collector through OTLP/HTTP (JSON encoding).
+//!
+//! Tracing is disabled until [init] is called, in which case every [Span] is a no-op.
+//! Finished spans are batched and posted to `{endpoint}/v1/traces` by a background task.
+
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+const MAX_PENDING_SPANS: usize = 4096;
+const MAX_BATCH_SIZE: usize = 512;
+const BATCH_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longest a batch export may take, so that a hung collector can't stall the exporter
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static EXPORTER: OnceLock<flume::Sender<SpanData>> = OnceLock::new();
+
+/// Identifies a span across process boundaries, as carried by the W3C `traceparent` header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+}
+
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+}
+
+struct SpanData {
+    name: &'static str,
+    kind: SpanKind,
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, AttributeValue)>,
+    error: Option<String>,
+}
+
+/// A unit of work that is exported once dropped
+pub struct Span {
+    data: Option<SpanData>,
+}
+
+impl SpanContext {
+    /// Parses a W3C `traceparent` value, e.g. `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+
+        let trace_id: [u8; 16] = decode_hex(trace_id)?.try_into().ok()?;
+        let span_id: [u8; 8] = decode_hex(span_id)?.try_into().ok()?;
+        let flags = decode_hex(flags)?;
+        if flags.len() != 1 || trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+
+        Some(SpanContext {
+            trace_id,
+            span_id,
+            sampled: flags[0] & 1 == 1,
+        })
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue::Int(value)
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::String(value)
+    }
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_string())
+    }
+}
+
+impl Span {
+    /// Starts a new trace, unless tracing is disabled
+    pub fn root(name: &'static str, kind: SpanKind) -> Self {
+        if EXPORTER.get().is_none() {
+            return Span { data: None };
+        }
+        let context = SpanContext {
+            trace_id: Uuid::new_v4().into_bytes(),
+            span_id: new_span_id(),
+            sampled: true,
+        };
+        Span::start(name, kind, context, None)
+    }
+
+    /// Starts a span under `parent`. Unsampled or missing parents produce no-op spans.
+    pub fn child_of(parent: Option<SpanContext>, name: &'static str) -> Self {
+        match parent {
+            Some(parent) if parent.sampled && EXPORTER.get().is_some() => {
+                let context = SpanContext {
+                    span_id: new_span_id(),
+                    ..parent
+                };
+                Span::start(name, SpanKind::Internal, context, Some(parent.span_id))
+            }
+            _ => Span { data: None },
+        }
+    }
+
+    pub fn child(&self, name: &'static str) -> Self {
+        Span::child_of(self.context(), name)
+    }
+
+    fn start(
+        name: &'static str,
+        kind: SpanKind,
+        context: SpanContext,
+        parent_span_id: Option<[u8; 8]>,
+    ) -> Self {
+        let now = SystemTime::now();
+        Span {
+            data: Some(SpanData {
+                name,
+                kind,
+                context,
+                parent_span_id,
+                start: now,
+                end: now,
+                attributes: Vec::new(),
+                error: None,
+            }),
+        }
+    }
+
+    /// Backdates the span start, for work that began before its parent context was known
+    pub fn started_at(mut self, start: SystemTime) -> Self {
+        if let Some(data) = self.data.as_mut() {
+            data.start = start;
+        }
+        self
+    }
+
+    pub fn context(&self) -> Option<SpanContext> {
+        self.data.as_ref().map(|data| data.context)
+    }
+
+    /// Moves a root span into a trace started elsewhere, e.g. by the gateway.
+    /// Must be called before any child span is created.
+    pub fn set_remote_parent(&mut self, parent: SpanContext) {
+        if !parent.sampled {
+            self.data = None;
+            return;
+        }
+        if let Some(data) = self.data.as_mut() {
+            data.context.trace_id = parent.trace_id;
+            data.parent_span_id = Some(parent.span_id);
+        }
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<AttributeValue>) {
+        if let Some(data) = self.data.as_mut() {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    pub fn set_error(&mut self, message: impl Into<String>) {
+        if let Some(data) = self.data.as_mut() {
+            data.error = Some(message.into());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(mut data) = self.data.take() else {
+            return;
+        };
+        data.end = SystemTime::now();
+        if let Some(exporter) = EXPORTER.get() {
+            // Tracing must never slow down invocations, so spans are dropped when the exporter lags
+            let _ = exporter.try_send(data);
+        }
+    }
+}
+
+/// Enables tracing and spawns the task exporting spans to the collector at `endpoint`
+pub fn init(endpoint: String, service_name: String) {
+    let (tx, rx) = flume::bounded::<SpanData>(MAX_PENDING_SPANS);
+    if EXPORTER.set(tx).is_err() {
+        log::warn!("Tracing already initialized");
+        return;
+    }
+
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let client = match reqwest::Client::builder().timeout(EXPORT_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            log::error!("Unable to build the span exporter client: {e}");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+        loop {
+            match rx.recv_async().await {
+                Ok(span) => batch.push(span),
+                Err(flume::RecvError::Disconnected) => break,
+            }
+
+            let deadline = tokio::time::Instant::now() + BATCH_TIMEOUT;
+            while batch.len() < MAX_BATCH_SIZE {
+                match tokio::time::timeout_at(deadline, rx.recv_async()).await {
+                    Ok(Ok(span)) => batch.push(span),
+                    _ => break,
+                }
+            }
+
+            let body = encode_otlp_json(&service_name, &batch);
+            batch.clear();
+            if let Err(e) = post_json(&client, &url, body).await {
+                log::warn!("Failed to export spans to {url}: {e}");
+            }
+        }
+    });
+}
+
+fn encode_otlp_json(service_name: &str, spans: &[SpanData]) -> Vec<u8> {
+    let spans = spans
+        .iter()
+        .map(|span| {
+            let mut json = serde_json::json!({
+                "traceId": encode_hex(&span.context.trace_id),
+                "spanId": encode_hex(&span.context.span_id),
+                "name": span.name,
+                "kind": span.kind as u8,
+                "startTimeUnixNano": unix_nanos(span.start).to_string(),
+                "endTimeUnixNano": unix_nanos(span.end).to_string(),
+                "attributes": span.attributes.iter().map(|(key, value)| {
+                    let value = match value {
+                        AttributeValue::String(s) => serde_json::json!({ "stringValue": s }),
+                        // OTLP/JSON encodes 64 bit integers as strings
+                        AttributeValue::Int(i) => serde_json::json!({ "intValue": i.to_string() }),
+                    };
+                    serde_json::json!({ "key": key, "value": value })
+                }).collect::<Vec<_>>(),
+                "status": match &span.error {
+                    Some(message) => serde_json::json!({ "code": 2, "message": message }),
+                    None => serde_json::json!({ "code": 0 }),
+                },
+            });
+            if let Some(parent_span_id) = span.parent_span_id {
+                json["parentSpanId"] = encode_hex(&parent_span_id).into();
+            }
+            json
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": service_name } }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "nur_worker" },
+                "spans": spans,
+            }],
+        }],
+    })
+    .to_string()
+    .into_bytes()
+}
+
+async fn post_json(client: &reqwest::Client, url: &str, body: Vec<u8>) -> Result<(), String> {
+    let response = client
+        .post(url)
+        .header("content-type", "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        status => Err(format!("unexpected response: {status}")),
+    }
+}
+
+fn new_span_id() -> [u8; 8] {
+    let bytes = Uuid::new_v4().into_bytes();
+    let mut span_id = [0; 8];
+    span_id.copy_from_slice(&bytes[..8]);
+    span_id
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    // from_str_radix alone would also accept a sign
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_traceparent() {
+        let ctx = SpanContext::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .unwrap();
+        assert_eq!(
+            encode_hex(&ctx.trace_id),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(encode_hex(&ctx.span_id), "00f067aa0ba902b7");
+        assert!(ctx.sampled);
+
+        let ctx = SpanContext::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00",
+        )
+        .unwrap();
+        assert!(!ctx.sampled);
+
+        assert!(
+            SpanContext::from_traceparent(
+                "00-00000000000000000000000000000000-00f067aa0ba902b7-01"
+            )
+            .is_none()
+        );
+        assert!(
+            SpanContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7")
+                .is_none()
+        );
+        assert!(SpanContext::from_traceparent("garbage").is_none());
+    }
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("00ff7a"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex("+f"), None);
+        assert_eq!(decode_hex("f"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+}
@@ -0,0 +1,804 @@
+//! Minimal WASI preview1 (`wasi_snapshot_preview1`) imports for guest modules.
+//!
+//! Guests get no arguments nor environment variables, stdout/stderr routed to the function logs,
+//! a virtual clock starting at instantiation, random bytes and a read-only in-memory filesystem
+//! preopened at `/`. Anything else fails with `ENOSYS` or `EROFS`.
+
+use crate::intrinsics::NurWasmMessage;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+use wasmer::{
+    AsStoreMut, Exports, Function, FunctionEnv, FunctionEnvMut, Memory, MemoryView, RuntimeError,
+};
+
+pub const WASI_MODULE_NAME: &str = "wasi_snapshot_preview1";
+
+/// Reactor initializer exported by modules built for `wasm32-wasip1`
+pub const EXPORTED_INITIALIZE_SYMBOL_NAME: &str = "_initialize";
+
+type Errno = i32;
+
+const ERRNO_SUCCESS: Errno = 0;
+const ERRNO_BADF: Errno = 8;
+const ERRNO_FAULT: Errno = 21;
+const ERRNO_INVAL: Errno = 28;
+const ERRNO_ISDIR: Errno = 31;
+const ERRNO_NOENT: Errno = 44;
+const ERRNO_NOSYS: Errno = 52;
+const ERRNO_NOTCAPABLE: Errno = 76;
+const ERRNO_ROFS: Errno = 69;
+const ERRNO_SPIPE: Errno = 70;
+
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+const FILETYPE_DIRECTORY: u8 = 3;
+const FILETYPE_REGULAR_FILE: u8 = 4;
+
+const OFLAGS_CREAT: i32 = 1 << 0;
+const OFLAGS_DIRECTORY: i32 = 1 << 1;
+const OFLAGS_EXCL: i32 = 1 << 2;
+const OFLAGS_TRUNC: i32 = 1 << 3;
+const FDFLAGS_APPEND: i32 = 1 << 0;
+const RIGHTS_FD_WRITE: i64 = 1 << 6;
+
+const CLOCK_REALTIME: i32 = 0;
+const CLOCK_MONOTONIC: i32 = 1;
+const CLOCK_PROCESS_CPUTIME: i32 = 2;
+const CLOCK_THREAD_CPUTIME: i32 = 3;
+const CLOCK_RESOLUTION_NS: u64 = 1_000;
+
+const FD_STDIN: i32 = 0;
+const FD_STDOUT: i32 = 1;
+const FD_STDERR: i32 = 2;
+const FD_PREOPEN: i32 = 3;
+
+/// Largest `fd_write` forwarded as one log message. Longer writes are short, guests write the
+/// rest again.
+const MAX_WRITE_LEN: usize = 16 * 1024;
+
+/// Read-only files exposed to guests, keyed by their path relative to `/`
+#[derive(Default)]
+pub struct MemFs {
+    files: HashMap<String, Arc<[u8]>>,
+}
+
+impl MemFs {
+    /// Snapshots every file under `root` into memory
+    pub fn from_dir(root: impl AsRef<Path>) -> io::Result<Self> {
+        let mut fs = MemFs::default();
+        fs.load_dir(root.as_ref(), "")?;
+        Ok(fs)
+    }
+
+    fn load_dir(&mut self, dir: &Path, prefix: &str) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = if prefix.is_empty() {
+                name
+            } else {
+                format!("{prefix}/{name}")
+            };
+            if entry.file_type()?.is_dir() {
+                self.load_dir(&entry.path(), &path)?;
+            } else {
+                self.files.insert(path, Arc::from(fs::read(entry.path())?));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        path.is_empty()
+            || self.files.keys().any(|file| {
+                file.len() > path.len()
+                    && file.starts_with(path)
+                    && file.as_bytes()[path.len()] == b'/'
+            })
+    }
+}
+
+struct OpenFile {
+    data: Arc<[u8]>,
+    offset: u64,
+}
+
+pub struct WasiEnv {
+    pub memory: Option<Memory>,
+    channel_tx: flume::Sender<NurWasmMessage>,
+    fs: Arc<MemFs>,
+    files: HashMap<i32, OpenFile>,
+    next_fd: i32,
+    started_at: Instant,
+    started_at_wall: SystemTime,
+}
+
+impl WasiEnv {
+    pub fn new(channel_tx: flume::Sender<NurWasmMessage>, fs: Arc<MemFs>) -> Self {
+        WasiEnv {
+            memory: None,
+            channel_tx,
+            fs,
+            files: HashMap::new(),
+            next_fd: FD_PREOPEN + 1,
+            started_at: Instant::now(),
+            started_at_wall: SystemTime::now(),
+        }
+    }
+}
+
+/// Builds the `wasi_snapshot_preview1` namespace
+pub fn exports(store: &mut impl AsStoreMut, env: &FunctionEnv<WasiEnv>) -> Exports {
+    let mut exports = Exports::new();
+    macro_rules! export {
+        ($($name:ident),* $(,)?) => {
+            $(exports.insert(stringify!($name), Function::new_typed_with_env(store, env, $name));)*
+        };
+    }
+    export!(
+        args_get,
+        args_sizes_get,
+        environ_get,
+        environ_sizes_get,
+        clock_res_get,
+        clock_time_get,
+        fd_close,
+        fd_fdstat_get,
+        fd_fdstat_set_flags,
+        fd_filestat_get,
+        fd_prestat_get,
+        fd_prestat_dir_name,
+        fd_pread,
+        fd_read,
+        fd_readdir,
+        fd_seek,
+        fd_sync,
+        fd_tell,
+        fd_write,
+        path_create_directory,
+        path_filestat_get,
+        path_open,
+        path_remove_directory,
+        path_rename,
+        path_unlink_file,
+        poll_oneoff,
+        proc_exit,
+        random_get,
+        sched_yield,
+    );
+    exports
+}
+
+/// Runs `f` with the guest memory, turning its result into a WASI errno
+fn with_memory(
+    env: &mut FunctionEnvMut<WasiEnv>,
+    f: impl FnOnce(&mut WasiEnv, &MemoryView) -> Result<(), Errno>,
+) -> Errno {
+    let (data, store) = env.data_and_store_mut();
+    let Some(memory) = data.memory.clone() else {
+        return ERRNO_FAULT;
+    };
+    let view = memory.view(&store);
+    match f(data, &view) {
+        Ok(()) => ERRNO_SUCCESS,
+        Err(errno) => errno,
+    }
+}
+
+/// Fails unless `[ptr, ptr + len)` is within the guest memory, so `len` can be allocated
+fn check_range(view: &MemoryView, ptr: i32, len: i32) -> Result<usize, Errno> {
+    let end = (ptr as u32 as u64)
+        .checked_add(len as u32 as u64)
+        .ok_or(ERRNO_FAULT)?;
+    if end > view.data_size() {
+        return Err(ERRNO_FAULT);
+    }
+    Ok(len as u32 as usize)
+}
+
+fn read_bytes(view: &MemoryView, ptr: i32, len: i32) -> Result<Vec<u8>, Errno> {
+    let mut buf = vec![0; check_range(view, ptr, len)?];
+    view.read(ptr as u32 as u64, &mut buf)
+        .map_err(|_| ERRNO_FAULT)?;
+    Ok(buf)
+}
+
+fn write_bytes(view: &MemoryView, ptr: i32, bytes: &[u8]) -> Result<(), Errno> {
+    view.write(ptr as u32 as u64, bytes)
+        .map_err(|_| ERRNO_FAULT)
+}
+
+fn read_u32(view: &MemoryView, ptr: i32) -> Result<u32, Errno> {
+    let bytes = read_bytes(view, ptr, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn write_u32(view: &MemoryView, ptr: i32, value: u32) -> Result<(), Errno> {
+    write_bytes(view, ptr, &value.to_le_bytes())
+}
+
+fn write_u64(view: &MemoryView, ptr: i32, value: u64) -> Result<(), Errno> {
+    write_bytes(view, ptr, &value.to_le_bytes())
+}
+
+/// Reads an array of `(buf, buf_len)` iovec structs
+fn read_iovs(view: &MemoryView, iovs: i32, iovs_len: i32) -> Result<Vec<(i32, i32)>, Errno> {
+    let size = (iovs_len as u32).checked_mul(8).ok_or(ERRNO_INVAL)?;
+    (iovs as u32).checked_add(size).ok_or(ERRNO_INVAL)?;
+    check_range(view, iovs, size as i32)?;
+    // The whole array is in bounds, so the addresses below can't overflow
+    (0..iovs_len as u32)
+        .map(|i| {
+            let iov = iovs as u32 + i * 8;
+            Ok((
+                read_u32(view, iov as i32)? as i32,
+                read_u32(view, (iov + 4) as i32)? as i32,
+            ))
+        })
+        .collect()
+}
+
+/// Resolves a guest path against the preopened `/`, refusing to escape it
+fn normalize_path(path: &[u8]) -> Result<String, Errno> {
+    let path = std::str::from_utf8(path).map_err(|_| ERRNO_INVAL)?;
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop().ok_or(ERRNO_NOTCAPABLE)?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    Ok(segments.join("/"))
+}
+
+/// Writes a 64 byte `filestat` struct
+fn write_filestat(view: &MemoryView, ptr: i32, filetype: u8, size: u64) -> Result<(), Errno> {
+    let mut filestat = [0_u8; 64];
+    filestat[16] = filetype;
+    filestat[24..32].copy_from_slice(&1_u64.to_le_bytes()); // nlink
+    filestat[32..40].copy_from_slice(&size.to_le_bytes());
+    write_bytes(view, ptr, &filestat)
+}
+
+fn args_get(_env: FunctionEnvMut<WasiEnv>, _argv: i32, _argv_buf: i32) -> Errno {
+    ERRNO_SUCCESS
+}
+
+fn args_sizes_get(mut env: FunctionEnvMut<WasiEnv>, argc: i32, argv_buf_size: i32) -> Errno {
+    with_memory(&mut env, |_, view| {
+        write_u32(view, argc, 0)?;
+        write_u32(view, argv_buf_size, 0)
+    })
+}
+
+fn environ_get(_env: FunctionEnvMut<WasiEnv>, _environ: i32, _environ_buf: i32) -> Errno {
+    ERRNO_SUCCESS
+}
+
+fn environ_sizes_get(mut env: FunctionEnvMut<WasiEnv>, count: i32, buf_size: i32) -> Errno {
+    with_memory(&mut env, |_, view| {
+        write_u32(view, count, 0)?;
+        write_u32(view, buf_size, 0)
+    })
+}
+
+fn clock_res_get(mut env: FunctionEnvMut<WasiEnv>, clock_id: i32, resolution: i32) -> Errno {
+    with_memory(&mut env, |_, view| match clock_id {
+        CLOCK_REALTIME | CLOCK_MONOTONIC | CLOCK_PROCESS_CPUTIME | CLOCK_THREAD_CPUTIME => {
+            write_u64(view, resolution, CLOCK_RESOLUTION_NS)
+        }
+        _ => Err(ERRNO_INVAL),
+    })
+}
+
+fn clock_time_get(
+    mut env: FunctionEnvMut<WasiEnv>,
+    clock_id: i32,
+    _precision: i64,
+    time: i32,
+) -> Errno {
+    with_memory(&mut env, |data, view| {
+        // The wall clock is derived from the monotonic one, so guests never see it go backwards
+        let elapsed = data.started_at.elapsed();
+        let nanos = match clock_id {
+            CLOCK_REALTIME => (data.started_at_wall + elapsed)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+            CLOCK_MONOTONIC | CLOCK_PROCESS_CPUTIME | CLOCK_THREAD_CPUTIME => {
+                elapsed.as_nanos() as u64
+            }
+            _ => return Err(ERRNO_INVAL),
+        };
+        write_u64(view, time, nanos)
+    })
+}
+
+fn fd_close(mut env: FunctionEnvMut<WasiEnv>, fd: i32) -> Errno {
+    match fd {
+        FD_STDIN | FD_STDOUT | FD_STDERR | FD_PREOPEN => ERRNO_SUCCESS,
+        fd => match env.data_mut().files.remove(&fd) {
+            Some(_) => ERRNO_SUCCESS,
+            None => ERRNO_BADF,
+        },
+    }
+}
+
+fn fd_fdstat_get(mut env: FunctionEnvMut<WasiEnv>, fd: i32, stat: i32) -> Errno {
+    with_memory(&mut env, |data, view| {
+        let filetype = match fd {
+            FD_STDIN | FD_STDOUT | FD_STDERR => FILETYPE_CHARACTER_DEVICE,
+            FD_PREOPEN => FILETYPE_DIRECTORY,
+            fd if data.files.contains_key(&fd) => FILETYPE_REGULAR_FILE,
+            _ => return Err(ERRNO_BADF),
+        };
+        let mut fdstat = [0_u8; 24];
+        fdstat[0] = filetype;
+        fdstat[8..16].copy_from_slice(&u64::MAX.to_le_bytes()); // rights_base
+        fdstat[16..24].copy_from_slice(&u64::MAX.to_le_bytes()); // rights_inheriting
+        write_bytes(view, stat, &fdstat)
+    })
+}
+
+fn fd_fdstat_set_flags(_env: FunctionEnvMut<WasiEnv>, _fd: i32, _flags: i32) -> Errno {
+    ERRNO_NOSYS
+}
+
+fn fd_filestat_get(mut env: FunctionEnvMut<WasiEnv>, fd: i32, filestat: i32) -> Errno {
+    with_memory(&mut env, |data, view| match fd {
+        FD_STDIN | FD_STDOUT | FD_STDERR => {
+            write_filestat(view, filestat, FILETYPE_CHARACTER_DEVICE, 0)
+        }
+        FD_PREOPEN => write_filestat(view, filestat, FILETYPE_DIRECTORY, 0),
+        fd => match data.files.get(&fd) {
+            Some(file) => write_filestat(
+                view,
+                filestat,
+                FILETYPE_REGULAR_FILE,
+                file.data.len() as u64,
+            ),
+            None => Err(ERRNO_BADF),
+        },
+    })
+}
+
+fn fd_prestat_get(mut env: FunctionEnvMut<WasiEnv>, fd: i32, prestat: i32) -> Errno {
+    with_memory(&mut env, |_, view| {
+        if fd != FD_PREOPEN {
+            return Err(ERRNO_BADF);
+        }
+        // tag 0 (directory) followed by the length of "/"
+        write_bytes(view, prestat, &[0, 0, 0, 0])?;
+        write_u32(view, prestat + 4, 1)
+    })
+}
+
+fn fd_prestat_dir_name(mut env: FunctionEnvMut<WasiEnv>, fd: i32, path: i32, len: i32) -> Errno {
+    with_memory(&mut env, |_, view| {
+        if fd != FD_PREOPEN {
+            return Err(ERRNO_BADF);
+        }
+        if len < 1 {
+            return Err(ERRNO_INVAL);
+        }
+        write_bytes(view, path, b"/")
+    })
+}
+
+/// Copies `data[offset..]` into the iovecs, returning the number of bytes copied
+fn copy_into_iovs(
+    view: &MemoryView,
+    data: &[u8],
+    offset: u64,
+    iovs: i32,
+    iovs_len: i32,
+) -> Result<u64, Errno> {
+    let mut offset = (offset as usize).min(data.len());
+    let start = offset;
+    for (buf, buf_len) in read_iovs(view, iovs, iovs_len)? {
+        let end = (offset + buf_len as u32 as usize).min(data.len());
+        write_bytes(view, buf, &data[offset..end])?;
+        offset = end;
+        if offset == data.len() {
+            break;
+        }
+    }
+    Ok((offset - start) as u64)
+}
+
+fn fd_pread(
+    mut env: FunctionEnvMut<WasiEnv>,
+    fd: i32,
+    iovs: i32,
+    iovs_len: i32,
+    offset: i64,
+    nread: i32,
+) -> Errno {
+    with_memory(&mut env, |data, view| {
+        let file = data.files.get(&fd).ok_or(ERRNO_BADF)?;
+        let n = copy_into_iovs(view, &file.data, offset as u64, iovs, iovs_len)?;
+        write_u32(view, nread, n as u32)
+    })
+}
+
+fn fd_read(
+    mut env: FunctionEnvMut<WasiEnv>,
+    fd: i32,
+    iovs: i32,
+    iovs_len: i32,
+    nread: i32,
+) -> Errno {
+    with_memory(&mut env, |data, view| {
+        if fd == FD_STDIN {
+            // Requests arrive through poll_stream, stdin is always at EOF
+            return write_u32(view, nread, 0);
+        }
+        let file = data.files.get_mut(&fd).ok_or(ERRNO_BADF)?;
+        let n = copy_into_iovs(view, &file.data, file.offset, iovs, iovs_len)?;
+        file.offset += n;
+        write_u32(view, nread, n as u32)
+    })
+}
+
+fn fd_readdir(
+    _env: FunctionEnvMut<WasiEnv>,
+    _fd: i32,
+    _buf: i32,
+    _buf_len: i32,
+    _cookie: i64,
+    _bufused: i32,
+) -> Errno {
+    ERRNO_NOSYS
+}
+
+fn fd_seek(
+    mut env: FunctionEnvMut<WasiEnv>,
+    fd: i32,
+    offset: i64,
+    whence: i32,
+    new_offset: i32,
+) -> Errno {
+    with_memory(&mut env, |data, view| {
+        if matches!(fd, FD_STDIN | FD_STDOUT | FD_STDERR) {
+            return Err(ERRNO_SPIPE);
+        }
+        let file = data.files.get_mut(&fd).ok_or(ERRNO_BADF)?;
+        let base = match whence {
+            0 => 0,
+            1 => file.offset as i64,
+            2 => file.data.len() as i64,
+            _ => return Err(ERRNO_INVAL),
+        };
+        let target = base.checked_add(offset).ok_or(ERRNO_INVAL)?;
+        if target < 0 {
+            return Err(ERRNO_INVAL);
+        }
+        file.offset = target as u64;
+        write_u64(view, new_offset, file.offset)
+    })
+}
+
+fn fd_sync(_env: FunctionEnvMut<WasiEnv>, _fd: i32) -> Errno {
+    // Nothing is buffered on the host side
+    ERRNO_SUCCESS
+}
+
+fn fd_tell(mut env: FunctionEnvMut<WasiEnv>, fd: i32, offset: i32) -> Errno {
+    with_memory(&mut env, |data, view| {
+        let file = data.files.get(&fd).ok_or(ERRNO_BADF)?;
+        write_u64(view, offset, file.offset)
+    })
+}
+
+fn fd_write(
+    mut env: FunctionEnvMut<WasiEnv>,
+    fd: i32,
+    iovs: i32,
+    iovs_len: i32,
+    nwritten: i32,
+) -> Errno {
+    with_memory(&mut env, |data, view| {
+        match fd {
+            FD_STDOUT | FD_STDERR => {}
+            fd if data.files.contains_key(&fd) => return Err(ERRNO_ROFS),
+            _ => return Err(ERRNO_BADF),
+        }
+
+        let mut bytes = Vec::new();
+        for (buf, buf_len) in read_iovs(view, iovs, iovs_len)? {
+            let len = check_range(view, buf, buf_len)?.min(MAX_WRITE_LEN - bytes.len());
+            bytes.extend_from_slice(&read_bytes(view, buf, len as i32)?);
+            if bytes.len() == MAX_WRITE_LEN {
+                break;
+            }
+        }
+        let n = bytes.len() as u32;
+
+        let log = String::from_utf8_lossy(&bytes).to_string();
+        log::trace!("wasi fd_write({fd}): {log}");
+        // The guest may keep printing after nur_end(), those lines are simply discarded
+        let _ = data.channel_tx.send(NurWasmMessage::LogMessage { log });
+
+        write_u32(view, nwritten, n)
+    })
+}
+
+fn path_create_directory(_env: FunctionEnvMut<WasiEnv>, _fd: i32, _path: i32, _len: i32) -> Errno {
+    ERRNO_ROFS
+}
+
+fn path_filestat_get(
+    mut env: FunctionEnvMut<WasiEnv>,
+    fd: i32,
+    _flags: i32,
+    path: i32,
+    path_len: i32,
+    filestat: i32,
+) -> Errno {
+    with_memory(&mut env, |data, view| {
+        if fd != FD_PREOPEN {
+            return Err(ERRNO_BADF);
+        }
+        let path = normalize_path(&read_bytes(view, path, path_len)?)?;
+        if let Some(file) = data.fs.files.get(&path) {
+            write_filestat(view, filestat, FILETYPE_REGULAR_FILE, file.len() as u64)
+        } else if data.fs.is_dir(&path) {
+            write_filestat(view, filestat, FILETYPE_DIRECTORY, 0)
+        } else {
+            Err(ERRNO_NOENT)
+        }
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn path_open(
+    mut env: FunctionEnvMut<WasiEnv>,
+    fd: i32,
+    _dirflags: i32,
+    path: i32,
+    path_len: i32,
+    oflags: i32,
+    rights_base: i64,
+    _rights_inheriting: i64,
+    fdflags: i32,
+    opened_fd: i32,
+) -> Errno {
+    with_memory(&mut env, |data, view| {
+        if fd != FD_PREOPEN {
+            return Err(ERRNO_BADF);
+        }
+        if oflags & (OFLAGS_CREAT | OFLAGS_EXCL | OFLAGS_TRUNC) != 0
+            || fdflags & FDFLAGS_APPEND != 0
+            || rights_base & RIGHTS_FD_WRITE != 0
+        {
+            return Err(ERRNO_ROFS);
+        }
+
+        let path = normalize_path(&read_bytes(view, path, path_len)?)?;
+        let Some(file) = data.fs.files.get(&path).cloned() else {
+            return Err(if data.fs.is_dir(&path) {
+                // Directory handles are not supported, there is no fd_readdir either
+                if oflags & OFLAGS_DIRECTORY != 0 {
+                    ERRNO_NOSYS
+                } else {
+                    ERRNO_ISDIR
+                }
+            } else {
+                ERRNO_NOENT
+            });
+        };
+
+        let new_fd = data.next_fd;
+        data.next_fd += 1;
+        data.files.insert(
+            new_fd,
+            OpenFile {
+                data: file,
+                offset: 0,
+            },
+        );
+        write_u32(view, opened_fd, new_fd as u32)
+    })
+}
+
+fn path_remove_directory(_env: FunctionEnvMut<WasiEnv>, _fd: i32, _path: i32, _len: i32) -> Errno {
+    ERRNO_ROFS
+}
+
+fn path_rename(
+    _env: FunctionEnvMut<WasiEnv>,
+    _fd: i32,
+    _old_path: i32,
+    _old_path_len: i32,
+    _new_fd: i32,
+    _new_path: i32,
+    _new_path_len: i32,
+) -> Errno {
+    ERRNO_ROFS
+}
+
+fn path_unlink_file(_env: FunctionEnvMut<WasiEnv>, _fd: i32, _path: i32, _len: i32) -> Errno {
+    ERRNO_ROFS
+}
+
+fn poll_oneoff(
+    _env: FunctionEnvMut<WasiEnv>,
+    _in: i32,
+    _out: i32,
+    _nsubscriptions: i32,
+    _nevents: i32,
+) -> Errno {
+    ERRNO_NOSYS
+}
+
+fn proc_exit(env: FunctionEnvMut<WasiEnv>, code: i32) -> Result<(), RuntimeError> {
+    log::debug!("wasi proc_exit({code})");
+    let _ = env.data().channel_tx.send(NurWasmMessage::Abort);
+    // Unwinds the guest, there is no way to return into it
+    Err(RuntimeError::new(format!("proc_exit({code})")))
+}
+
+fn random_get(mut env: FunctionEnvMut<WasiEnv>, buf: i32, len: i32) -> Errno {
+    with_memory(&mut env, |_, view| {
+        let mut bytes = vec![0; check_range(view, buf, len)?];
+        getrandom::fill(&mut bytes).map_err(|_| ERRNO_NOSYS)?;
+        write_bytes(view, buf, &bytes)
+    })
+}
+
+fn sched_yield(_env: FunctionEnvMut<WasiEnv>) -> Errno {
+    ERRNO_SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmer::{Imports, Instance, Module, Store};
+
+    const WAT: &str = r#"
+        (module
+            (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "path_open" (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "random_get" (func $random_get (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "hello\n")
+            (data (i32.const 16) "etc/motd")
+            ;; iovec { buf: 0, len: 6 } at 32
+            (data (i32.const 32) "\00\00\00\00\06\00\00\00")
+            ;; iovec { buf: 0, len: 4 GiB - 1 } at 48
+            (data (i32.const 48) "\00\00\00\00\ff\ff\ff\ff")
+            ;; Two iovecs { buf: 0, len: 64 KiB } at 56
+            (data (i32.const 56) "\00\00\00\00\00\00\01\00\00\00\00\00\00\00\01\00")
+
+            (func (export "print") (result i32)
+                (call $fd_write (i32.const 1) (i32.const 32) (i32.const 1) (i32.const 40)))
+
+            ;; Opens /etc/motd, reads it into [0..6) and returns the errno of the read
+            (func (export "read_motd") (result i32)
+                (drop (call $path_open (i32.const 3) (i32.const 0) (i32.const 16) (i32.const 8)
+                    (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 44)))
+                (call $fd_read (i32.load (i32.const 44)) (i32.const 32) (i32.const 1) (i32.const 40)))
+
+            (func (export "print_out_of_bounds") (result i32)
+                (call $fd_write (i32.const 1) (i32.const 48) (i32.const 1) (i32.const 40)))
+
+            (func (export "print_large") (result i32)
+                (call $fd_write (i32.const 1) (i32.const 56) (i32.const 2) (i32.const 40)))
+
+            (func (export "print_too_many_iovs") (result i32)
+                (call $fd_write (i32.const 1) (i32.const 32) (i32.const 0x20000000) (i32.const 40)))
+
+            (func (export "print_wrapping_iovs") (result i32)
+                (call $fd_write (i32.const 1) (i32.const -8) (i32.const 2) (i32.const 40)))
+
+            (func (export "random_out_of_bounds") (result i32)
+                (call $random_get (i32.const 65000) (i32.const -1)))
+
+            (func (export "create") (result i32)
+                (call $path_open (i32.const 3) (i32.const 0) (i32.const 16) (i32.const 8)
+                    (i32.const 1) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 44)))
+        )
+    "#;
+
+    fn instantiate(fs: MemFs) -> (Store, Instance, flume::Receiver<NurWasmMessage>) {
+        let mut store = Store::default();
+        let module = Module::new(&store, WAT).unwrap();
+        let (tx, rx) = flume::unbounded();
+        let env = FunctionEnv::new(&mut store, WasiEnv::new(tx, Arc::new(fs)));
+
+        let mut imports = Imports::new();
+        imports.register_namespace(WASI_MODULE_NAME, exports(&mut store, &env));
+        let instance = Instance::new(&mut store, &module, &imports).unwrap();
+
+        let memory = instance.exports.get_memory("memory").unwrap().clone();
+        env.as_mut(&mut store).memory = Some(memory);
+        (store, instance, rx)
+    }
+
+    fn call(store: &mut Store, instance: &Instance, name: &str) -> i32 {
+        let func = instance.exports.get_function(name).unwrap();
+        func.call(store, &[]).unwrap()[0].unwrap_i32()
+    }
+
+    #[test]
+    fn test_stdout_is_logged() {
+        let (mut store, instance, rx) = instantiate(MemFs::default());
+
+        assert_eq!(call(&mut store, &instance, "print"), ERRNO_SUCCESS);
+        match rx.try_recv() {
+            Ok(NurWasmMessage::LogMessage { log }) => assert_eq!(log, "hello\n"),
+            _ => panic!("expected a log message"),
+        }
+    }
+
+    #[test]
+    fn test_large_writes_are_short() {
+        let (mut store, instance, rx) = instantiate(MemFs::default());
+
+        assert_eq!(call(&mut store, &instance, "print_large"), ERRNO_SUCCESS);
+        match rx.try_recv() {
+            Ok(NurWasmMessage::LogMessage { log }) => assert!(log.starts_with("hello\n")),
+            _ => panic!("expected a log message"),
+        }
+        let memory = instance.exports.get_memory("memory").unwrap();
+        let mut nwritten = [0_u8; 4];
+        memory.view(&store).read(40, &mut nwritten).unwrap();
+        assert_eq!(u32::from_le_bytes(nwritten), MAX_WRITE_LEN as u32);
+    }
+
+    #[test]
+    fn test_read_only_fs() {
+        let mut fs = MemFs::default();
+        fs.files
+            .insert("etc/motd".to_string(), Arc::from(b"mu!".to_vec()));
+        let (mut store, instance, _rx) = instantiate(fs);
+
+        assert_eq!(call(&mut store, &instance, "read_motd"), ERRNO_SUCCESS);
+        let memory = instance.exports.get_memory("memory").unwrap();
+        let mut buf = [0_u8; 6];
+        memory.view(&store).read(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"mu!lo\n");
+
+        assert_eq!(call(&mut store, &instance, "create"), ERRNO_ROFS);
+    }
+
+    #[test]
+    fn test_out_of_bounds() {
+        let (mut store, instance, rx) = instantiate(MemFs::default());
+
+        assert_eq!(
+            call(&mut store, &instance, "print_out_of_bounds"),
+            ERRNO_FAULT
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(
+            call(&mut store, &instance, "print_too_many_iovs"),
+            ERRNO_INVAL
+        );
+        assert_eq!(
+            call(&mut store, &instance, "print_wrapping_iovs"),
+            ERRNO_INVAL
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(
+            call(&mut store, &instance, "random_out_of_bounds"),
+            ERRNO_FAULT
+        );
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path(b"/a/./b/../c").unwrap(), "a/c");
+        assert_eq!(normalize_path(b"..").unwrap_err(), ERRNO_NOTCAPABLE);
+    }
+}